@@ -0,0 +1,313 @@
+//! Offline inspection of a stopped broker's data directory.
+//!
+//! Usage: inspect [--key-file <file>] <data-dir> <command>
+//!
+//!   shards                 list shards with their depth and file sizes
+//!   wal <shard>            dump the WAL records of a shard
+//!   snapshot <shard>       dump the snapshot of a shard
+//!   verify                 check every WAL and snapshot checksum
+//!   export <file>          write pending messages as JSON Lines
+//!   import <file> [shards] push an export into the data directory, routed
+//!                          for `shards` shards (default: the existing count);
+//!                          existing data is resharded to match
+//!
+//! Every command but `import` opens the directory read-only. Encrypted data
+//! directories need the keys that wrote them, from `--key-file` or the
+//! broker's `RLBG_ENCRYPTION_KEY_FILE` / `RLBG_ENCRYPTION_KEY` variables;
+//! `import` encrypts with the active key.
+
+use rlbg::crypto::Keyring;
+use rlbg::export;
+use rlbg::protocol::Message;
+use rlbg::shards::record::{FileKind, WalDamage, file_key_id};
+use rlbg::shards::{
+    QueueConfig, ShardedQueue, WalOp, WalStorage, existing_shard_ids, read_shard_count,
+    snapshot_path, wal_path,
+};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+const USAGE: &str = "usage: inspect [--key-file <file>] <data-dir> shards | wal <shard> | snapshot <shard> | verify \
+                     | export <file> | import <file> [shards]";
+const DEFAULT_SHARD_COUNT: usize = 4;
+const PREVIEW_LEN: usize = 80;
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let keys = match load_keys(&mut args) {
+        Ok(keys) => keys.map(Arc::new),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+    let keys = keys.as_ref();
+    let (data_dir, command) = match args.as_slice() {
+        [dir, cmd, ..] => (Path::new(dir), cmd.as_str()),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    let shard_arg = || -> Option<usize> { args.get(2)?.parse().ok() };
+
+    let result = match command {
+        "shards" => list_shards(data_dir, keys),
+        "wal" | "snapshot" => {
+            let Some(shard) = shard_arg() else {
+                eprintln!("{}", USAGE);
+                return ExitCode::from(2);
+            };
+            if command == "wal" {
+                dump_wal(data_dir, shard, keys)
+            } else {
+                dump_snapshot(data_dir, shard, keys)
+            }
+        }
+        "verify" => verify(data_dir, keys),
+        "export" | "import" => {
+            let Some(file) = args.get(2) else {
+                eprintln!("{}", USAGE);
+                return ExitCode::from(2);
+            };
+            if command == "export" {
+                export_dir(data_dir, Path::new(file), keys)
+            } else {
+                let shards = args.get(3).and_then(|n| n.parse().ok());
+                import_dir(data_dir, Path::new(file), shards, keys)
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Takes `--key-file <file>` out of `args`, falling back to the broker's
+/// environment variables.
+fn load_keys(args: &mut Vec<String>) -> io::Result<Option<Keyring>> {
+    let Some(at) = args.iter().position(|arg| arg == "--key-file") else {
+        return Keyring::from_env();
+    };
+    if at + 1 >= args.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--key-file needs a path",
+        ));
+    }
+    let path = args.remove(at + 1);
+    args.remove(at);
+    Keyring::load(Path::new(&path)).map(Some)
+}
+
+fn file_len(path: &Path) -> u64 {
+    std::fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+fn list_shards(data_dir: &Path, keys: Option<&Arc<Keyring>>) -> io::Result<bool> {
+    for id in existing_shard_ids(data_dir)? {
+        let mut depth = 0;
+        let mut depth_bytes = 0;
+        let next_seq = WalStorage::recover_files(data_dir, id, keys, |stored| {
+            depth += 1;
+            depth_bytes += stored.msg.encoded_len();
+            Ok(())
+        })?;
+        println!(
+            "shard {}: depth {} ({} bytes) next_seq {} wal {} bytes snapshot {} bytes",
+            id,
+            depth,
+            depth_bytes,
+            next_seq,
+            file_len(&wal_path(data_dir, id)),
+            file_len(&snapshot_path(data_dir, id)),
+        );
+        for (path, kind) in [
+            (wal_path(data_dir, id), FileKind::Wal),
+            (snapshot_path(data_dir, id), FileKind::Snapshot),
+        ] {
+            if let Ok(Some(key_id)) = file_key_id(&path, kind) {
+                println!("  {} encrypted with key {}", path.display(), key_id);
+            }
+        }
+    }
+    Ok(true)
+}
+
+fn dump_wal(data_dir: &Path, id: usize, keys: Option<&Arc<Keyring>>) -> io::Result<bool> {
+    let scan = WalStorage::scan_wal(&wal_path(data_dir, id), id, keys)?;
+    for record in &scan.records {
+        match record.op {
+            WalOp::Push => {
+                println!(
+                    "@{} {} seq {} len {}",
+                    record.offset,
+                    record.op,
+                    record.seq,
+                    record.data.len()
+                );
+                print_message(&record.data);
+            }
+            WalOp::Pop => println!("@{} {} seq {}", record.offset, record.op, record.seq),
+        }
+    }
+    println!("{} records", scan.records.len());
+    if let Some(damage) = scan.damage {
+        println!("stopped: {}", damage);
+    }
+    Ok(true)
+}
+
+fn dump_snapshot(data_dir: &Path, id: usize, keys: Option<&Arc<Keyring>>) -> io::Result<bool> {
+    let mut count = 0;
+    let next_seq = WalStorage::read_snapshot(&snapshot_path(data_dir, id), id, keys, |stored| {
+        println!("seq {}", stored.seq);
+        print_message(&stored.msg.encode());
+        count += 1;
+        Ok(())
+    })?;
+    println!("{} messages, next_seq {}", count, next_seq);
+    Ok(true)
+}
+
+fn verify(data_dir: &Path, keys: Option<&Arc<Keyring>>) -> io::Result<bool> {
+    let mut ok = true;
+    for id in existing_shard_ids(data_dir)? {
+        let wal = wal_path(data_dir, id);
+        if wal.exists() {
+            let scan = WalStorage::scan_wal(&wal, id, keys)?;
+            match scan.damage {
+                None => println!("shard {} wal: ok ({} records)", id, scan.records.len()),
+                Some(damage @ WalDamage::TornTail { .. }) => println!(
+                    "shard {} wal: ok ({} records), {} left by a crash",
+                    id,
+                    scan.records.len(),
+                    damage
+                ),
+                Some(damage) => {
+                    ok = false;
+                    println!("shard {} wal: FAILED, {}", id, damage);
+                }
+            }
+        }
+
+        let snapshot = snapshot_path(data_dir, id);
+        if snapshot.exists() {
+            let mut count = 0;
+            match WalStorage::read_snapshot(&snapshot, id, keys, |_| {
+                count += 1;
+                Ok(())
+            }) {
+                Ok(_) => println!("shard {} snapshot: ok ({} messages)", id, count),
+                Err(e) => {
+                    ok = false;
+                    println!(
+                        "shard {} snapshot: FAILED after {} messages, {}",
+                        id, count, e
+                    );
+                }
+            }
+        }
+    }
+    Ok(ok)
+}
+
+fn export_dir(data_dir: &Path, file: &Path, keys: Option<&Arc<Keyring>>) -> io::Result<bool> {
+    let mut out = BufWriter::new(File::create(file)?);
+    let mut count = 0;
+    for id in existing_shard_ids(data_dir)? {
+        WalStorage::recover_files(data_dir, id, keys, |stored| {
+            count += 1;
+            export::write_message(&mut out, stored.seq, &stored.msg)
+        })?;
+    }
+    out.flush()?;
+    println!("exported {} messages to {}", count, file.display());
+    Ok(true)
+}
+
+fn import_dir(
+    data_dir: &Path,
+    file: &Path,
+    shards: Option<usize>,
+    keys: Option<&Arc<Keyring>>,
+) -> io::Result<bool> {
+    let shard_count = match shards {
+        Some(n) => n,
+        None => read_shard_count(data_dir)
+            .ok()
+            .flatten()
+            .unwrap_or(DEFAULT_SHARD_COUNT),
+    };
+    let config = QueueConfig {
+        encryption: keys.cloned(),
+        ..Default::default()
+    };
+    let queue = ShardedQueue::with_config(shard_count, data_dir, config)?;
+    let count = queue.import(BufReader::new(File::open(file)?))?;
+    queue.force_checkpoint()?;
+    println!(
+        "imported {} messages into {} shards of {}",
+        count,
+        shard_count,
+        data_dir.display()
+    );
+    Ok(true)
+}
+
+fn print_message(encoded: &[u8]) {
+    match Message::decode(encoded) {
+        Ok(msg) => {
+            println!(
+                "  {:?} flags={:#06x} payload_len={}",
+                msg.header.msg_type, msg.header.flags, msg.header.payload_len
+            );
+            for tlv in &msg.tlvs {
+                println!(
+                    "    tag {:#04x} len {} {}",
+                    tlv.tag,
+                    tlv.value.len(),
+                    preview(&tlv.value)
+                );
+            }
+        }
+        Err(e) => println!("  <undecodable message: {}>", e),
+    }
+}
+
+/// Quoted text for printable UTF-8 values, hex otherwise; long values are cut.
+fn preview(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(text) if !text.chars().any(|c| c.is_control() && c != '\n') => {
+            let cut: String = text.chars().take(PREVIEW_LEN).collect();
+            let ellipsis = if cut.len() < text.len() { "..." } else { "" };
+            format!("{:?}{}", cut, ellipsis)
+        }
+        _ => {
+            let hex: Vec<String> = value
+                .iter()
+                .take(PREVIEW_LEN / 3)
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            let ellipsis = if value.len() > PREVIEW_LEN / 3 {
+                " ..."
+            } else {
+                ""
+            };
+            format!("{}{}", hex.join(" "), ellipsis)
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Guarding the admin commands: `export`, `import` and `backup`, which
+//! read or write files, and `promote`, `reload` and `log_level`, which
+//! change how the broker runs.
+//!
+//! Anyone who can reach the client port can send them, so they are refused
+//! unless they carry the admin token. The file commands also take a path
+//! from the client, which is taken relative to the admin directory. The
+//! token can be rotated with a reload.
+
+use crate::crypto::same_secret;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{RwLock, RwLockReadGuard};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AdminConfig {
+    /// Directory the file commands read and write in.
+    pub dir: Option<PathBuf>,
+    /// Secret the file commands must carry.
+    pub token: Option<String>,
+}
+
+impl AdminConfig {
+    /// Whether `token` is the configured one. Without a configured token
+    /// nothing is.
+    pub fn authorize(&self, token: &[u8]) -> bool {
+        let Some(expected) = &self.token else {
+            return false;
+        };
+        same_secret(expected.as_bytes(), token)
+    }
+
+    /// Where `name` is inside the admin directory. Only plain relative
+    /// paths are taken, so nothing outside it can be named.
+    pub fn resolve(&self, name: &str) -> io::Result<PathBuf> {
+        let Some(dir) = &self.dir else {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "no admin directory is configured",
+            ));
+        };
+        let path = Path::new(name);
+        let plain = path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        let names_file = path.components().any(|c| matches!(c, Component::Normal(_)));
+        if !plain || !names_file {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a relative path without ..", name),
+            ));
+        }
+        Ok(dir.join(path))
+    }
+}
+
+static ADMIN: RwLock<AdminConfig> = RwLock::new(AdminConfig {
+    dir: None,
+    token: None,
+});
+
+/// Replaces the running broker's admin settings, at startup and when a
+/// reload rotates the token.
+pub fn set_global_admin(config: AdminConfig) {
+    *ADMIN.write().unwrap() = config;
+}
+
+/// The running broker's admin settings. A broker that never set them
+/// refuses every file command.
+pub fn global_admin() -> RwLockReadGuard<'static, AdminConfig> {
+    ADMIN.read().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin() -> AdminConfig {
+        AdminConfig {
+            dir: Some(PathBuf::from("/srv/admin")),
+            token: Some("s3cret".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_resolves_only_relative_paths_inside_the_directory() {
+        let admin = admin();
+        assert_eq!(
+            admin.resolve("exports/today.jsonl").unwrap(),
+            PathBuf::from("/srv/admin/exports/today.jsonl")
+        );
+        for name in [
+            "",
+            ".",
+            "/etc/passwd",
+            "../escape",
+            "a/../../escape",
+            "a/..",
+        ] {
+            let err = admin.resolve(name).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{:?}", name);
+        }
+
+        let unset = AdminConfig { dir: None, ..admin };
+        let err = unset.resolve("today.jsonl").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_authorizes_only_the_configured_token() {
+        let admin = admin();
+        assert!(admin.authorize(b"s3cret"));
+        assert!(!admin.authorize(b"s3cre"));
+        assert!(!admin.authorize(b"s3cret!"));
+        assert!(!admin.authorize(b"S3cret"));
+        assert!(!admin.authorize(b""));
+
+        let unset = AdminConfig {
+            token: None,
+            ..admin
+        };
+        assert!(!unset.authorize(b""));
+        assert!(!unset.authorize(b"s3cret"));
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Span over which worker panics from one IP are counted towards a ban.
+pub const PANIC_WINDOW: Duration = Duration::from_secs(60);
+
+/// What happens to a connection arriving while the broker is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdmissionPolicy {
+    /// Stop accepting until a connection closes; new clients wait in the
+    /// listen backlog.
+    Block,
+    /// Accept, send a "server busy" Control frame telling the client when to
+    /// retry, and close.
+    Reject,
+}
+
+#[derive(Debug, Clone)]
+pub struct AdmissionConfig {
+    /// Open client connections at most.
+    pub max_connections: usize,
+    /// Open connections from one IP at most. Connections over this limit
+    /// are always rejected, since blocking would hold up every other IP.
+    pub max_per_ip: Option<usize>,
+    pub policy: AdmissionPolicy,
+    /// Retry hint sent with every rejection.
+    pub retry_after: Duration,
+    /// Worker panics one IP's requests may cause within `PANIC_WINDOW`
+    /// before it is banned.
+    pub panic_limit: Option<usize>,
+    /// How long a ban lasts.
+    pub ban: Duration,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 10_000,
+            max_per_ip: None,
+            policy: AdmissionPolicy::Reject,
+            retry_after: Duration::from_secs(1),
+            panic_limit: Some(3),
+            ban: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Why a connection was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /// The broker holds `max_connections` already.
+    Full,
+    /// The peer's IP holds `max_per_ip` already.
+    PerIp,
+    /// The peer's IP is banned for crashing workers.
+    Banned,
+}
+
+/// Counts open connections against the admission limits.
+#[derive(Debug)]
+pub struct Admission {
+    config: AdmissionConfig,
+    open: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    /// Recent worker panics per IP, oldest first.
+    panics: HashMap<IpAddr, VecDeque<Instant>>,
+    /// Banned IPs and when their ban ends.
+    banned: HashMap<IpAddr, Instant>,
+}
+
+impl Admission {
+    pub fn new(config: AdmissionConfig) -> Self {
+        Self {
+            config,
+            open: 0,
+            per_ip: HashMap::new(),
+            panics: HashMap::new(),
+            banned: HashMap::new(),
+        }
+    }
+
+    pub fn policy(&self) -> AdmissionPolicy {
+        self.config.policy
+    }
+
+    pub fn retry_after(&self) -> Duration {
+        self.config.retry_after
+    }
+
+    /// What is left of the ban on `ip`, if it is banned.
+    pub fn banned_for(&self, ip: IpAddr) -> Option<Duration> {
+        let until = *self.banned.get(&ip)?;
+        until.checked_duration_since(Instant::now())
+    }
+
+    /// Swaps in new limits, keeping the connections already counted. Ones
+    /// over a lowered limit stay open; only new arrivals see it.
+    pub fn reconfigure(&mut self, config: AdmissionConfig) {
+        self.config = config;
+    }
+
+    /// Whether another connection fits under `max_connections`.
+    pub fn has_room(&self) -> bool {
+        self.open < self.config.max_connections
+    }
+
+    /// Counts a connection from `ip` in, unless it breaks a limit.
+    pub fn admit(&mut self, ip: IpAddr) -> Result<(), Refusal> {
+        if self.banned.contains_key(&ip) {
+            if self.banned_for(ip).is_some() {
+                return Err(Refusal::Banned);
+            }
+            self.banned.remove(&ip);
+        }
+        if !self.has_room() {
+            return Err(Refusal::Full);
+        }
+        let count = self.per_ip.entry(ip).or_default();
+        if self.config.max_per_ip.is_some_and(|max| *count >= max) {
+            return Err(Refusal::PerIp);
+        }
+        *count += 1;
+        self.open += 1;
+        Ok(())
+    }
+
+    /// Counts an admitted connection from `ip` out again.
+    pub fn release(&mut self, ip: IpAddr) {
+        if let Some(count) = self.per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_ip.remove(&ip);
+            }
+            self.open -= 1;
+        }
+    }
+
+    /// Counts a worker panic caused by a request from `ip`. Returns true
+    /// when that bans the IP.
+    pub fn record_panic(&mut self, ip: IpAddr, now: Instant) -> bool {
+        // Forget panics too old to count, including other IPs'
+        self.panics.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|t| now.saturating_duration_since(*t) >= PANIC_WINDOW)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let Some(limit) = self.config.panic_limit else {
+            return false;
+        };
+        let times = self.panics.entry(ip).or_default();
+        times.push_back(now);
+        if times.len() < limit {
+            return false;
+        }
+        self.panics.remove(&ip);
+        self.banned.insert(ip, now + self.config.ban);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_total_and_per_ip() {
+        let mut admission = Admission::new(AdmissionConfig {
+            max_connections: 3,
+            max_per_ip: Some(2),
+            policy: AdmissionPolicy::Block,
+            retry_after: Duration::from_secs(1),
+            ..Default::default()
+        });
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        assert_eq!(admission.admit(a), Ok(()));
+        assert_eq!(admission.admit(a), Ok(()));
+        assert_eq!(admission.admit(a), Err(Refusal::PerIp));
+        assert_eq!(admission.admit(b), Ok(()));
+        assert!(!admission.has_room());
+        assert_eq!(admission.admit(b), Err(Refusal::Full));
+
+        admission.release(a);
+        assert!(admission.has_room());
+        assert_eq!(admission.admit(a), Ok(()));
+        // Refused connections were never counted in
+        admission.release(b);
+        admission.release(b);
+        assert_eq!(admission.admit(b), Ok(()));
+        assert!(!admission.has_room());
+    }
+
+    #[test]
+    fn test_bans_ips_whose_requests_keep_crashing_workers() {
+        let mut admission = Admission::new(AdmissionConfig {
+            panic_limit: Some(2),
+            ban: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+        assert!(!admission.record_panic(a, now));
+        assert!(!admission.record_panic(b, now));
+        assert!(admission.record_panic(a, now));
+
+        assert_eq!(admission.admit(a), Err(Refusal::Banned));
+        assert!(admission.banned_for(a).is_some());
+        assert_eq!(admission.admit(b), Ok(()));
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(admission.banned_for(a), None);
+        assert_eq!(admission.admit(a), Ok(()));
+
+        // Panics further apart than the window never add up
+        let later = now + PANIC_WINDOW;
+        assert!(!admission.record_panic(b, later));
+        assert!(!admission.record_panic(b, later + PANIC_WINDOW));
+    }
+}
@@ -0,0 +1,1049 @@
+use crate::broker::admission::{Admission, AdmissionConfig, AdmissionPolicy, Refusal};
+use crate::broker::epoll::{
+    EPOLLERR, EPOLLHUP, EPOLLIN, EPOLLOUT, EPOLLRDHUP, Epoll, Event, Waker,
+};
+use crate::broker::handlers::{busy_reply, handle_frame, internal_error_reply};
+use crate::broker::threadpool::{ThreadPool, panic_message};
+use crate::broker::timeouts::{TimeoutConfig, set_keepalive};
+use crate::log_error;
+use crate::log_info;
+use crate::log_warn;
+use crate::logger::global_loger;
+use crate::protocol::{Header, MAX_INFLATED_LEN, MessageType};
+use crate::shards::ShardedQueue;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const LISTENER: u64 = 0;
+const WAKER: u64 = 1;
+const FIRST_CONNECTION: u64 = 2;
+
+/// Events handled per wakeup.
+const EVENT_CAPACITY: usize = 1024;
+const READ_CHUNK: usize = 16 * 1024;
+/// Bytes read from one connection per wakeup, so a client that keeps its
+/// socket full cannot hold the loop away from the others.
+const READ_BUDGET: usize = 4 * READ_CHUNK;
+const HEADER_LEN: usize = 12;
+
+/// Frames a connection may have waiting before the loop stops reading
+/// from it, which pushes back on the client through TCP. Complete frames
+/// past these stay unsplit in the input until there is room.
+const MAX_PENDING_FRAMES: usize = 64;
+
+/// Bounds on how often timeouts are checked.
+const MIN_TICK: Duration = Duration::from_millis(10);
+const MAX_TICK: Duration = Duration::from_secs(1);
+
+/// How long a refused connection is kept before closing, and how many are
+/// kept at most.
+const LINGER: Duration = Duration::from_secs(1);
+const MAX_LINGERING: usize = 1024;
+
+/// Limits handed to a running loop, applied on its next wakeup.
+type PendingConfig = Arc<Mutex<Option<(AdmissionConfig, TimeoutConfig)>>>;
+
+/// Replies workers hand back, keyed by connection token, or what the
+/// handler panicked with.
+type Completions = Arc<Mutex<Vec<(u64, Result<Vec<u8>, String>)>>>;
+
+#[derive(Debug)]
+struct Connection {
+    stream: TcpStream,
+    peer: SocketAddr,
+    /// Bytes read but not yet framed.
+    input: Vec<u8>,
+    /// Complete frames waiting for a worker.
+    frames: VecDeque<Arc<[u8]>>,
+    /// Replies not yet written.
+    output: Vec<u8>,
+    /// Whether a worker holds one of this connection's frames. Frames are
+    /// handled one at a time so replies keep request order.
+    busy: bool,
+    /// Type byte of the last frame handed to a worker.
+    last_type: u8,
+    /// The peer will send nothing more.
+    read_closed: bool,
+    /// Close once the pending replies are written.
+    closing: bool,
+    /// Interest currently registered with epoll.
+    interest: u32,
+    /// When the first byte of the request still being read arrived.
+    read_started: Option<Instant>,
+    /// When pending replies were last written to, if any are pending.
+    write_stalled: Option<Instant>,
+    /// Last time anything was read or written.
+    last_active: Instant,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, peer: SocketAddr) -> Self {
+        Self {
+            stream,
+            peer,
+            input: Vec::new(),
+            frames: VecDeque::new(),
+            output: Vec::new(),
+            busy: false,
+            last_type: 0,
+            read_closed: false,
+            closing: false,
+            interest: EPOLLIN | EPOLLRDHUP,
+            read_started: None,
+            write_stalled: None,
+            last_active: Instant::now(),
+        }
+    }
+
+    /// Readiness worth waking up for. Epoll is level-triggered, so reading
+    /// stops being of interest once the peer is done or has enough queued.
+    fn interest(&self) -> u32 {
+        let mut interest = 0;
+        if !self.read_closed && !self.closing && self.frames.len() < MAX_PENDING_FRAMES {
+            interest |= EPOLLIN | EPOLLRDHUP;
+        }
+        if !self.output.is_empty() {
+            interest |= EPOLLOUT;
+        }
+        interest
+    }
+
+    fn is_idle(&self) -> bool {
+        !self.busy && self.frames.is_empty() && self.input.is_empty() && self.output.is_empty()
+    }
+
+    /// Names the limit of `timeouts` this connection broke, if any.
+    fn expired(&self, now: Instant, timeouts: &TimeoutConfig) -> Option<&'static str> {
+        let past = |since: Option<Instant>, limit: Option<Duration>| matches!((since, limit), (Some(since), Some(limit)) if now - since > limit);
+        if past(self.read_started, timeouts.read) {
+            Some("read")
+        } else if past(self.write_stalled, timeouts.write) {
+            Some("write")
+        } else if self.is_idle() && past(Some(self.last_active), timeouts.idle) {
+            Some("idle")
+        } else {
+            None
+        }
+    }
+
+    /// Queues a reply to be written.
+    fn queue_output(&mut self, reply: &[u8]) {
+        if self.output.is_empty() {
+            self.write_stalled = Some(Instant::now());
+        }
+        self.output.extend_from_slice(reply);
+    }
+
+    fn is_done(&self) -> bool {
+        (self.read_closed || self.closing)
+            && !self.busy
+            && self.frames.is_empty()
+            && self.output.is_empty()
+    }
+
+    /// Reads up to `READ_BUDGET` bytes of what the socket has and splits
+    /// them into frames.
+    fn read_frames(&mut self) -> io::Result<()> {
+        let mut chunk = [0u8; READ_CHUNK];
+        let mut read = 0;
+        while read < READ_BUDGET && self.frames.len() < MAX_PENDING_FRAMES {
+            let room = (READ_BUDGET - read).min(READ_CHUNK);
+            match self.stream.read(&mut chunk[..room]) {
+                Ok(0) => {
+                    self.read_closed = true;
+                    break;
+                }
+                Ok(n) => {
+                    self.input.extend_from_slice(&chunk[..n]);
+                    read += n;
+                    self.last_active = Instant::now();
+                    self.read_started.get_or_insert(self.last_active);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        self.split_frames();
+        Ok(())
+    }
+
+    /// Moves the complete frames at the front of the input to `frames`,
+    /// up to `MAX_PENDING_FRAMES` waiting.
+    fn split_frames(&mut self) {
+        let mut start = 0;
+        while self.frames.len() < MAX_PENDING_FRAMES && self.input.len() - start >= HEADER_LEN {
+            let header = match Header::decode(&self.input[start..start + HEADER_LEN]) {
+                Ok(header) => header,
+                Err(_) => {
+                    // Without a header there is no telling where the next
+                    // frame starts: let a worker answer this one and hang up.
+                    self.frames.push_back(self.input[start..].into());
+                    self.closing = true;
+                    start = self.input.len();
+                    break;
+                }
+            };
+            let len = HEADER_LEN + header.payload_len as usize;
+            if header.payload_len as usize > MAX_INFLATED_LEN {
+                // The truncated frame fails to decode, which gets the
+                // client an error reply before the connection closes.
+                self.frames
+                    .push_back(self.input[start..start + HEADER_LEN].into());
+                self.closing = true;
+                start = self.input.len();
+                break;
+            }
+            if self.input.len() - start < len {
+                break;
+            }
+            self.frames.push_back(self.input[start..start + len].into());
+            start += len;
+        }
+        self.input.drain(..start);
+        if self.input.is_empty() || self.frames.len() >= MAX_PENDING_FRAMES {
+            // Nothing is half read, or nothing is read until frames are
+            // handled, so the client is not the one keeping us waiting
+            self.read_started = None;
+        } else if start > 0 {
+            // A new request began within what was just read
+            self.read_started = Some(Instant::now());
+        } else {
+            self.read_started.get_or_insert_with(Instant::now);
+        }
+    }
+
+    fn write_output(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.output.drain(..n);
+                    self.last_active = Instant::now();
+                    self.write_stalled = Some(self.last_active);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if self.output.is_empty() {
+            self.write_stalled = None;
+        }
+        Ok(())
+    }
+}
+
+/// Asks an `EventLoop` to shut down gracefully.
+#[derive(Debug, Clone)]
+pub struct Stopper {
+    stop: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl Stopper {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Release);
+        let _ = self.waker.wake();
+    }
+}
+
+/// Swaps the connection limits of a running `EventLoop`.
+#[derive(Debug, Clone)]
+pub struct Reconfigurer {
+    pending: PendingConfig,
+    waker: Arc<Waker>,
+}
+
+impl Reconfigurer {
+    /// Open connections are kept; they are held to the new timeouts from
+    /// the next check on, and the new admission limits apply to arrivals.
+    pub fn reconfigure(&self, admission: AdmissionConfig, timeouts: TimeoutConfig) {
+        *self.pending.lock().unwrap() = Some((admission, timeouts));
+        let _ = self.waker.wake();
+    }
+}
+
+/// Multiplexes every client connection onto one thread.
+///
+/// The loop only moves bytes: it splits what it reads into frames and
+/// hands each to the worker pool, one frame per connection at a time, and
+/// writes back the replies the workers produce. Workers report back through
+/// a shared list and an eventfd that wakes the loop.
+pub struct EventLoop {
+    epoll: Epoll,
+    listener: TcpListener,
+    waker: Arc<Waker>,
+    completions: Completions,
+    connections: HashMap<u64, Connection>,
+    /// Connections with frames the full worker pool could not take yet.
+    stalled: VecDeque<u64>,
+    next_token: u64,
+    pool: ThreadPool,
+    admission: Admission,
+    /// Whether the listener is registered for new connections.
+    accepting: bool,
+    /// Refused connections waiting to be closed.
+    lingering: Vec<(TcpStream, Instant)>,
+    /// Set by a `Stopper` to start a graceful shutdown.
+    stop: Arc<AtomicBool>,
+    /// When the shutdown in progress gives up on open connections.
+    draining: Option<Instant>,
+    /// Set by a `Reconfigurer`.
+    pending: PendingConfig,
+    timeouts: TimeoutConfig,
+    shard_count: usize,
+    queue: Arc<ShardedQueue>,
+}
+
+impl EventLoop {
+    pub fn new(
+        listener: TcpListener,
+        pool: ThreadPool,
+        admission: AdmissionConfig,
+        timeouts: TimeoutConfig,
+        shard_count: usize,
+        queue: Arc<ShardedQueue>,
+    ) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        let epoll = Epoll::new()?;
+        let waker = Arc::new(Waker::new()?);
+        epoll.add(&listener, LISTENER, EPOLLIN)?;
+        epoll.add(&*waker, WAKER, EPOLLIN)?;
+        Ok(Self {
+            epoll,
+            listener,
+            waker,
+            completions: Arc::new(Mutex::new(Vec::new())),
+            connections: HashMap::new(),
+            stalled: VecDeque::new(),
+            next_token: FIRST_CONNECTION,
+            pool,
+            admission: Admission::new(admission),
+            accepting: true,
+            lingering: Vec::new(),
+            stop: Arc::new(AtomicBool::new(false)),
+            draining: None,
+            pending: Arc::new(Mutex::new(None)),
+            timeouts,
+            shard_count,
+            queue,
+        })
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events: Vec<Event> = Vec::with_capacity(EVENT_CAPACITY);
+        let mut next_sweep = Instant::now();
+        loop {
+            let tick = self.tick();
+            if let Some(deadline) = self.draining {
+                if self.connections.is_empty() {
+                    log_info!(global_loger(), "Every connection drained");
+                    return Ok(());
+                }
+                if Instant::now() >= deadline {
+                    log_warn!(
+                        global_loger(),
+                        "Drain deadline passed with {} connections open",
+                        self.connections.len()
+                    );
+                    return Ok(());
+                }
+            }
+            self.epoll.wait(&mut events, tick.as_millis() as i32)?;
+            for event in &events {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    WAKER => {
+                        self.waker.drain();
+                        self.complete();
+                    }
+                    token => self.ready(token, event.readiness()),
+                }
+            }
+            let pending = self.pending.lock().unwrap().take();
+            if let Some((admission, timeouts)) = pending {
+                self.reconfigure(admission, timeouts);
+            }
+            if self.draining.is_none() && self.stop.load(Ordering::Acquire) {
+                self.drain();
+            }
+            let now = Instant::now();
+            if now >= next_sweep {
+                self.sweep(now);
+                next_sweep = now + tick;
+            }
+        }
+    }
+
+    /// A handle that makes `run` drain and return, from any thread.
+    pub fn stopper(&self) -> Stopper {
+        Stopper {
+            stop: self.stop.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+
+    /// A handle that swaps the loop's limits while it runs.
+    pub fn reconfigurer(&self) -> Reconfigurer {
+        Reconfigurer {
+            pending: self.pending.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+
+    /// Timeouts are checked every quarter of the shortest one, so none is
+    /// overrun by more than that.
+    fn tick(&self) -> Duration {
+        self.timeouts.shortest().map_or(MAX_TICK, |shortest| {
+            (shortest / 4).clamp(MIN_TICK, MAX_TICK)
+        })
+    }
+
+    fn reconfigure(&mut self, admission: AdmissionConfig, timeouts: TimeoutConfig) {
+        self.admission.reconfigure(admission);
+        self.timeouts = timeouts;
+        if self.draining.is_none() {
+            // A raised limit or a switch to rejecting makes room for clients
+            // waiting in the backlog; `accept` stops again if still full
+            self.set_accepting(true);
+        }
+    }
+
+    /// Stops accepting and reading, and lets every connection close once
+    /// the requests it already sent are answered.
+    fn drain(&mut self) {
+        log_info!(
+            global_loger(),
+            "Shutting down: draining {} connections within {:?}",
+            self.connections.len(),
+            self.timeouts.drain
+        );
+        self.draining = Some(Instant::now() + self.timeouts.drain);
+        self.set_accepting(false);
+        let tokens: Vec<u64> = self.connections.keys().copied().collect();
+        for token in tokens {
+            if let Some(conn) = self.connections.get_mut(&token) {
+                conn.closing = true;
+            }
+            self.update(token);
+        }
+    }
+
+    /// Lets go of refused connections that have lingered long enough.
+    fn release_lingering(&mut self, now: Instant) {
+        self.lingering.retain_mut(|(stream, since)| {
+            // Unread input would make the close a reset
+            let mut sink = [0u8; READ_CHUNK];
+            while matches!(stream.read(&mut sink), Ok(n) if n > 0) {}
+            now - *since < LINGER
+        });
+    }
+
+    /// Closes connections that broke a timeout, and lets go of refused
+    /// ones that have lingered long enough.
+    fn sweep(&mut self, now: Instant) {
+        self.release_lingering(now);
+
+        let expired: Vec<(u64, &'static str)> = self
+            .connections
+            .iter()
+            .filter_map(|(token, conn)| Some((*token, conn.expired(now, &self.timeouts)?)))
+            .collect();
+        for (token, limit) in expired {
+            if let Some(conn) = self.connections.get(&token) {
+                log_warn!(
+                    global_loger(),
+                    { peer = conn.peer },
+                    "Closing connection: {} timeout",
+                    limit
+                );
+            }
+            self.close(token);
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            if self.admission.policy() == AdmissionPolicy::Block && !self.admission.has_room() {
+                self.set_accepting(false);
+                break;
+            }
+            match self.listener.accept() {
+                Ok((stream, peer)) => match self.admission.admit(peer.ip()) {
+                    Ok(()) => {
+                        if let Err(e) = self.register(stream, peer) {
+                            self.admission.release(peer.ip());
+                            log_error!(global_loger(), "Failed to register connection: {}", e);
+                        }
+                    }
+                    Err(refusal) => self.refuse(stream, peer, refusal),
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    log_error!(global_loger(), "Connection failed: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Tells a connection over the limits to come back later and drops it.
+    fn refuse(&mut self, mut stream: TcpStream, peer: SocketAddr, refusal: Refusal) {
+        log_warn!(
+            global_loger(),
+            { peer = peer },
+            "Rejecting connection: {:?}",
+            refusal
+        );
+        // The frame is tiny and the socket fresh, so it fits the send
+        // buffer; a client that is already gone just misses it.
+        let retry_after = match refusal {
+            Refusal::Banned => self.admission.banned_for(peer.ip()).unwrap_or_default(),
+            Refusal::Full | Refusal::PerIp => self.admission.retry_after(),
+        };
+        let _ = stream.set_nonblocking(true);
+        let _ = stream.write_all(&busy_reply(retry_after));
+        // Closing now would reset the connection as soon as the client's
+        // first request arrives, which can destroy the reply before it is
+        // read. Hang up our side and keep the socket a moment instead.
+        let _ = stream.shutdown(Shutdown::Write);
+        if self.lingering.len() < MAX_LINGERING {
+            self.lingering.push((stream, Instant::now()));
+        }
+    }
+
+    /// Starts or stops taking new connections off the listen backlog.
+    fn set_accepting(&mut self, accepting: bool) {
+        if self.accepting == accepting {
+            return;
+        }
+        let interest = if accepting { EPOLLIN } else { 0 };
+        match self.epoll.modify(&self.listener, LISTENER, interest) {
+            Ok(()) => self.accepting = accepting,
+            Err(e) => {
+                log_error!(global_loger(), "Failed to update listener: {}", e);
+            }
+        }
+    }
+
+    fn register(&mut self, stream: TcpStream, peer: SocketAddr) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        if let Some(idle) = self.timeouts.keepalive {
+            set_keepalive(&stream, idle)?;
+        }
+        let token = self.next_token;
+        self.next_token += 1;
+        self.epoll.add(&stream, token, EPOLLIN | EPOLLRDHUP)?;
+        self.connections
+            .insert(token, Connection::new(stream, peer));
+        Ok(())
+    }
+
+    fn ready(&mut self, token: u64, readiness: u32) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+        // A hung up socket can take no replies, so there is nothing to finish
+        if readiness & (EPOLLERR | EPOLLHUP) != 0 {
+            self.close(token);
+            return;
+        }
+        let mut result = Ok(());
+        if readiness & (EPOLLIN | EPOLLRDHUP) != 0 && !conn.read_closed && !conn.closing {
+            result = conn.read_frames();
+        }
+        if readiness & EPOLLOUT != 0 && result.is_ok() {
+            result = conn.write_output();
+        }
+        match result {
+            Ok(()) => self.update(token),
+            Err(e) => {
+                if e.kind() != ErrorKind::ConnectionReset {
+                    log_error!(
+                        global_loger(),
+                        { peer = conn.peer },
+                        "Failed to reead from the client {}",
+                        e
+                    );
+                }
+                self.close(token);
+            }
+        }
+    }
+
+    /// Moves finished replies into their connections.
+    fn complete(&mut self) {
+        let done = std::mem::take(&mut *self.completions.lock().unwrap());
+        for (token, reply) in done {
+            // The connection may have gone away while its frame was handled
+            let Some(conn) = self.connections.get_mut(&token) else {
+                continue;
+            };
+            conn.busy = false;
+            conn.last_active = Instant::now();
+            match reply {
+                Ok(reply) => conn.queue_output(&reply),
+                Err(message) => self.panicked(token, &message),
+            }
+            let Some(conn) = self.connections.get_mut(&token) else {
+                continue;
+            };
+            if let Err(e) = conn.write_output() {
+                log_error!(
+                    global_loger(),
+                    { peer = conn.peer },
+                    "Failed to write to the client {}",
+                    e
+                );
+                self.close(token);
+                continue;
+            }
+            self.update(token);
+        }
+        // Workers freed up, so retry connections the pool turned away
+        for _ in 0..self.stalled.len() {
+            let Some(token) = self.stalled.pop_front() else {
+                break;
+            };
+            self.update(token);
+        }
+    }
+
+    /// Answers a request whose handler panicked with an internal error, and
+    /// bans the peer once its requests have crashed workers too often.
+    fn panicked(&mut self, token: u64, message: &str) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+        let msg_type = MessageType::from_u8(conn.last_type);
+        let type_name = msg_type.map_or_else(
+            || format!("{:#04x}", conn.last_type),
+            |t| format!("{:?}", t),
+        );
+        log_error!(
+            global_loger(),
+            { peer = conn.peer, message_type = type_name },
+            "Worker panicked: {}",
+            message
+        );
+        self.pool.record_panic();
+        conn.queue_output(&internal_error_reply(
+            msg_type.unwrap_or(MessageType::Control),
+        ));
+        let ip = conn.peer.ip();
+        if self.admission.record_panic(ip, Instant::now()) {
+            log_warn!(
+                global_loger(),
+                { peer = ip },
+                "Banning for {:?} after repeated worker panics",
+                self.admission.banned_for(ip).unwrap_or_default()
+            );
+            conn.closing = true;
+        }
+    }
+
+    /// Dispatches the next frame if the connection is idle, refreshes its
+    /// epoll interest, and closes it once nothing is left to do.
+    fn update(&mut self, token: u64) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+        conn.split_frames();
+        if !conn.busy
+            && let Some(frame) = conn.frames.pop_front()
+        {
+            let shard_count = self.shard_count;
+            let queue = self.queue.clone();
+            let completions = self.completions.clone();
+            let waker = self.waker.clone();
+            let job_frame = frame.clone();
+            conn.last_type = frame[5];
+            let job = Box::new(move || {
+                let reply = panic::catch_unwind(AssertUnwindSafe(|| {
+                    handle_frame(&job_frame, shard_count, &queue)
+                }))
+                .map_err(|payload| panic_message(&*payload));
+                completions.lock().unwrap().push((token, reply));
+                let _ = waker.wake();
+            });
+            match self.pool.submit(job) {
+                Ok(()) => conn.busy = true,
+                Err(_) => {
+                    // Every worker is busy; retry once one reports back
+                    conn.frames.push_front(frame);
+                    if !self.stalled.contains(&token) {
+                        self.stalled.push_back(token);
+                    }
+                }
+            }
+        }
+        if conn.is_done() {
+            self.close(token);
+            return;
+        }
+        let interest = conn.interest();
+        if interest != conn.interest {
+            conn.interest = interest;
+            if let Err(e) = self.epoll.modify(&conn.stream, token, interest) {
+                log_error!(global_loger(), "Failed to update connection: {}", e);
+            }
+        }
+    }
+
+    /// Stops the workers once they have finished what they hold.
+    pub fn shutdown(&mut self) {
+        self.pool.shutdown();
+    }
+
+    fn close(&mut self, token: u64) {
+        if let Some(conn) = self.connections.remove(&token) {
+            let _ = self.epoll.delete(&conn.stream);
+            self.admission.release(conn.peer.ip());
+            if self.admission.has_room() && self.draining.is_none() {
+                self.set_accepting(true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ErrorCode, MAGIC, Message, MessageType, Tlv, VERSION};
+    use std::thread;
+
+    fn message(msg_type: MessageType, tlvs: Vec<Tlv>) -> Message {
+        Message {
+            header: Header {
+                magic: *MAGIC,
+                version: VERSION,
+                msg_type,
+                flags: 0,
+                payload_len: 0,
+            },
+            tlvs,
+        }
+    }
+
+    fn read_reply(stream: &mut TcpStream) -> Message {
+        let mut frame = vec![0u8; HEADER_LEN];
+        stream.read_exact(&mut frame).unwrap();
+        let header = Header::decode(&frame).unwrap();
+        frame.resize(HEADER_LEN + header.payload_len as usize, 0);
+        stream.read_exact(&mut frame[HEADER_LEN..]).unwrap();
+        Message::decode(&frame).unwrap()
+    }
+
+    fn start(pool_size: usize, admission: AdmissionConfig) -> String {
+        start_with_timeouts(pool_size, admission, TimeoutConfig::default())
+    }
+
+    fn start_with_timeouts(
+        pool_size: usize,
+        admission: AdmissionConfig,
+        timeouts: TimeoutConfig,
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let queue = Arc::new(ShardedQueue::in_memory(1));
+        let pool = ThreadPool::new(pool_size, 8);
+        let mut event_loop = EventLoop::new(listener, pool, admission, timeouts, 1, queue).unwrap();
+        thread::spawn(move || event_loop.run());
+        addr
+    }
+
+    #[test]
+    fn test_many_connections_share_few_workers() {
+        let addr = start(2, AdmissionConfig::default());
+        let clients: Vec<_> = (0..50)
+            .map(|id| {
+                let addr = addr.clone();
+                thread::spawn(move || {
+                    let mut stream = TcpStream::connect(&addr).unwrap();
+                    stream
+                        .set_read_timeout(Some(Duration::from_secs(10)))
+                        .unwrap();
+                    // A push and a pop in one write: the pop must see the push
+                    let mut frames = message(
+                        MessageType::JobPush,
+                        vec![Tlv {
+                            tag: 0x01,
+                            value: format!("job{}", id).into_bytes(),
+                        }],
+                    )
+                    .encode();
+                    frames.extend(message(MessageType::JobAck, Vec::new()).encode());
+                    stream.write_all(&frames).unwrap();
+
+                    let pushed = read_reply(&mut stream);
+                    assert_eq!(pushed.header.msg_type, MessageType::Control);
+                    assert_eq!(pushed.tlvs[0].value, [1]);
+                    let popped = read_reply(&mut stream);
+                    assert_eq!(popped.header.msg_type, MessageType::JobPush);
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_frame_split_across_reads() {
+        let addr = start(1, AdmissionConfig::default());
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let frame = message(
+            MessageType::JobPush,
+            vec![Tlv {
+                tag: 0x01,
+                value: b"split".to_vec(),
+            }],
+        )
+        .encode();
+        stream.write_all(&frame[..7]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stream.write_all(&frame[7..]).unwrap();
+        assert_eq!(read_reply(&mut stream).tlvs[0].value, [1]);
+
+        // Garbage gets an error reply, then the broker hangs up
+        stream.write_all(&[0xAB; HEADER_LEN]).unwrap();
+        let reply = read_reply(&mut stream);
+        assert_eq!(reply.tlvs[0].value, [0]);
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    fn push(stream: &mut TcpStream) -> Message {
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let frame = message(
+            MessageType::JobPush,
+            vec![Tlv {
+                tag: 0x01,
+                value: b"job".to_vec(),
+            }],
+        );
+        stream.write_all(&frame.encode()).unwrap();
+        read_reply(stream)
+    }
+
+    #[test]
+    fn test_pipelined_reads_stay_within_budget_and_frame_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, peer) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut conn = Connection::new(stream, peer);
+
+        let frame = message(
+            MessageType::JobPush,
+            vec![Tlv {
+                tag: 0x01,
+                value: b"job".to_vec(),
+            }],
+        )
+        .encode();
+        let count = 4 * READ_BUDGET / frame.len();
+        let frames = frame.repeat(count);
+        let writer = thread::spawn(move || {
+            client.write_all(&frames).unwrap();
+            client
+        });
+
+        let buffered = |conn: &Connection| {
+            conn.input.len() + conn.frames.iter().map(|f| f.len()).sum::<usize>()
+        };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut handled = 0;
+        while handled < count {
+            assert!(Instant::now() < deadline, "{} of {} frames", handled, count);
+            let before = buffered(&conn);
+            conn.read_frames().unwrap();
+            assert!(buffered(&conn) - before <= READ_BUDGET);
+            assert!(conn.frames.len() <= MAX_PENDING_FRAMES);
+            if conn.frames.len() == MAX_PENDING_FRAMES {
+                // Frames held back in the input have fully arrived
+                assert!(conn.read_started.is_none());
+            }
+            // A worker takes the frames; the rest come out of the input
+            // before anything more is read
+            while let Some(split) = conn.frames.pop_front() {
+                assert_eq!(split[..], frame[..]);
+                handled += 1;
+                conn.split_frames();
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(conn.input.is_empty());
+        drop(writer.join().unwrap());
+    }
+
+    #[test]
+    fn test_answers_every_pipelined_request() {
+        let addr = start(2, AdmissionConfig::default());
+        let mut client = TcpStream::connect(&addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let count = 5000;
+        let mut frames = Vec::new();
+        for id in 0..count {
+            let job = message(
+                MessageType::JobPush,
+                vec![Tlv {
+                    tag: 0x01,
+                    value: format!("job{}", id).into_bytes(),
+                }],
+            );
+            frames.extend(job.encode());
+        }
+        let mut writer = client.try_clone().unwrap();
+        let writing = thread::spawn(move || writer.write_all(&frames).unwrap());
+
+        // Another client is served while the first one's requests queue up
+        let mut other = TcpStream::connect(&addr).unwrap();
+        assert_eq!(push(&mut other).tlvs[0].value, [1]);
+        for _ in 0..count {
+            assert_eq!(read_reply(&mut client).tlvs[0].value, [1]);
+        }
+        writing.join().unwrap();
+    }
+
+    #[test]
+    fn test_rejects_connections_over_the_per_ip_limit() {
+        let addr = start(
+            1,
+            AdmissionConfig {
+                max_per_ip: Some(1),
+                retry_after: Duration::from_millis(250),
+                ..Default::default()
+            },
+        );
+        let mut first = TcpStream::connect(&addr).unwrap();
+        assert_eq!(push(&mut first).tlvs[0].value, [1]);
+
+        let mut second = TcpStream::connect(&addr).unwrap();
+        let reply = push(&mut second);
+        assert_eq!(reply.tlvs[3].value, [ErrorCode::ServerBusy as u8]);
+        assert_eq!(reply.tlvs[4].value, 250u32.to_be_bytes());
+        assert_eq!(second.read(&mut [0u8; 1]).unwrap(), 0);
+
+        // The slot frees up when the first connection goes
+        drop(first);
+        thread::sleep(Duration::from_millis(50));
+        let mut third = TcpStream::connect(&addr).unwrap();
+        assert_eq!(push(&mut third).tlvs[0].value, [1]);
+    }
+
+    #[test]
+    fn test_blocks_accepting_while_full() {
+        let addr = start(
+            1,
+            AdmissionConfig {
+                max_connections: 1,
+                policy: AdmissionPolicy::Block,
+                ..Default::default()
+            },
+        );
+        let mut first = TcpStream::connect(&addr).unwrap();
+        assert_eq!(push(&mut first).tlvs[0].value, [1]);
+
+        // Sits in the backlog, unanswered, until the first one closes
+        let mut second = TcpStream::connect(&addr).unwrap();
+        let waiting = thread::spawn(move || push(&mut second));
+        thread::sleep(Duration::from_millis(100));
+        assert!(!waiting.is_finished());
+        drop(first);
+        assert_eq!(waiting.join().unwrap().tlvs[0].value, [1]);
+    }
+
+    #[test]
+    fn test_closes_idle_and_stalled_connections() {
+        let addr = start_with_timeouts(
+            1,
+            AdmissionConfig::default(),
+            TimeoutConfig {
+                read: Some(Duration::from_millis(200)),
+                idle: Some(Duration::from_millis(400)),
+                ..Default::default()
+            },
+        );
+        let mut idle = TcpStream::connect(&addr).unwrap();
+        assert_eq!(push(&mut idle).tlvs[0].value, [1]);
+        let mut stalled = TcpStream::connect(&addr).unwrap();
+        stalled
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        // Half a header, then nothing
+        stalled.write_all(&MAGIC[..]).unwrap();
+
+        let started = Instant::now();
+        assert_eq!(stalled.read(&mut [0u8; 1]).unwrap(), 0);
+        let read_closed = started.elapsed();
+        assert_eq!(idle.read(&mut [0u8; 1]).unwrap(), 0);
+        let idle_closed = started.elapsed();
+        assert!(
+            read_closed >= Duration::from_millis(200),
+            "{:?}",
+            read_closed
+        );
+        assert!(idle_closed > read_closed, "{:?}", idle_closed);
+    }
+
+    #[test]
+    fn test_stop_drains_requests_already_received() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let queue = Arc::new(ShardedQueue::in_memory(1));
+        let mut event_loop = EventLoop::new(
+            listener,
+            ThreadPool::new(1, 8),
+            AdmissionConfig::default(),
+            TimeoutConfig::default(),
+            1,
+            queue.clone(),
+        )
+        .unwrap();
+        let stopper = event_loop.stopper();
+        let running = thread::spawn(move || event_loop.run());
+
+        let mut idle = TcpStream::connect(&addr).unwrap();
+        idle.set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut client = TcpStream::connect(&addr).unwrap();
+        let mut frames = Vec::new();
+        for id in 0..20 {
+            let job = message(
+                MessageType::JobPush,
+                vec![Tlv {
+                    tag: 0x01,
+                    value: format!("job{}", id).into_bytes(),
+                }],
+            );
+            frames.extend(job.encode());
+        }
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        client.write_all(&frames).unwrap();
+        assert_eq!(read_reply(&mut client).tlvs[0].value, [1]);
+
+        stopper.stop();
+        for _ in 1..20 {
+            assert_eq!(read_reply(&mut client).tlvs[0].value, [1]);
+        }
+        assert_eq!(client.read(&mut [0u8; 1]).unwrap(), 0);
+        assert_eq!(idle.read(&mut [0u8; 1]).unwrap(), 0);
+        running.join().unwrap().unwrap();
+        assert_eq!(queue.pop_batch(0, usize::MAX).unwrap().len(), 20);
+    }
+}
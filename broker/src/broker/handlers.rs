@@ -1,46 +1,84 @@
+use crate::broker::admin::global_admin;
+use crate::broker::reload::{Reloader, global_reloader};
+use crate::broker::threadpool::{PoolStats, global_pool_monitor};
+use crate::cluster::global_cluster;
+use crate::export::json;
+use crate::log_debug;
 use crate::log_error;
 use crate::log_info;
-use crate::logger::global_loger;
-use crate::protocol::{Header, MAGIC, Message, MessageType, Tlv, VERSION};
-use crate::shards::ShardedQueue;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use crate::log_warn;
+use crate::logger::{LogFilter, global_loger};
+use crate::protocol::{
+    ErrorCode, Header, MAGIC, Message, MessageType, TAG_MIN_REPLICAS, Tlv, VERSION,
+};
+use crate::replication::{LeaderStats, Node, global_node};
+use crate::shards::{ShardStats, ShardedQueue, compute_shard_key};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
-pub fn handle_client(mut stream: TcpStream, shard_count: usize, queue: Arc<ShardedQueue>) {
-    let mut buf = [0u8; 4092];
+/// Replies to one request. They are compressed when the request they
+/// answer was.
+struct Peer {
+    out: Vec<u8>,
+    compress: bool,
+}
 
-    loop {
-        match stream.read(&mut buf) {
-            Ok(0) => break, // connection close
-            Ok(n) => match Message::decode(&buf[..n]) {
-                Ok(msg) => dispatch_message(msg, shard_count, &queue, &mut stream),
-                Err(e) => {
-                    send_success_or_error_message(
-                        &mut stream,
-                        MessageType::Control,
-                        "failed to decode",
-                        0,
-                    );
-                    log_error!(global_loger(), "Failed to decode the message {}", e);
-                }
-            },
-            Err(e) => {
-                log_error!(global_loger(), "Failed to reead from the client {}", e);
-            }
+impl Peer {
+    fn send(&mut self, msg: &Message) -> std::io::Result<()> {
+        let encoded = if self.compress {
+            msg.encode_compressed()
+        } else {
+            msg.encode()
+        };
+        self.out.extend_from_slice(&encoded);
+        Ok(())
+    }
+}
+
+/// Handles one frame read off a connection and returns the encoded replies
+/// to write back.
+pub fn handle_frame(frame: &[u8], shard_count: usize, queue: &Arc<ShardedQueue>) -> Vec<u8> {
+    let mut peer = Peer {
+        out: Vec::new(),
+        compress: false,
+    };
+    match Message::decode(frame) {
+        Ok(msg) => {
+            peer.compress = msg.is_compressed();
+            dispatch_message(msg, shard_count, queue, &mut peer)
+        }
+        Err(e) => {
+            send_success_or_error_message(&mut peer, MessageType::Control, "failed to decode", 0);
+            log_error!(global_loger(), "Failed to decode the message {}", e);
         }
     }
+    peer.out
 }
 
-fn dispatch_message(
-    msg: Message,
-    shard_count: usize,
-    queue: &Arc<ShardedQueue>,
-    stream: &mut TcpStream,
-) {
-    match msg.header.msg_type {
-        MessageType::JobPush => handle_job_push(stream, shard_count, msg, queue),
-        MessageType::JobAck => handle_job_ack(stream, shard_count, msg, queue),
+fn dispatch_message(msg: Message, shard_count: usize, queue: &Arc<ShardedQueue>, peer: &mut Peer) {
+    let msg_type = msg.header.msg_type;
+    // Pops change the queue too, so a follower refuses both
+    if matches!(msg_type, MessageType::JobPush | MessageType::JobAck) && global_node().is_follower()
+    {
+        send_error_message(peer, msg_type, ErrorCode::ReadOnly, "follower is read-only");
+        return;
+    }
+    if matches!(msg_type, MessageType::JobPush | MessageType::JobAck)
+        && let Some(cluster) = global_cluster()
+    {
+        let key = compute_shard_key(&msg, shard_count);
+        if !cluster.owns(key) {
+            send_redirect(peer, msg_type, cluster.owner(key));
+            return;
+        }
+    }
+    match msg_type {
+        MessageType::JobPush => handle_job_push(peer, shard_count, msg, queue),
+        MessageType::JobAck => handle_job_ack(peer, shard_count, msg, queue),
+        MessageType::Control => handle_control(peer, msg, queue),
         _ => {
             log_error!(
                 global_loger(),
@@ -52,57 +90,476 @@ fn dispatch_message(
 }
 
 fn handle_job_push(
-    stream: &mut TcpStream,
+    peer: &mut Peer,
     shard_count: usize,
-    msg: Message,
+    mut msg: Message,
     queue: &Arc<ShardedQueue>,
 ) {
+    let min_replicas = take_min_replicas(&mut msg);
     let key = compute_shard_key(&msg, shard_count);
-    queue.push(key, msg.clone());
-    log_info!(global_loger(), "Recieved {:?} ", msg);
-    send_success_or_error_message(stream, MessageType::JobAck, "success", 1);
+    let job = job_id(&msg);
+    if let Err(e) = queue.push(key, msg.clone()) {
+        if e.kind() == ErrorKind::StorageFull {
+            log_warn!(global_loger(), { shard = key, job = job }, "Rejected push to full shard");
+            send_error_message(
+                peer,
+                MessageType::JobPush,
+                ErrorCode::QueueFull,
+                "queue full",
+            );
+        } else {
+            log_error!(
+                global_loger(),
+                { shard = key, job = job },
+                "Failed to store the message: {}",
+                e
+            );
+            send_error_message(
+                peer,
+                MessageType::JobPush,
+                ErrorCode::Internal,
+                "failed to store",
+            );
+        }
+        return;
+    }
+    log_debug!(
+        global_loger(),
+        { shard = key, job = job },
+        "Recieved push with {} TLVs",
+        msg.tlvs.len()
+    );
+    if min_replicas > 0
+        && let Err(e) = global_node().wait_for_replicas(queue.feed(), min_replicas)
+    {
+        log_warn!(global_loger(), { shard = key, job = job }, "Push {}", e);
+        send_error_message(
+            peer,
+            MessageType::JobPush,
+            ErrorCode::ReplicationTimeout,
+            "replication timeout",
+        );
+        return;
+    }
+    send_success_or_error_message(peer, MessageType::JobAck, "success", 1);
 }
 
-fn handle_job_ack(
-    stream: &mut TcpStream,
-    shard_count: usize,
-    msg: Message,
-    queue: &Arc<ShardedQueue>,
-) {
+/// The job id producers put in the first TLV, for logs.
+fn job_id(msg: &Message) -> String {
+    msg.tlvs
+        .first()
+        .map(|tlv| String::from_utf8_lossy(&tlv.value).into_owned())
+        .unwrap_or_default()
+}
+
+/// Removes the replica count a producer may attach to a push, so it is not
+/// stored with the job.
+fn take_min_replicas(msg: &mut Message) -> usize {
+    match msg.tlvs.iter().position(|tlv| tlv.tag == TAG_MIN_REPLICAS) {
+        Some(at) => msg.tlvs.remove(at).value.first().copied().unwrap_or(0) as usize,
+        None => 0,
+    }
+}
+
+fn handle_job_ack(peer: &mut Peer, shard_count: usize, msg: Message, queue: &Arc<ShardedQueue>) {
     let key = compute_shard_key(&msg, shard_count);
     let response = queue.pop(key);
     match response {
-        Some(msg) => {
-            let encoded = msg.encode();
+        Ok(Some(msg)) => {
+            if let Err(e) = peer.send(&msg) {
+                log_error!(
+                    global_loger(),
+                    { shard = key, job = job_id(&msg) },
+                    "Failed to send ack: {}",
+                    e
+                );
+            };
+        }
+        Ok(None) => {
+            send_success_or_error_message(peer, MessageType::Control, "No message to pop", 0);
+        }
+        Err(e) => {
+            log_error!(global_loger(), { shard = key }, "Failed to pop: {}", e);
+            send_error_message(
+                peer,
+                MessageType::Control,
+                ErrorCode::Internal,
+                "failed to read the queue",
+            );
+        }
+    }
+}
 
-            if let Err(e) = stream.write_all(&encoded) {
-                log_error!(global_loger(), "Failed to send ack: {}", e);
+/// Admin commands arrive as Control frames whose first TLV holds the command
+/// name and whose second TLV, when present, holds its argument. Commands
+/// that touch files, the replication role or the settings take the admin
+/// token in a third TLV.
+fn handle_control(peer: &mut Peer, msg: Message, queue: &Arc<ShardedQueue>) {
+    let tlv_text = |i: usize| {
+        msg.tlvs
+            .get(i)
+            .map(|tlv| String::from_utf8_lossy(&tlv.value).into_owned())
+            .unwrap_or_default()
+    };
+    let command = tlv_text(0);
+    match command.as_str() {
+        "stats" => {
+            let details = stats_json(
+                &queue.stats(),
+                &replication_json(queue),
+                &cluster_json(),
+                &workers_json(),
+                &log_json(),
+            );
+            send_success_or_error_message(peer, MessageType::Control, &details, 1);
+        }
+        "export" => {
+            let Some(path) = admin_path(peer, "export", &tlv_text(1), &msg) else {
+                return;
             };
+            // Never overwrites, so an export cannot clobber another file
+            let result = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .and_then(|file| {
+                    let mut out = BufWriter::new(file);
+                    let count = queue.export(&mut out)?;
+                    out.flush()?;
+                    Ok(count)
+                });
+            reply_admin_result(peer, "export", &path, result);
         }
-        None => {
-            send_success_or_error_message(stream, MessageType::Control, "No message to pop", 0);
+        "import" => {
+            let Some(path) = admin_path(peer, "import", &tlv_text(1), &msg) else {
+                return;
+            };
+            let result = File::open(&path).and_then(|file| queue.import(BufReader::new(file)));
+            reply_admin_result(peer, "import", &path, result);
+        }
+        "backup" => {
+            let Some(path) = admin_path(peer, "backup", &tlv_text(1), &msg) else {
+                return;
+            };
+            let result = queue.backup(&path);
+            reply_admin_result(peer, "backup", &path, result);
+        }
+        "promote" => handle_promote(peer, &msg, &global_node()),
+        "log_level" => {
+            if !authorized(peer, "log_level", &msg) {
+                return;
+            }
+            let filter = tlv_text(1);
+            if filter.is_empty() {
+                let details = global_loger().filter().to_string();
+                send_success_or_error_message(peer, MessageType::Control, &details, 1);
+                return;
+            }
+            match filter.parse::<LogFilter>() {
+                Ok(filter) => {
+                    log_warn!(global_loger(), "Log level set to {}", filter);
+                    let details = format!("log level set to {}", filter);
+                    global_loger().set_filter(filter);
+                    send_success_or_error_message(peer, MessageType::Control, &details, 1);
+                }
+                Err(e) => {
+                    let details = format!("log_level failed: {}", e);
+                    send_success_or_error_message(peer, MessageType::Control, &details, 0);
+                }
+            }
+        }
+        "reload" => {
+            if !authorized(peer, "reload", &msg) {
+                return;
+            }
+            match global_reloader().map(Reloader::reload) {
+                Some(Ok(changes)) => {
+                    let details = if changes.is_empty() {
+                        "reloaded, no changes".to_string()
+                    } else {
+                        format!("reloaded {}", changes.join(", "))
+                    };
+                    send_success_or_error_message(peer, MessageType::Control, &details, 1)
+                }
+                Some(Err(e)) => {
+                    log_error!(global_loger(), "Reload failed, keeping settings: {}", e);
+                    let details = format!("reload failed: {}", e);
+                    send_error_message(peer, MessageType::Control, ErrorCode::Internal, &details);
+                }
+                None => {
+                    let details = "reload failed: no configuration source";
+                    send_error_message(peer, MessageType::Control, ErrorCode::Internal, details);
+                }
+            }
+        }
+        _ => {
+            log_error!(global_loger(), "Unknown control command: {:?}", command);
+            send_success_or_error_message(peer, MessageType::Control, "unknown control command", 0);
         }
     }
 }
 
-fn compute_shard_key(msg: &Message, shard_count: usize) -> usize {
-    if let Some(tlv) = msg.tlvs.first() {
-        let mut hash = 0usize;
-        for b in &tlv.value {
-            hash = hash.wrapping_mul(31).wrapping_add(*b as usize);
+/// Turns a follower into a leader. A second leader splits the cluster, so
+/// only an admin may.
+fn handle_promote(peer: &mut Peer, msg: &Message, node: &Node) {
+    if !authorized(peer, "promote", msg) {
+        return;
+    }
+    match node.promote() {
+        Ok(()) => {
+            send_success_or_error_message(peer, MessageType::Control, "promoted to leader", 1)
+        }
+        Err(e) => {
+            let details = format!("promote failed: {}", e);
+            send_error_message(peer, MessageType::Control, ErrorCode::Internal, &details);
         }
-        return hash % shard_count;
     }
-    0
 }
 
-fn send_success_or_error_message(
-    stream: &mut TcpStream,
-    msg_type: MessageType,
-    details: &str,
-    flag: u16,
-) {
-    let msg = Message {
+/// Whether `msg` carries the admin token in its third TLV. A refusal is
+/// answered here.
+fn authorized(peer: &mut Peer, command: &str, msg: &Message) -> bool {
+    let token = msg.tlvs.get(2).map_or(&[][..], |tlv| &tlv.value);
+    if global_admin().authorize(token) {
+        return true;
+    }
+    log_warn!(
+        global_loger(),
+        "Refused {} without a valid admin token",
+        command
+    );
+    let details = format!("{} refused: missing or wrong admin token", command);
+    send_error_message(
+        peer,
+        MessageType::Control,
+        ErrorCode::Unauthorized,
+        &details,
+    );
+    false
+}
+
+/// The file `name` stands for in the admin directory, once the token in
+/// `msg` checks out. A refusal is answered here and gives `None`.
+fn admin_path(peer: &mut Peer, command: &str, name: &str, msg: &Message) -> Option<PathBuf> {
+    if !authorized(peer, command, msg) {
+        return None;
+    }
+    match global_admin().resolve(name) {
+        Ok(path) => Some(path),
+        Err(e) => {
+            reply_admin_result(peer, command, Path::new(name), Err(e));
+            None
+        }
+    }
+}
+
+fn reply_admin_result(peer: &mut Peer, command: &str, path: &Path, result: std::io::Result<usize>) {
+    match result {
+        Ok(count) => {
+            log_info!(
+                global_loger(),
+                "{} of {} messages via {}",
+                command,
+                count,
+                path.display()
+            );
+            let done = match command {
+                "backup" => "backed up".to_string(),
+                _ => format!("{}ed", command),
+            };
+            let details = format!("{} {} messages", done, count);
+            send_success_or_error_message(peer, MessageType::Control, &details, 1);
+        }
+        Err(e) => {
+            log_error!(
+                global_loger(),
+                "{} via {} failed: {}",
+                command,
+                path.display(),
+                e
+            );
+            let details = format!("{} failed: {}", command, e);
+            send_error_message(peer, MessageType::Control, ErrorCode::Internal, &details);
+        }
+    }
+}
+
+fn cluster_json() -> String {
+    let Some(cluster) = global_cluster() else {
+        return "null".to_string();
+    };
+    let members: Vec<String> = cluster
+        .members()
+        .iter()
+        .map(|m| format!("\"{}\"", m))
+        .collect();
+    let owned: Vec<String> = cluster
+        .owned_shards()
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    format!(
+        "{{\"self\":\"{}\",\"members\":[{}],\"owned_shards\":[{}]}}",
+        cluster.advertise(),
+        members.join(","),
+        owned.join(",")
+    )
+}
+
+fn replication_json(queue: &ShardedQueue) -> String {
+    match global_node().leader_stats() {
+        Some(LeaderStats {
+            addr,
+            connected,
+            applied,
+            leader_lsn,
+            lag,
+            last_contact_ms,
+        }) => format!(
+            "{{\"role\":\"follower\",\"leader\":\"{}\",\"connected\":{},\"applied\":{},\"leader_lsn\":{},\"lag\":{},\"last_contact_ms\":{}}}",
+            addr, connected, applied, leader_lsn, lag, last_contact_ms
+        ),
+        None => {
+            let followers: Vec<String> = queue
+                .feed()
+                .followers()
+                .iter()
+                .map(|follower| {
+                    // Names come from the follower's hello
+                    let mut name = String::new();
+                    json::escape(&mut name, &follower.name);
+                    format!(
+                        "{{\"addr\":\"{}\",\"name\":{},\"acked\":{},\"lag\":{}}}",
+                        follower.addr, name, follower.acked, follower.lag
+                    )
+                })
+                .collect();
+            format!(
+                "{{\"role\":\"leader\",\"lsn\":{},\"followers\":[{}]}}",
+                queue.feed().head(),
+                followers.join(",")
+            )
+        }
+    }
+}
+
+fn workers_json() -> String {
+    let Some(monitor) = global_pool_monitor() else {
+        return "null".to_string();
+    };
+    let stats @ PoolStats {
+        min_workers,
+        max_workers,
+        workers,
+        busy,
+        queued,
+        panics,
+    } = monitor.stats();
+    format!(
+        "{{\"size\":{},\"min\":{},\"max\":{},\"busy\":{},\"queued\":{},\"utilisation\":{:.2},\"panics\":{}}}",
+        workers,
+        min_workers,
+        max_workers,
+        busy,
+        queued,
+        stats.utilisation(),
+        panics
+    )
+}
+
+fn log_json() -> String {
+    let logger = global_loger();
+    format!(
+        "{{\"buffered\":{},\"dropped\":{}}}",
+        logger.buffered(),
+        logger.dropped()
+    )
+}
+
+fn stats_json(
+    stats: &[ShardStats],
+    replication: &str,
+    cluster: &str,
+    workers: &str,
+    log: &str,
+) -> String {
+    let shards: Vec<String> = stats
+        .iter()
+        .map(|s| {
+            format!(
+                "{{\"id\":{},\"in_memory\":{},\"in_memory_bytes\":{},\"on_disk\":{},\"on_disk_bytes\":{}}}",
+                s.id, s.in_memory, s.in_memory_bytes, s.on_disk, s.on_disk_bytes
+            )
+        })
+        .collect();
+    format!(
+        "{{\"shards\":[{}],\"replication\":{},\"cluster\":{},\"workers\":{},\"log\":{}}}",
+        shards.join(","),
+        replication,
+        cluster,
+        workers,
+        log
+    )
+}
+
+fn send_success_or_error_message(peer: &mut Peer, msg_type: MessageType, details: &str, flag: u16) {
+    let msg = control_message(msg_type, details, flag);
+    write_message(peer, &msg);
+}
+
+/// Sends a failed Control response that also carries a machine-readable
+/// error code, so clients can tell e.g. backpressure from a broker fault.
+fn send_error_message(peer: &mut Peer, msg_type: MessageType, code: ErrorCode, details: &str) {
+    let mut msg = control_message(msg_type, details, 0);
+    msg.tlvs.push(Tlv {
+        tag: 0x04,
+        value: vec![code as u8],
+    });
+    write_message(peer, &msg);
+}
+
+/// Tells the client which cluster member owns the shard of its request.
+fn send_redirect(peer: &mut Peer, msg_type: MessageType, owner: &str) {
+    let mut msg = control_message(msg_type, "redirect", 0);
+    msg.tlvs.push(Tlv {
+        tag: 0x04,
+        value: vec![ErrorCode::Redirect as u8],
+    });
+    msg.tlvs.push(Tlv {
+        tag: 0x05,
+        value: owner.as_bytes().to_vec(),
+    });
+    write_message(peer, &msg);
+}
+
+/// The frame sent to a connection turned away at admission, before it
+/// has sent anything.
+pub fn busy_reply(retry_after: Duration) -> Vec<u8> {
+    let mut msg = control_message(MessageType::Control, "server busy", 0);
+    msg.tlvs.push(Tlv {
+        tag: 0x04,
+        value: vec![ErrorCode::ServerBusy as u8],
+    });
+    let millis = retry_after.as_millis().min(u32::MAX as u128) as u32;
+    msg.tlvs.push(Tlv {
+        tag: 0x06,
+        value: millis.to_be_bytes().to_vec(),
+    });
+    msg.encode()
+}
+
+/// The reply to a request whose handler panicked.
+pub fn internal_error_reply(msg_type: MessageType) -> Vec<u8> {
+    let mut msg = control_message(msg_type, "internal error", 0);
+    msg.tlvs.push(Tlv {
+        tag: 0x04,
+        value: vec![ErrorCode::Internal as u8],
+    });
+    msg.encode()
+}
+
+fn control_message(msg_type: MessageType, details: &str, flag: u16) -> Message {
+    Message {
         header: Header {
             magic: *MAGIC,
             version: VERSION,
@@ -124,9 +581,81 @@ fn send_success_or_error_message(
                 value: details.as_bytes().to_vec(),
             }, // error details
         ],
-    };
-    let encoded = msg.encode();
-    if let Err(e) = stream.write_all(&encoded) {
+    }
+}
+
+fn write_message(peer: &mut Peer, msg: &Message) {
+    if let Err(e) = peer.send(msg) {
         log_error!(global_loger(), "Failed to send msg to the client: {}", e);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replication::ReplicationConfig;
+    use std::net::TcpListener;
+
+    fn command(tlvs: &[&str]) -> Message {
+        let mut msg = control_message(MessageType::Control, "", 0);
+        msg.tlvs = tlvs
+            .iter()
+            .map(|value| Tlv {
+                tag: 0x01,
+                value: value.as_bytes().to_vec(),
+            })
+            .collect();
+        msg
+    }
+
+    fn error_code(reply: &[u8]) -> Option<u8> {
+        let reply = Message::decode(reply).unwrap();
+        let code = reply.tlvs.iter().find(|tlv| tlv.tag == 0x04)?;
+        code.value.first().copied()
+    }
+
+    #[test]
+    fn test_promote_needs_the_admin_token() {
+        let config = ReplicationConfig {
+            secret: Some("s3cret".to_string()),
+            ..ReplicationConfig::default()
+        };
+        // Nothing listens there; the node stays a follower regardless
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let leader = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let node = Node::with_config(config);
+        node.follow(&leader, Arc::new(ShardedQueue::in_memory(1)))
+            .unwrap();
+
+        // No token is configured in tests, so none is right
+        for msg in [command(&["promote"]), command(&["promote", "", "guess"])] {
+            let mut peer = Peer {
+                out: Vec::new(),
+                compress: false,
+            };
+            handle_promote(&mut peer, &msg, &node);
+            assert_eq!(error_code(&peer.out), Some(ErrorCode::Unauthorized as u8));
+            assert!(node.is_follower());
+        }
+        node.promote().unwrap();
+    }
+
+    #[test]
+    fn test_settings_commands_need_the_admin_token() {
+        let queue = Arc::new(ShardedQueue::in_memory(1));
+        let mut peer = Peer {
+            out: Vec::new(),
+            compress: false,
+        };
+        for msg in [
+            command(&["reload", "", "guess"]),
+            command(&["log_level", "warn"]),
+            command(&["log_level", "", "guess"]),
+        ] {
+            peer.out.clear();
+            handle_control(&mut peer, msg, &queue);
+            assert_eq!(error_code(&peer.out), Some(ErrorCode::Unauthorized as u8));
+        }
+    }
+}
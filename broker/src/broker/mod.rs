@@ -1,3 +1,10 @@
+pub mod admin;
+pub mod admission;
+pub mod epoll;
+pub mod event_loop;
 pub mod handlers;
+pub mod reload;
 pub mod server;
+pub mod signals;
 pub mod threadpool;
+pub mod timeouts;
@@ -0,0 +1,198 @@
+//! Picking up changed settings without a restart.
+
+use crate::broker::admin::set_global_admin;
+use crate::broker::event_loop::Reconfigurer;
+use crate::broker::server::ServerConfig;
+use crate::config::{RELOADABLE, SECRETS, describe};
+use crate::log_info;
+use crate::log_warn;
+use crate::logger::{Level, global_loger};
+use crate::shards::ShardedQueue;
+use std::io;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Reads the settings again, the same way they were read at startup.
+pub type ConfigSource = Box<dyn Fn() -> io::Result<ServerConfig> + Send + Sync>;
+
+/// Applies the reloadable settings of a fresh `ConfigSource` read to the
+/// running broker. Connections stay open throughout.
+pub struct Reloader {
+    source: ConfigSource,
+    /// The settings in effect.
+    current: Mutex<ServerConfig>,
+    queue: Arc<ShardedQueue>,
+    event_loop: Reconfigurer,
+}
+
+impl Reloader {
+    pub fn new(
+        source: ConfigSource,
+        current: ServerConfig,
+        queue: Arc<ShardedQueue>,
+        event_loop: Reconfigurer,
+    ) -> Self {
+        Self {
+            source,
+            current: Mutex::new(current),
+            queue,
+            event_loop,
+        }
+    }
+
+    /// Rereads the settings and applies what changed, returning each change
+    /// as `key: old -> new`. Changes to settings that need a restart are
+    /// logged and left alone. On a read error nothing changes.
+    pub fn reload(&self) -> io::Result<Vec<String>> {
+        let next = (self.source)()?;
+        let mut current = self.current.lock().unwrap();
+        let before = describe(&current);
+        let after = describe(&next);
+
+        let mut changes = Vec::new();
+        let mut ignored = Vec::new();
+        for (key, old) in &before {
+            let new = &after[key];
+            if old == new {
+                continue;
+            }
+            if SECRETS.contains(key) && RELOADABLE.contains(key) {
+                changes.push(format!("{}: <redacted> -> <redacted>", key));
+            } else if RELOADABLE.contains(key) {
+                changes.push(format!("{}: {} -> {}", key, old, new));
+            } else {
+                ignored.push(*key);
+            }
+        }
+        if !ignored.is_empty() {
+            log_warn!(
+                global_loger(),
+                "Changed settings need a restart to apply: {}",
+                ignored.join(", ")
+            );
+        }
+        if changes.is_empty() {
+            log_info!(global_loger(), "Reloaded configuration: no changes");
+            return Ok(changes);
+        }
+
+        current.log_level = next.log_level;
+        current.log_format = next.log_format;
+        current.log_buffer = next.log_buffer;
+        current.log_when_full = next.log_when_full;
+        current.queue.max_depth = next.queue.max_depth;
+        current.queue.max_bytes = next.queue.max_bytes;
+        current.queue.overflow = next.queue.overflow;
+        current.admission = next.admission;
+        current.timeouts = next.timeouts;
+        current.admin.token = next.admin.token;
+
+        self.queue
+            .set_limits(current.queue.max_depth, current.queue.max_bytes);
+        self.queue.set_overflow(current.queue.overflow);
+        self.event_loop
+            .reconfigure(current.admission.clone(), current.timeouts.clone());
+        set_global_admin(current.admin.clone());
+        // The diff goes out under whichever level shows it, so turning info
+        // logging on or off still records the change
+        let logger = global_loger();
+        logger.set_format(current.log_format);
+        logger.set_buffer(current.log_buffer, current.log_when_full);
+        if !logger.enabled(module_path!(), Level::Info) {
+            logger.set_filter(current.log_level.clone());
+        }
+        log_info!(logger, "Reloaded configuration: {}", changes.join(", "));
+        logger.set_filter(current.log_level.clone());
+        Ok(changes)
+    }
+}
+
+static RELOADER: OnceLock<Reloader> = OnceLock::new();
+
+pub fn init_global_reloader(reloader: Reloader) -> io::Result<()> {
+    RELOADER.set(reloader).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "Global reloader already initialized",
+        )
+    })
+}
+
+/// The running broker's reloader, if it has one.
+pub fn global_reloader() -> Option<&'static Reloader> {
+    RELOADER.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::admin::global_admin;
+    use crate::broker::event_loop::EventLoop;
+    use crate::broker::threadpool::ThreadPool;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[test]
+    fn test_reload_applies_only_reloadable_changes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let queue = Arc::new(ShardedQueue::in_memory(1));
+        let config = ServerConfig::default();
+        let event_loop = EventLoop::new(
+            listener,
+            ThreadPool::new(1, 1),
+            config.admission.clone(),
+            config.timeouts.clone(),
+            1,
+            queue.clone(),
+        )
+        .unwrap();
+
+        let next = Arc::new(Mutex::new(config.clone()));
+        let source: ConfigSource = {
+            let next = next.clone();
+            Box::new(move || Ok(next.lock().unwrap().clone()))
+        };
+        let reloader = Reloader::new(source, config, queue, event_loop.reconfigurer());
+        assert!(reloader.reload().unwrap().is_empty());
+
+        {
+            let mut next = next.lock().unwrap();
+            next.queue.max_depth = Some(10);
+            next.admission.max_connections = 5;
+            next.timeouts.idle = None;
+            next.shard_count = 16;
+            next.admin.token = Some("rotated".to_string());
+        }
+        let changes = reloader.reload().unwrap();
+        assert_eq!(
+            changes,
+            [
+                "admin_token: <redacted> -> <redacted>",
+                "idle_timeout_ms: 300000 -> 0",
+                "max_connections: 10000 -> 5",
+                "max_depth: 1000000 -> 10",
+            ]
+        );
+        let current = reloader.current.lock().unwrap();
+        assert_eq!(current.queue.max_depth, Some(10));
+        assert_eq!(current.admission.max_connections, 5);
+        assert_eq!(current.timeouts.idle, None);
+        assert!(global_admin().authorize(b"rotated"));
+        // Needs a restart, so it is left as it was
+        assert_eq!(current.shard_count, 4);
+        assert_eq!(current.log_level, Level::Info.into());
+        drop(current);
+
+        // A failed read changes nothing
+        let failing = Reloader::new(
+            Box::new(|| Err(io::Error::new(io::ErrorKind::InvalidInput, "bad file"))),
+            ServerConfig::default(),
+            Arc::new(ShardedQueue::in_memory(1)),
+            event_loop.reconfigurer(),
+        );
+        assert!(failing.reload().is_err());
+        assert_eq!(
+            failing.current.lock().unwrap().timeouts.read,
+            Some(Duration::from_secs(30))
+        );
+    }
+}
@@ -1,38 +1,155 @@
-use crate::broker::threadpool::ThreadPool;
+use crate::broker::admin::{AdminConfig, set_global_admin};
+use crate::broker::admission::AdmissionConfig;
+use crate::broker::event_loop::EventLoop;
+use crate::broker::reload::{ConfigSource, Reloader, global_reloader, init_global_reloader};
+use crate::broker::signals::{reload_on_hangup, stop_on_terminate};
+use crate::broker::threadpool::{PoolConfig, ThreadPool, init_global_pool_monitor};
+use crate::broker::timeouts::TimeoutConfig;
+use crate::cluster::{ClusterConfig, init_global_cluster};
 use crate::log_error;
 use crate::log_info;
-use crate::logger::{global_loger, init_logger};
-use crate::shards::{get_global_queue, init_global_queue};
+use crate::logger::{
+    DEFAULT_BUFFER, Level, LogFilter, LogFormat, LogOverflow, RotationConfig, global_loger,
+    init_logger,
+};
+use crate::replication::{ReplicationConfig, global_node, init_global_node};
+use crate::shards::{QueueConfig, StorageKind, get_global_queue, init_global_queue};
 use std::net::TcpListener;
+use std::path::PathBuf;
 
-pub fn run(
-    addr: &str,
-    shard_count: usize,
-    pool_size: usize,
-    max_queue_size: usize,
-) -> std::io::Result<()> {
+/// Where the queue keeps its shard files unless configured otherwise.
+pub const DATA_DIR: &str = "./queue_data";
+
+/// Everything `run` needs to bring a broker up.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// TCP bind address for clients.
+    pub addr: String,
+    /// Where the queue keeps its shard files.
+    pub data_dir: PathBuf,
+    pub shard_count: usize,
+    /// Worker threads handling requests, and requests waiting for them.
+    pub pool: PoolConfig,
+    pub queue: QueueConfig,
+    pub replication: ReplicationConfig,
+    pub cluster: Option<ClusterConfig>,
+    pub admission: AdmissionConfig,
+    pub timeouts: TimeoutConfig,
+    /// Where file admin commands may work, and the token they need.
+    pub admin: AdminConfig,
+    /// Which messages get logged, per module.
+    pub log_level: LogFilter,
+    pub log_format: LogFormat,
+    /// File to log to instead of stdout.
+    pub log_file: Option<PathBuf>,
+    pub log_rotation: RotationConfig,
+    /// Log lines waiting to be written at most, and what happens to more.
+    pub log_buffer: usize,
+    pub log_when_full: LogOverflow,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: "0.0.0.0:4000".to_string(),
+            data_dir: PathBuf::from(DATA_DIR),
+            shard_count: 4,
+            pool: PoolConfig::default(),
+            queue: QueueConfig {
+                memory_budget: Some(256 * 1024 * 1024), // bytes kept in memory per queue
+                max_depth: Some(1_000_000),             // messages per shard
+                max_bytes: Some(1024 * 1024 * 1024),    // bytes per shard
+                storage: StorageKind::Wal,
+                ..Default::default()
+            },
+            replication: ReplicationConfig::default(),
+            cluster: None,
+            admission: AdmissionConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            admin: AdminConfig::default(),
+            log_level: Level::Info.into(),
+            log_format: LogFormat::Text,
+            log_file: None,
+            log_rotation: RotationConfig::default(),
+            log_buffer: DEFAULT_BUFFER,
+            log_when_full: LogOverflow::Block,
+        }
+    }
+}
+
+/// Serves until SIGTERM or SIGINT. `reload` rereads the settings on
+/// SIGHUP or a `reload` admin command.
+pub fn run(config: ServerConfig, reload: ConfigSource) -> std::io::Result<()> {
     init_logger();
+    global_loger().set_filter(config.log_level.clone());
+    global_loger().set_format(config.log_format);
+    global_loger().set_buffer(config.log_buffer, config.log_when_full);
+    if let Some(path) = &config.log_file {
+        global_loger().log_to_file(path, config.log_rotation.clone())?;
+    }
+    let shard_count = config.shard_count;
+    set_global_admin(config.admin.clone());
 
-    init_global_queue(shard_count, "./queue_data")?;
-    let queue = get_global_queue();
-    let listener = TcpListener::bind(addr)?;
-    log_info!(global_loger(), "Broker listening on {}", addr);
+    if let Some(cluster) = &config.cluster {
+        init_global_cluster(cluster, shard_count)?;
+        log_info!(
+            global_loger(),
+            "Cluster member {} of {:?}",
+            cluster.advertise,
+            cluster.members
+        );
+    }
 
-    let mut pool = ThreadPool::new(pool_size, shard_count, max_queue_size);
+    let current = config.clone();
+    init_global_queue(shard_count, &config.data_dir, config.queue)?;
+    let queue = get_global_queue();
+    let replication = config.replication;
+    init_global_node(&replication)?;
+    let node = global_node();
+    if let Some(leader) = &replication.follow {
+        node.follow(leader, queue.clone())?;
+    }
+    if let Some(listen) = &replication.listen {
+        node.serve(TcpListener::bind(listen)?, queue.clone());
+        log_info!(global_loger(), "Replication listening on {}", listen);
+    }
+    let listener = TcpListener::bind(&config.addr)?;
+    log_info!(global_loger(), "Broker listening on {}", config.addr);
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(s) => {
-                if let Err(e) = pool.submit(s) {
-                    log_error!(global_loger(), "Failed to submit connection: {}", e);
-                }
-            }
-            Err(e) => {
-                log_error!(global_loger(), "Connection failed: {}", e);
-            }
+    let pool = ThreadPool::with_config(config.pool);
+    init_global_pool_monitor(pool.monitor())?;
+    let mut event_loop = EventLoop::new(
+        listener,
+        pool,
+        config.admission,
+        config.timeouts,
+        shard_count,
+        queue.clone(),
+    )?;
+    stop_on_terminate(event_loop.stopper())?;
+    init_global_reloader(Reloader::new(
+        reload,
+        current,
+        queue.clone(),
+        event_loop.reconfigurer(),
+    ))?;
+    reload_on_hangup(|| {
+        if let Some(Err(e)) = global_reloader().map(Reloader::reload) {
+            log_error!(global_loger(), "Reload failed, keeping settings: {}", e);
         }
+    })?;
+    if let Err(e) = event_loop.run() {
+        log_error!(global_loger(), "Event loop failed: {}", e);
     }
-    pool.shutdown();
+    // Workers finish what they hold before the shards are synced, so every
+    // acknowledged push is on disk and the next start replays nothing
+    event_loop.shutdown();
+    queue.flush()?;
     queue.force_checkpoint()?;
+    log_info!(
+        global_loger(),
+        "Broker stopped; shards flushed and checkpointed"
+    );
+    global_loger().shutdown();
     Ok(())
 }
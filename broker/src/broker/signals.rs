@@ -0,0 +1,85 @@
+//! Process signals that stop or reconfigure the broker.
+
+use crate::broker::epoll::{EPOLLIN, Epoll, Event, Waker};
+use crate::broker::event_loop::Stopper;
+use std::ffi::c_int;
+use std::io;
+use std::sync::OnceLock;
+use std::thread;
+
+pub const SIGHUP: c_int = 1;
+pub const SIGINT: c_int = 2;
+pub const SIGTERM: c_int = 15;
+
+const SIG_ERR: usize = usize::MAX;
+
+unsafe extern "C" {
+    fn signal(signum: c_int, handler: usize) -> usize;
+}
+
+static TERMINATE: OnceLock<Stopper> = OnceLock::new();
+static HANGUP: OnceLock<Waker> = OnceLock::new();
+
+fn install(signum: c_int, handler: extern "C" fn(c_int)) -> io::Result<()> {
+    // SAFETY: every handler only touches state that is safe to use from a
+    // signal handler.
+    if unsafe { signal(signum, handler as usize) } == SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+extern "C" fn on_terminate(_signum: c_int) {
+    // Only an atomic store and an eventfd write, both safe in a handler
+    if let Some(stopper) = TERMINATE.get() {
+        stopper.stop();
+    }
+}
+
+/// Stops the event loop behind `stopper` gracefully on SIGTERM or SIGINT,
+/// instead of the process dying on the spot.
+pub fn stop_on_terminate(stopper: Stopper) -> io::Result<()> {
+    TERMINATE.set(stopper).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "Termination handler already installed",
+        )
+    })?;
+    install(SIGTERM, on_terminate)?;
+    install(SIGINT, on_terminate)
+}
+
+extern "C" fn on_hangup(_signum: c_int) {
+    if let Some(waker) = HANGUP.get() {
+        let _ = waker.wake();
+    }
+}
+
+/// Runs `reload` on a thread of its own after every SIGHUP. Signals that
+/// arrive while it runs are coalesced into one more call.
+pub fn reload_on_hangup(reload: impl Fn() + Send + 'static) -> io::Result<()> {
+    let epoll = Epoll::new()?;
+    let waker = Waker::new()?;
+    epoll.add(&waker, 0, EPOLLIN)?;
+    HANGUP.set(waker).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "Hangup handler already installed",
+        )
+    })?;
+    thread::spawn(move || {
+        let mut events: Vec<Event> = Vec::with_capacity(1);
+        loop {
+            if epoll.wait(&mut events, -1).is_err() {
+                return;
+            }
+            if !events.is_empty()
+                && let Some(waker) = HANGUP.get()
+            {
+                waker.drain();
+                reload();
+            }
+        }
+    });
+    install(SIGHUP, on_hangup)
+}
@@ -1,92 +1,208 @@
-use crate::broker::handlers::handle_client;
-use crate::shards::get_global_queue;
+use crate::log_error;
+use crate::logger::global_loger;
+use std::any::Any;
 use std::collections::VecDeque;
 use std::io::{Error, ErrorKind};
-use std::net::TcpStream;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
 use std::thread;
+use std::time::Duration;
+
+/// How many workers a pool keeps, and how much work it queues.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// Workers kept even when there is nothing to do.
+    pub min_workers: usize,
+    /// Workers the pool grows to while jobs wait for one.
+    pub max_workers: usize,
+    /// Jobs waiting for a worker at most.
+    pub queue_size: usize,
+    /// Time a worker above `min_workers` waits for a job before exiting.
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min_workers: 4,
+            max_workers: 32,
+            queue_size: 100,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// A snapshot of how loaded a pool is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub min_workers: usize,
+    pub max_workers: usize,
+    /// Workers running now.
+    pub workers: usize,
+    /// Workers running a job.
+    pub busy: usize,
+    /// Jobs waiting for a worker.
+    pub queued: usize,
+    /// Jobs that panicked since the pool started.
+    pub panics: u64,
+}
+
+impl PoolStats {
+    /// Share of the running workers that are busy, from 0 to 1.
+    pub fn utilisation(&self) -> f64 {
+        if self.workers == 0 {
+            return 0.0;
+        }
+        self.busy as f64 / self.workers as f64
+    }
+}
 
 pub struct ThreadPool {
-    workers: Vec<thread::JoinHandle<()>>,
-    queue: Arc<(Mutex<QueueState>, Condvar, Condvar)>, // (lock, task_cvar, space_cvar)
+    workers: Arc<Mutex<Vec<thread::JoinHandle<()>>>>,
+    queue: Arc<(Mutex<QueueState>, Condvar)>, // (lock, task_cvar)
     is_shutdown: Arc<AtomicBool>,
-    max_queue_size: usize,
+    config: PoolConfig,
 }
 
+/// A unit of work, such as handling one decoded request.
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
 struct QueueState {
-    tasks: VecDeque<TcpStream>,
+    tasks: VecDeque<Job>,
+    /// Workers running, busy or not.
+    workers: usize,
+    /// Workers waiting for a job.
+    idle: usize,
+    panics: u64,
 }
 
 impl ThreadPool {
-    pub fn new(size: usize, shard_count: usize, max_queue_size: usize) -> Self {
-        let queue = Arc::new((
-            Mutex::new(QueueState {
-                tasks: VecDeque::new(),
-            }),
-            Condvar::new(), // task available
-            Condvar::new(), // space available
-        ));
-        let is_shutdown = Arc::new(AtomicBool::new(false));
-        let mut workers = Vec::with_capacity(size);
-
-        for _ in 0..size {
-            let queue_clone = Arc::clone(&queue);
-            let shutdown_clone = Arc::clone(&is_shutdown);
-
-            workers.push(thread::spawn(move || {
-                loop {
-                    let stream_opt = {
-                        let (lock, task_cvar, space_cvar) = &*queue_clone;
-                        let mut state = lock.lock().unwrap();
-                        while state.tasks.is_empty() && !shutdown_clone.load(Ordering::Acquire) {
-                            state = task_cvar.wait(state).unwrap();
-                        }
-                        if shutdown_clone.load(Ordering::Acquire) && state.tasks.is_empty() {
+    /// A pool of exactly `size` workers.
+    pub fn new(size: usize, max_queue_size: usize) -> Self {
+        Self::with_config(PoolConfig {
+            min_workers: size,
+            max_workers: size,
+            queue_size: max_queue_size,
+            ..Default::default()
+        })
+    }
+
+    /// A pool that starts with `min_workers`, adds one whenever a job is
+    /// queued with no idle worker to take it, up to `max_workers`, and lets
+    /// workers above the minimum go after `idle_timeout` without a job.
+    pub fn with_config(config: PoolConfig) -> Self {
+        let pool = Self {
+            workers: Arc::new(Mutex::new(Vec::with_capacity(config.max_workers))),
+            queue: Arc::new((
+                Mutex::new(QueueState {
+                    tasks: VecDeque::new(),
+                    workers: 0,
+                    idle: 0,
+                    panics: 0,
+                }),
+                Condvar::new(), // task available
+            )),
+            is_shutdown: Arc::new(AtomicBool::new(false)),
+            config,
+        };
+        {
+            let mut state = pool.queue.0.lock().unwrap();
+            for _ in 0..pool.config.min_workers {
+                pool.spawn_worker(&mut state);
+            }
+        }
+        pool
+    }
+
+    /// Starts a worker, counted in `state` before it runs.
+    fn spawn_worker(&self, state: &mut QueueState) {
+        state.workers += 1;
+        let queue = Arc::clone(&self.queue);
+        let shutdown = Arc::clone(&self.is_shutdown);
+        let min_workers = self.config.min_workers;
+        let idle_timeout = self.config.idle_timeout;
+
+        let handle = thread::spawn(move || {
+            loop {
+                let job = {
+                    let (lock, task_cvar) = &*queue;
+                    let mut state = lock.lock().unwrap();
+                    state.idle += 1;
+                    while state.tasks.is_empty() && !shutdown.load(Ordering::Acquire) {
+                        let (next, waited) = task_cvar.wait_timeout(state, idle_timeout).unwrap();
+                        state = next;
+                        if waited.timed_out()
+                            && state.tasks.is_empty()
+                            && state.workers > min_workers
+                        {
+                            state.idle -= 1;
+                            state.workers -= 1;
                             return;
                         }
-                        let stream = state.tasks.pop_front();
-                        space_cvar.notify_one();
-                        stream
-                    };
-
-                    if let Some(stream) = stream_opt {
-                        let result = std::panic::catch_unwind(|| {
-                            handle_client(stream, shard_count, get_global_queue());
-                        });
-                        if let Err(e) = result {
-                            eprintln!("Worker panic {:?}", e);
-                        }
+                    }
+                    state.idle -= 1;
+                    if shutdown.load(Ordering::Acquire) && state.tasks.is_empty() {
+                        state.workers -= 1;
+                        return;
+                    }
+                    state.tasks.pop_front()
+                };
+
+                if let Some(job) = job {
+                    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+                    if let Err(payload) = result {
+                        log_error!(global_loger(), "Worker panic: {}", panic_message(&*payload));
+                        queue.0.lock().unwrap().panics += 1;
                     }
                 }
-            }));
-        }
-        Self {
-            workers,
-            queue,
-            is_shutdown,
-            max_queue_size,
-        }
+            }
+        });
+        let mut workers = self.workers.lock().unwrap();
+        // Forget workers that already retired
+        workers.retain(|worker| !worker.is_finished());
+        workers.push(handle);
     }
 
-    pub fn submit(&self, stream: TcpStream) -> Result<(), Error> {
+    /// Queues `job` for a worker. Fails instead of waiting when the queue
+    /// is full.
+    pub fn submit(&self, job: Job) -> Result<(), Error> {
         if self.is_shutdown.load(Ordering::Acquire) {
             return Err(Error::new(
                 ErrorKind::ConnectionRefused,
                 "broker is shutdown",
             ));
         }
-        let (lock, task_cvar, space_cvar) = &*self.queue;
+        let (lock, task_cvar) = &*self.queue;
         let mut state = lock.lock().unwrap();
-        if state.tasks.len() > self.max_queue_size {
-            eprintln!("Queue is full");
+        if state.tasks.len() > self.config.queue_size {
             return Err(Error::new(ErrorKind::StorageFull, "Queue is full"));
         }
-        state.tasks.push_back(stream);
+        state.tasks.push_back(job);
+        // Every idle worker has a job coming already
+        if state.tasks.len() > state.idle && state.workers < self.config.max_workers {
+            self.spawn_worker(&mut state);
+        }
         task_cvar.notify_one();
         Ok(())
     }
 
+    pub fn stats(&self) -> PoolStats {
+        stats(&self.queue, &self.config)
+    }
+
+    /// Counts a panic a job caught itself.
+    pub fn record_panic(&self) {
+        self.queue.0.lock().unwrap().panics += 1;
+    }
+
+    /// A handle that reads this pool's stats from elsewhere.
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor {
+            queue: self.queue.clone(),
+            config: self.config.clone(),
+        }
+    }
+
     pub fn shutdown(&mut self) {
         self.initiate_shutdown();
         self.join_workers();
@@ -95,19 +211,42 @@ impl ThreadPool {
     pub fn initiate_shutdown(&self) {
         self.is_shutdown.store(true, Ordering::Release);
 
-        let (lock, task_cvar, space_cvar) = &*self.queue;
+        let (lock, task_cvar) = &*self.queue;
         let _unused = lock.lock().unwrap();
         task_cvar.notify_all();
-        space_cvar.notify_all();
     }
 
     fn join_workers(&mut self) {
-        for worker in self.workers.drain(..) {
+        let workers: Vec<_> = self.workers.lock().unwrap().drain(..).collect();
+        for worker in workers {
             let _ = worker.join();
         }
     }
 }
 
+fn stats(queue: &(Mutex<QueueState>, Condvar), config: &PoolConfig) -> PoolStats {
+    let state = queue.0.lock().unwrap();
+    PoolStats {
+        min_workers: config.min_workers,
+        max_workers: config.max_workers,
+        workers: state.workers,
+        busy: state.workers - state.idle,
+        queued: state.tasks.len(),
+        panics: state.panics,
+    }
+}
+
+/// The message a panic was raised with.
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
 impl Drop for ThreadPool {
     fn drop(&mut self) {
         if !self.is_shutdown.load(Ordering::Acquire) {
@@ -116,3 +255,87 @@ impl Drop for ThreadPool {
         self.join_workers();
     }
 }
+
+/// Reads a pool's stats without owning it.
+pub struct PoolMonitor {
+    queue: Arc<(Mutex<QueueState>, Condvar)>,
+    config: PoolConfig,
+}
+
+impl PoolMonitor {
+    pub fn stats(&self) -> PoolStats {
+        stats(&self.queue, &self.config)
+    }
+}
+
+static POOL: OnceLock<PoolMonitor> = OnceLock::new();
+
+pub fn init_global_pool_monitor(monitor: PoolMonitor) -> Result<(), Error> {
+    POOL.set(monitor).map_err(|_| {
+        Error::new(
+            ErrorKind::AlreadyExists,
+            "Global pool monitor already initialized",
+        )
+    })
+}
+
+/// The broker's worker pool, if it is running.
+pub fn global_pool_monitor() -> Option<&'static PoolMonitor> {
+    POOL.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    fn wait_for(pool: &ThreadPool, check: impl Fn(PoolStats) -> bool) -> PoolStats {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let stats = pool.stats();
+            if check(stats) || Instant::now() >= deadline {
+                return stats;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_grows_under_load_and_shrinks_when_idle() {
+        let pool = ThreadPool::with_config(PoolConfig {
+            min_workers: 1,
+            max_workers: 4,
+            queue_size: 16,
+            idle_timeout: Duration::from_millis(50),
+        });
+        assert_eq!(
+            wait_for(&pool, |s| s.workers == 1 && s.busy == 0).workers,
+            1
+        );
+
+        // Jobs that hold their worker until released
+        let (release, gate) = mpsc::channel::<()>();
+        let gate = Arc::new(Mutex::new(gate));
+        for _ in 0..6 {
+            let gate = gate.clone();
+            pool.submit(Box::new(move || {
+                let _ = gate.lock().unwrap().recv();
+            }))
+            .unwrap();
+        }
+        // Jobs lock the gate one at a time, so count the waiting ones too
+        let stats = wait_for(&pool, |s| s.workers == 4 && s.busy + s.queued == 6);
+        assert_eq!(stats.workers, 4);
+        assert_eq!(stats.busy + stats.queued, 6);
+        assert!(stats.utilisation() > 0.0);
+
+        for _ in 0..6 {
+            release.send(()).unwrap();
+        }
+        let stats = wait_for(&pool, |s| s.workers == 1);
+        assert_eq!(stats.workers, 1);
+        assert_eq!(stats.busy, 0);
+        assert_eq!(stats.utilisation(), 0.0);
+    }
+}
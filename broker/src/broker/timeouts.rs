@@ -0,0 +1,131 @@
+use std::ffi::{c_int, c_void};
+use std::io;
+use std::net::TcpStream;
+use std::os::fd::AsRawFd;
+use std::time::Duration;
+
+const SOL_SOCKET: c_int = 1;
+const SO_KEEPALIVE: c_int = 9;
+const IPPROTO_TCP: c_int = 6;
+const TCP_KEEPIDLE: c_int = 4;
+const TCP_KEEPINTVL: c_int = 5;
+const TCP_KEEPCNT: c_int = 6;
+
+/// Unanswered probes before the kernel drops a keepalive connection.
+const KEEPALIVE_PROBES: c_int = 3;
+
+unsafe extern "C" {
+    fn setsockopt(fd: c_int, level: c_int, name: c_int, value: *const c_void, len: u32) -> c_int;
+}
+
+/// How long client connections may stall. `None` disables a limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeoutConfig {
+    /// Time a request may take to arrive once its first byte has.
+    pub read: Option<Duration>,
+    /// Time a reply may sit unsent while the client reads nothing.
+    pub write: Option<Duration>,
+    /// Time a connection may stay open with no request in progress.
+    pub idle: Option<Duration>,
+    /// Idle time before the kernel starts probing a connection, which also
+    /// spaces the probes. Catches peers that vanished without a FIN.
+    pub keepalive: Option<Duration>,
+    /// Time a graceful shutdown waits for requests already received.
+    pub drain: Duration,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            read: Some(Duration::from_secs(30)),
+            write: Some(Duration::from_secs(30)),
+            idle: Some(Duration::from_secs(300)),
+            keepalive: Some(Duration::from_secs(60)),
+            drain: Duration::from_secs(10),
+        }
+    }
+}
+
+impl TimeoutConfig {
+    /// The shortest limit set, which bounds how stale a check may be.
+    pub fn shortest(&self) -> Option<Duration> {
+        [self.read, self.write, self.idle]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+}
+
+fn set_option(stream: &TcpStream, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+    // SAFETY: `value` outlives the call and the kernel copies it.
+    let result = unsafe {
+        setsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &value as *const c_int as *const c_void,
+            size_of::<c_int>() as u32,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Turns on TCP keepalive, probing after `idle` without traffic.
+pub fn set_keepalive(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+    let secs = idle.as_secs().clamp(1, i16::MAX as u64) as c_int;
+    set_option(stream, SOL_SOCKET, SO_KEEPALIVE, 1)?;
+    set_option(stream, IPPROTO_TCP, TCP_KEEPIDLE, secs)?;
+    set_option(stream, IPPROTO_TCP, TCP_KEEPINTVL, secs)?;
+    set_option(stream, IPPROTO_TCP, TCP_KEEPCNT, KEEPALIVE_PROBES)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    unsafe extern "C" {
+        fn getsockopt(
+            fd: c_int,
+            level: c_int,
+            name: c_int,
+            value: *mut c_void,
+            len: *mut u32,
+        ) -> c_int;
+    }
+
+    fn get_option(stream: &TcpStream, level: c_int, name: c_int) -> c_int {
+        let mut value: c_int = 0;
+        let mut len = size_of::<c_int>() as u32;
+        let result = unsafe {
+            getsockopt(
+                stream.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut c_int as *mut c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(result, 0);
+        value
+    }
+
+    #[test]
+    fn test_set_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert_eq!(get_option(&stream, SOL_SOCKET, SO_KEEPALIVE), 0);
+
+        set_keepalive(&stream, Duration::from_secs(45)).unwrap();
+        assert_ne!(get_option(&stream, SOL_SOCKET, SO_KEEPALIVE), 0);
+        assert_eq!(get_option(&stream, IPPROTO_TCP, TCP_KEEPIDLE), 45);
+        assert_eq!(get_option(&stream, IPPROTO_TCP, TCP_KEEPINTVL), 45);
+        assert_eq!(
+            get_option(&stream, IPPROTO_TCP, TCP_KEEPCNT),
+            KEEPALIVE_PROBES
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! Static multi-node clusters.
+//!
+//! Every broker of a cluster runs with the same shard count and the same
+//! member list. Shards are spread over the members with a consistent hash
+//! ring, so each node can tell who owns the shard `compute_shard_key` picks
+//! for a job without asking anyone, and adding or removing a member only
+//! moves the shards that land on it.
+
+use std::io;
+use std::sync::OnceLock;
+
+/// Points each member gets on the ring, to even out the spread.
+const VIRTUAL_NODES: usize = 64;
+
+#[derive(Debug, Clone, Default)]
+pub struct ClusterConfig {
+    /// Client addresses of every member, this one included. Empty for a
+    /// single broker that owns every shard.
+    pub members: Vec<String>,
+    /// The address other members and clients know this broker by.
+    pub advertise: String,
+}
+
+#[derive(Debug)]
+pub struct Cluster {
+    members: Vec<String>,
+    me: usize,
+    /// Owner of each shard, indexed by shard id.
+    owners: Vec<usize>,
+}
+
+/// FNV-1a, chosen because every member must hash identically.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    // FNV mixes the last bytes poorly; finish like splitmix64
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^ (hash >> 33)
+}
+
+impl Cluster {
+    pub fn new(config: &ClusterConfig, shard_count: usize) -> io::Result<Self> {
+        let invalid = |details: String| io::Error::new(io::ErrorKind::InvalidInput, details);
+        let mut members = config.members.clone();
+        members.sort();
+        members.dedup();
+        if members.len() != config.members.len() {
+            return Err(invalid("cluster members must be unique".to_string()));
+        }
+        let me = members
+            .iter()
+            .position(|m| *m == config.advertise)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "advertised address {} is not a cluster member",
+                    config.advertise
+                ))
+            })?;
+
+        let mut ring: Vec<(u64, usize)> = Vec::with_capacity(members.len() * VIRTUAL_NODES);
+        for (index, member) in members.iter().enumerate() {
+            for point in 0..VIRTUAL_NODES {
+                ring.push((hash(format!("{}#{}", member, point).as_bytes()), index));
+            }
+        }
+        ring.sort_unstable();
+        let owners = (0..shard_count)
+            .map(|shard| {
+                let point = hash(format!("shard-{}", shard).as_bytes());
+                let at = ring.partition_point(|(h, _)| *h < point);
+                ring[at % ring.len()].1
+            })
+            .collect();
+        Ok(Self {
+            members,
+            me,
+            owners,
+        })
+    }
+
+    /// Client address of the member owning `shard`.
+    pub fn owner(&self, shard: usize) -> &str {
+        &self.members[self.owners[shard]]
+    }
+
+    pub fn owns(&self, shard: usize) -> bool {
+        self.owners[shard] == self.me
+    }
+
+    /// Ids of the shards this broker owns.
+    pub fn owned_shards(&self) -> Vec<usize> {
+        (0..self.owners.len()).filter(|&s| self.owns(s)).collect()
+    }
+
+    pub fn members(&self) -> &[String] {
+        &self.members
+    }
+
+    pub fn advertise(&self) -> &str {
+        &self.members[self.me]
+    }
+}
+
+static CLUSTER: OnceLock<Cluster> = OnceLock::new();
+
+pub fn init_global_cluster(config: &ClusterConfig, shard_count: usize) -> io::Result<()> {
+    let cluster = Cluster::new(config, shard_count)?;
+    CLUSTER.set(cluster).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "Global cluster already initialized",
+        )
+    })
+}
+
+/// The cluster this broker belongs to, if any.
+pub fn global_cluster() -> Option<&'static Cluster> {
+    CLUSTER.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(members: &[&str], advertise: &str) -> ClusterConfig {
+        ClusterConfig {
+            members: members.iter().map(|m| m.to_string()).collect(),
+            advertise: advertise.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_every_shard_has_one_owner() {
+        let members = ["10.0.0.1:4000", "10.0.0.2:4000", "10.0.0.3:4000"];
+        let clusters: Vec<Cluster> = members
+            .iter()
+            .map(|m| Cluster::new(&config(&members, m), 64).unwrap())
+            .collect();
+        for shard in 0..64 {
+            let owner = clusters[0].owner(shard);
+            assert!(clusters.iter().all(|c| c.owner(shard) == owner));
+            assert_eq!(clusters.iter().filter(|c| c.owns(shard)).count(), 1);
+        }
+        for cluster in &clusters {
+            assert!(cluster.owned_shards().len() > 5, "{:?}", cluster.owners);
+        }
+
+        assert!(Cluster::new(&config(&members, "10.0.0.9:4000"), 4).is_err());
+        assert!(Cluster::new(&config(&[members[0], members[0]], members[0]), 4).is_err());
+    }
+
+    #[test]
+    fn test_adding_a_member_only_moves_shards_to_it() {
+        let before = Cluster::new(&config(&["a:1", "b:1", "c:1"], "a:1"), 128).unwrap();
+        let after = Cluster::new(&config(&["a:1", "b:1", "c:1", "d:1"], "a:1"), 128).unwrap();
+        let mut moved = 0;
+        for shard in 0..128 {
+            if before.owner(shard) != after.owner(shard) {
+                assert_eq!(after.owner(shard), "d:1");
+                moved += 1;
+            }
+        }
+        assert!(moved > 0 && moved < 64, "moved {}", moved);
+    }
+}
@@ -0,0 +1,848 @@
+//! Broker settings.
+//!
+//! Every setting has a key, like `shards`. It can come from a config file
+//! (`key = value` lines, named by `--config` or `RLBG_CONFIG`), from an
+//! environment variable (`RLBG_SHARDS`), or from a flag (`--shards`). Flags
+//! win over the environment, which wins over the file, which wins over the
+//! built-in defaults.
+
+use crate::broker::admission::AdmissionPolicy;
+use crate::broker::server::ServerConfig;
+use crate::cluster::ClusterConfig;
+use crate::crypto::Keyring;
+use crate::replication::wire::MAX_HELLO_FIELD;
+use crate::shards::{OverflowPolicy, StorageKind};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Every key, with what it sets.
+pub const KEYS: &[(&str, &str)] = &[
+    ("listen", "client address to bind, e.g. 0.0.0.0:4000"),
+    ("data_dir", "directory of the shard files"),
+    (
+        "storage",
+        "wal to keep shards on disk, memory to lose them on restart",
+    ),
+    ("shards", "number of shards"),
+    ("workers", "threads handling requests at most"),
+    ("min_workers", "threads kept when there are no requests"),
+    (
+        "worker_idle_ms",
+        "time an extra thread waits for a request before exiting",
+    ),
+    ("queue_size", "requests waiting for a worker at most"),
+    ("wal_batch_size", "WAL records between syncs to disk"),
+    (
+        "checkpoint_threshold",
+        "pushes between looking for shards due a checkpoint",
+    ),
+    (
+        "memory_budget",
+        "bytes of messages kept in memory, 0 for no limit",
+    ),
+    ("max_depth", "messages per shard at most, 0 for no limit"),
+    ("max_bytes", "bytes per shard at most, 0 for no limit"),
+    (
+        "overflow",
+        "reject, block or drop_oldest pushes to a full shard",
+    ),
+    (
+        "overflow_timeout_ms",
+        "time a blocked push waits for space before it is rejected",
+    ),
+    ("compress", "deflate records on disk, true or false"),
+    (
+        "log_level",
+        "debug, info, warn or error, per module like info,shards=warn",
+    ),
+    ("log_format", "text or json, one object per line"),
+    ("log_file", "file to log to instead of stdout"),
+    (
+        "log_rotate_bytes",
+        "size the log file is rotated at, 0 for no limit",
+    ),
+    (
+        "log_rotate_daily",
+        "rotate the log file every UTC day, true or false",
+    ),
+    ("log_keep", "rotated log files kept"),
+    ("log_gzip", "gzip rotated log files, true or false"),
+    ("log_buffer", "log lines waiting to be written at most"),
+    (
+        "log_when_full",
+        "block or drop log lines while the buffer is full",
+    ),
+    ("replication_listen", "address followers connect to"),
+    ("follow", "replication address of the leader to follow"),
+    ("ack_timeout_ms", "how long pushes wait for replica acks"),
+    (
+        "replication_secret",
+        "secret the leader and its followers must share",
+    ),
+    (
+        "replication_name",
+        "name this broker gives its leader, defaults to listen",
+    ),
+    (
+        "replication_followers",
+        "follower names whose acks count toward min_replicas",
+    ),
+    ("cluster", "client addresses of every cluster member"),
+    ("advertise", "this member's address in `cluster`"),
+    ("max_connections", "open client connections at most"),
+    (
+        "max_connections_per_ip",
+        "open connections per client IP, 0 for no limit",
+    ),
+    ("when_full", "block or reject connections over the limit"),
+    ("retry_after_ms", "retry hint sent to rejected connections"),
+    (
+        "panic_limit",
+        "worker panics per client IP a minute before a ban, 0 for no limit",
+    ),
+    ("panic_ban_ms", "how long a client IP stays banned"),
+    (
+        "read_timeout_ms",
+        "time a request may take to arrive, 0 for none",
+    ),
+    (
+        "write_timeout_ms",
+        "time a reply may take to be read, 0 for none",
+    ),
+    (
+        "idle_timeout_ms",
+        "time a connection may sit idle, 0 for none",
+    ),
+    ("keepalive_ms", "TCP keepalive idle time, 0 for off"),
+    (
+        "drain_timeout_ms",
+        "time a graceful shutdown waits for requests",
+    ),
+    (
+        "admin_dir",
+        "directory export, import and backup files are read and written in",
+    ),
+    (
+        "admin_token",
+        "secret admin commands must carry as their third TLV",
+    ),
+];
+
+/// Keys a running broker picks up on reload; the rest need a restart.
+pub const RELOADABLE: &[&str] = &[
+    "log_level",
+    "log_format",
+    "log_buffer",
+    "log_when_full",
+    "max_depth",
+    "max_bytes",
+    "overflow",
+    "overflow_timeout_ms",
+    "max_connections",
+    "max_connections_per_ip",
+    "when_full",
+    "retry_after_ms",
+    "panic_limit",
+    "panic_ban_ms",
+    "read_timeout_ms",
+    "write_timeout_ms",
+    "idle_timeout_ms",
+    "keepalive_ms",
+    "drain_timeout_ms",
+    "admin_token",
+];
+
+/// Keys whose values are never logged or reported.
+pub const SECRETS: &[&str] = &["admin_token", "replication_secret"];
+
+/// How long a push waits for space under `overflow = block` unless
+/// `overflow_timeout_ms` says otherwise.
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Environment variables are the key in upper case after this prefix.
+const ENV_PREFIX: &str = "RLBG_";
+const CONFIG_ENV: &str = "RLBG_CONFIG";
+
+/// What the broker was asked to do.
+#[derive(Debug)]
+pub struct Settings {
+    pub server: ServerConfig,
+    /// Backup directory to restore before serving.
+    pub restore: Option<PathBuf>,
+}
+
+/// Where a value came from, for error messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    File { path: PathBuf, line: usize },
+    Env(String),
+    Flag(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::File { path, line } => write!(f, "{}:{}", path.display(), line),
+            Source::Env(var) => write!(f, "environment variable {}", var),
+            Source::Flag(flag) => write!(f, "flag {}", flag),
+        }
+    }
+}
+
+/// Usage text listing every flag.
+pub fn usage() -> String {
+    let mut usage = String::from(
+        "usage: broker [--config <file>] [--restore <backup-dir>] [--<key> <value>]...\n\n\
+         Each key can also be set as RLBG_<KEY> or as `key = value` in the config file.\n\n",
+    );
+    for (key, help) in KEYS {
+        usage.push_str(&format!("  --{:<24} {}\n", key.replace('_', "-"), help));
+    }
+    usage
+}
+
+fn is_key(key: &str) -> bool {
+    KEYS.iter().any(|(k, _)| *k == key)
+}
+
+/// Builds the settings from `args` (without the program name), the
+/// environment as seen through `env`, and the config file they name. Every
+/// problem found is reported, one per line.
+pub fn load(
+    args: impl IntoIterator<Item = String>,
+    env: impl Fn(&str) -> Option<String>,
+) -> io::Result<Settings> {
+    let mut errors = Vec::new();
+    let mut flags = Vec::new();
+    let mut config_file = env(CONFIG_ENV).map(PathBuf::from);
+    let mut restore = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(name) = arg.strip_prefix("--") else {
+            errors.push(format!("unexpected argument {:?}", arg));
+            continue;
+        };
+        let (name, inline) = match name.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (name.to_string(), None),
+        };
+        let flag = format!("--{}", name);
+        let Some(value) = inline.or_else(|| args.next()) else {
+            errors.push(format!("{} needs a value", flag));
+            break;
+        };
+        let key = name.replace('-', "_");
+        match key.as_str() {
+            "config" => config_file = Some(PathBuf::from(value)),
+            "restore" => restore = Some(PathBuf::from(value)),
+            _ if is_key(&key) => flags.push((key, value, Source::Flag(flag))),
+            _ => errors.push(format!("unknown flag {}", flag)),
+        }
+    }
+
+    // Later layers override earlier ones
+    let mut values: BTreeMap<String, (String, Source)> = BTreeMap::new();
+    if let Some(path) = &config_file {
+        match std::fs::read_to_string(path) {
+            Ok(text) => match parse_file(path, &text) {
+                Ok(entries) => {
+                    for (key, value, source) in entries {
+                        values.insert(key, (value, source));
+                    }
+                }
+                Err(mut file_errors) => errors.append(&mut file_errors),
+            },
+            Err(e) => errors.push(format!("cannot read config file {}: {}", path.display(), e)),
+        }
+    }
+    for (key, _) in KEYS {
+        let var = format!("{}{}", ENV_PREFIX, key.to_ascii_uppercase());
+        if let Some(value) = env(&var) {
+            values.insert(key.to_string(), (value, Source::Env(var)));
+        }
+    }
+    for (key, value, source) in flags {
+        values.insert(key, (value, source));
+    }
+
+    let mut server = ServerConfig::default();
+    let mut members = None;
+    let mut advertise = None;
+    let mut replication_name = None;
+    let mut block_timeout = None;
+    for (key, (value, source)) in &values {
+        let applied = match key.as_str() {
+            "overflow_timeout_ms" => millis(value).map(|t| block_timeout = Some(t)),
+            "cluster" => {
+                members = Some(split_list(value));
+                Ok(())
+            }
+            "advertise" => {
+                advertise = Some(value.clone());
+                Ok(())
+            }
+            "replication_name" => hello_field(value).map(|name| replication_name = Some(name)),
+            _ => apply(&mut server, key, value),
+        };
+        if let Err(problem) = applied {
+            errors.push(format!("{}: {} = {:?}: {}", source, key, value, problem));
+        }
+    }
+
+    // Cluster members are named by their client address. This broker's
+    // defaults to its listen address; set `advertise` when that is a
+    // wildcard like 0.0.0.0.
+    match (members, advertise) {
+        (Some(members), advertise) => {
+            if members.is_empty() {
+                errors.push("cluster needs at least one member".to_string());
+            }
+            server.cluster = Some(ClusterConfig {
+                members,
+                advertise: advertise.unwrap_or_else(|| server.addr.clone()),
+            });
+        }
+        (None, Some(_)) => errors.push("advertise is only used with cluster".to_string()),
+        (None, None) => {}
+    }
+    // Followers are named by their listen address unless given a name
+    let replication = &mut server.replication;
+    replication.name = replication_name.unwrap_or_else(|| server.addr.clone());
+    if (replication.listen.is_some() || replication.follow.is_some())
+        && replication.secret.is_none()
+    {
+        errors.push("replication_listen and follow need replication_secret".to_string());
+    }
+    match (block_timeout, &mut server.queue.overflow) {
+        (Some(limit), OverflowPolicy::Block { timeout }) => *timeout = limit,
+        (Some(_), _) => {
+            errors.push("overflow_timeout_ms is only used with overflow = block".to_string())
+        }
+        (None, _) => {}
+    }
+    // A small `workers` alone lowers the default minimum with it
+    if !values.contains_key("min_workers") {
+        server.pool.min_workers = server.pool.min_workers.min(server.pool.max_workers);
+    }
+    if server.pool.min_workers > server.pool.max_workers {
+        errors.push(format!(
+            "min_workers ({}) is above workers ({})",
+            server.pool.min_workers, server.pool.max_workers
+        ));
+    }
+    match Keyring::from_vars(&env) {
+        Ok(keys) => server.queue.encryption = keys.map(Arc::new),
+        Err(e) => errors.push(e.to_string()),
+    }
+
+    if !errors.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            errors.join("\n"),
+        ));
+    }
+    Ok(Settings { server, restore })
+}
+
+/// Reads `key = value` lines. Values may be bare, "quoted", or a
+/// ["list", "of", "strings"]; `#` starts a comment.
+fn parse_file(path: &Path, text: &str) -> Result<Vec<(String, String, Source)>, Vec<String>> {
+    let mut entries = Vec::new();
+    let mut errors = Vec::new();
+    for (index, raw) in text.lines().enumerate() {
+        let source = Source::File {
+            path: path.to_path_buf(),
+            line: index + 1,
+        };
+        let line = strip_comment(raw).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            errors.push(format!(
+                "{}: expected `key = value`, got {:?}",
+                source, line
+            ));
+            continue;
+        };
+        let key = key.trim();
+        if !is_key(key) {
+            errors.push(format!("{}: unknown key {:?}", source, key));
+            continue;
+        }
+        match parse_value(value.trim()) {
+            Some(value) => entries.push((key.to_string(), value, source)),
+            None => errors.push(format!("{}: malformed value for {}", source, key)),
+        }
+    }
+    if errors.is_empty() {
+        Ok(entries)
+    } else {
+        Err(errors)
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (at, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..at],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn unquote(value: &str) -> Option<String> {
+    if let Some(inner) = value.strip_prefix('"') {
+        let inner = inner.strip_suffix('"')?;
+        return (!inner.contains('"')).then(|| inner.to_string());
+    }
+    (!value.is_empty() && !value.contains(['"', '[', ']'])).then(|| value.to_string())
+}
+
+/// Flattens a value to the text a flag would carry; lists become comma
+/// separated.
+fn parse_value(value: &str) -> Option<String> {
+    if let Some(inner) = value.strip_prefix('[') {
+        let inner = inner.strip_suffix(']')?.trim();
+        if inner.is_empty() {
+            return Some(String::new());
+        }
+        let items: Option<Vec<String>> = inner
+            .trim_end_matches(',')
+            .split(',')
+            .map(|item| unquote(item.trim()))
+            .collect();
+        return Some(items?.join(","));
+    }
+    unquote(value)
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|m| !m.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn number<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .replace('_', "")
+        .parse()
+        .map_err(|_| "expected a whole number".to_string())
+}
+
+fn positive(value: &str) -> Result<usize, String> {
+    match number(value)? {
+        0 => Err("must be at least 1".to_string()),
+        n => Ok(n),
+    }
+}
+
+/// A limit where 0 means none.
+fn limit(value: &str) -> Result<Option<usize>, String> {
+    Ok(Some(number(value)?).filter(|&n| n > 0))
+}
+
+fn millis(value: &str) -> Result<Duration, String> {
+    Ok(Duration::from_millis(number(value)?))
+}
+
+/// A timeout where 0 means none.
+fn timeout(value: &str) -> Result<Option<Duration>, String> {
+    Ok(Some(millis(value)?).filter(|d| !d.is_zero()))
+}
+
+fn boolean(value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err("expected true or false".to_string()),
+    }
+}
+
+fn address(value: &str) -> Result<String, String> {
+    match value.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+            Ok(value.to_string())
+        }
+        _ => Err("expected host:port".to_string()),
+    }
+}
+
+/// A value sent in the replication hello.
+fn hello_field(value: &str) -> Result<String, String> {
+    match value.len() {
+        0 => Err("must not be empty".to_string()),
+        n if n > MAX_HELLO_FIELD => Err(format!("must be at most {} bytes", MAX_HELLO_FIELD)),
+        _ => Ok(value.to_string()),
+    }
+}
+
+fn apply(server: &mut ServerConfig, key: &str, value: &str) -> Result<(), String> {
+    match key {
+        "listen" => server.addr = address(value)?,
+        "data_dir" if value.is_empty() => return Err("must not be empty".to_string()),
+        "data_dir" => server.data_dir = PathBuf::from(value),
+        "storage" => {
+            server.queue.storage = match value {
+                "wal" => StorageKind::Wal,
+                "memory" => StorageKind::Memory,
+                _ => return Err("expected wal or memory".to_string()),
+            }
+        }
+        "shards" => server.shard_count = positive(value)?,
+        "workers" => server.pool.max_workers = positive(value)?,
+        "min_workers" => server.pool.min_workers = number(value)?,
+        "worker_idle_ms" => server.pool.idle_timeout = millis(value)?,
+        "queue_size" => server.pool.queue_size = positive(value)?,
+        "wal_batch_size" => server.queue.wal_batch_size = positive(value)?,
+        "checkpoint_threshold" => server.queue.checkpoint_threshold = positive(value)?,
+        "memory_budget" => server.queue.memory_budget = limit(value)?,
+        "max_depth" => server.queue.max_depth = limit(value)?,
+        "max_bytes" => server.queue.max_bytes = limit(value)?,
+        "overflow" => {
+            server.queue.overflow = match value {
+                "reject" => OverflowPolicy::Reject,
+                "block" => OverflowPolicy::Block {
+                    timeout: BLOCK_TIMEOUT,
+                },
+                "drop_oldest" => OverflowPolicy::DropOldest,
+                _ => return Err("expected reject, block or drop_oldest".to_string()),
+            }
+        }
+        "compress" => server.queue.compress = boolean(value)?,
+        "log_level" => server.log_level = value.parse()?,
+        "log_format" => server.log_format = value.parse()?,
+        "log_file" if value.is_empty() => server.log_file = None,
+        "log_file" => server.log_file = Some(PathBuf::from(value)),
+        "log_rotate_bytes" => server.log_rotation.max_bytes = limit(value)?.map(|n| n as u64),
+        "log_rotate_daily" => server.log_rotation.daily = boolean(value)?,
+        "log_keep" => server.log_rotation.keep = number(value)?,
+        "log_gzip" => server.log_rotation.gzip = boolean(value)?,
+        "log_buffer" => server.log_buffer = positive(value)?,
+        "log_when_full" => server.log_when_full = value.parse()?,
+        "replication_listen" => server.replication.listen = Some(address(value)?),
+        "follow" => server.replication.follow = Some(address(value)?),
+        "ack_timeout_ms" => server.replication.ack_timeout = millis(value)?,
+        "replication_secret" => server.replication.secret = Some(hello_field(value)?),
+        "replication_followers" => server.replication.followers = split_list(value),
+        "max_connections" => server.admission.max_connections = positive(value)?,
+        "max_connections_per_ip" => server.admission.max_per_ip = limit(value)?,
+        "when_full" => {
+            server.admission.policy = match value {
+                "block" => AdmissionPolicy::Block,
+                "reject" => AdmissionPolicy::Reject,
+                _ => return Err("expected block or reject".to_string()),
+            }
+        }
+        "retry_after_ms" => server.admission.retry_after = millis(value)?,
+        "panic_limit" => server.admission.panic_limit = limit(value)?,
+        "panic_ban_ms" => server.admission.ban = millis(value)?,
+        "read_timeout_ms" => server.timeouts.read = timeout(value)?,
+        "write_timeout_ms" => server.timeouts.write = timeout(value)?,
+        "idle_timeout_ms" => server.timeouts.idle = timeout(value)?,
+        "keepalive_ms" => server.timeouts.keepalive = timeout(value)?,
+        "drain_timeout_ms" => server.timeouts.drain = millis(value)?,
+        "admin_dir" if value.is_empty() => server.admin.dir = None,
+        "admin_dir" => server.admin.dir = Some(PathBuf::from(value)),
+        "admin_token" if value.is_empty() => server.admin.token = None,
+        "admin_token" => server.admin.token = Some(value.to_string()),
+        _ => return Err("unknown key".to_string()),
+    }
+    Ok(())
+}
+
+/// Every key's value in `server`, written the way it would be set.
+pub fn describe(server: &ServerConfig) -> BTreeMap<&'static str, String> {
+    fn opt<T: ToString>(value: Option<T>) -> String {
+        value.map_or("0".to_string(), |v| v.to_string())
+    }
+    fn ms(value: Duration) -> String {
+        value.as_millis().to_string()
+    }
+    let queue = &server.queue;
+    let admission = &server.admission;
+    let timeouts = &server.timeouts;
+    let mut values = BTreeMap::new();
+    for (key, _) in KEYS {
+        let value = match *key {
+            "listen" => server.addr.clone(),
+            "data_dir" => server.data_dir.display().to_string(),
+            "storage" => match queue.storage {
+                StorageKind::Wal => "wal".to_string(),
+                StorageKind::Memory => "memory".to_string(),
+            },
+            "shards" => server.shard_count.to_string(),
+            "workers" => server.pool.max_workers.to_string(),
+            "min_workers" => server.pool.min_workers.to_string(),
+            "worker_idle_ms" => ms(server.pool.idle_timeout),
+            "queue_size" => server.pool.queue_size.to_string(),
+            "wal_batch_size" => queue.wal_batch_size.to_string(),
+            "checkpoint_threshold" => queue.checkpoint_threshold.to_string(),
+            "memory_budget" => opt(queue.memory_budget),
+            "max_depth" => opt(queue.max_depth),
+            "max_bytes" => opt(queue.max_bytes),
+            "overflow" => match queue.overflow {
+                OverflowPolicy::Reject => "reject".to_string(),
+                OverflowPolicy::Block { .. } => "block".to_string(),
+                OverflowPolicy::DropOldest => "drop_oldest".to_string(),
+            },
+            "overflow_timeout_ms" => match queue.overflow {
+                OverflowPolicy::Block { timeout } => ms(timeout),
+                _ => ms(BLOCK_TIMEOUT),
+            },
+            "compress" => queue.compress.to_string(),
+            "log_level" => server.log_level.to_string(),
+            "log_format" => server.log_format.to_string(),
+            "log_file" => server
+                .log_file
+                .as_ref()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default(),
+            "log_rotate_bytes" => opt(server.log_rotation.max_bytes),
+            "log_rotate_daily" => server.log_rotation.daily.to_string(),
+            "log_keep" => server.log_rotation.keep.to_string(),
+            "log_gzip" => server.log_rotation.gzip.to_string(),
+            "log_buffer" => server.log_buffer.to_string(),
+            "log_when_full" => server.log_when_full.to_string(),
+            "replication_listen" => server.replication.listen.clone().unwrap_or_default(),
+            "follow" => server.replication.follow.clone().unwrap_or_default(),
+            "ack_timeout_ms" => ms(server.replication.ack_timeout),
+            "replication_secret" => server.replication.secret.clone().unwrap_or_default(),
+            "replication_name" => server.replication.name.clone(),
+            "replication_followers" => server.replication.followers.join(","),
+            "cluster" => server
+                .cluster
+                .as_ref()
+                .map(|c| c.members.join(","))
+                .unwrap_or_default(),
+            "advertise" => server
+                .cluster
+                .as_ref()
+                .map(|c| c.advertise.clone())
+                .unwrap_or_default(),
+            "max_connections" => admission.max_connections.to_string(),
+            "max_connections_per_ip" => opt(admission.max_per_ip),
+            "when_full" => match admission.policy {
+                AdmissionPolicy::Block => "block".to_string(),
+                AdmissionPolicy::Reject => "reject".to_string(),
+            },
+            "retry_after_ms" => ms(admission.retry_after),
+            "panic_limit" => opt(admission.panic_limit),
+            "panic_ban_ms" => ms(admission.ban),
+            "read_timeout_ms" => opt(timeouts.read.map(ms)),
+            "write_timeout_ms" => opt(timeouts.write.map(ms)),
+            "idle_timeout_ms" => opt(timeouts.idle.map(ms)),
+            "keepalive_ms" => opt(timeouts.keepalive.map(ms)),
+            "drain_timeout_ms" => ms(timeouts.drain),
+            "admin_dir" => server
+                .admin
+                .dir
+                .as_ref()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default(),
+            "admin_token" => server.admin.token.clone().unwrap_or_default(),
+            _ => continue,
+        };
+        values.insert(*key, value);
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::Level;
+    use std::collections::HashMap;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |var| vars.get(var).cloned()
+    }
+
+    fn config_file(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rbq_{}_{}.toml", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_flags_override_env_override_file() {
+        let path = config_file(
+            "precedence",
+            r#"
+            # broker settings
+            listen = "127.0.0.1:5000"
+            shards = 8
+            workers = 16       # threads
+            data_dir = "/var/lib/rlbg # not a comment"
+            cluster = ["127.0.0.1:5000", "127.0.0.1:5001"]
+            "#,
+        );
+        let settings = load(
+            args(&[
+                "--config",
+                path.to_str().unwrap(),
+                "--shards=2",
+                "--log-level",
+                "warn",
+            ]),
+            env(&[("RLBG_SHARDS", "6"), ("RLBG_WORKERS", "12")]),
+        )
+        .unwrap();
+        let server = settings.server;
+        assert_eq!(server.shard_count, 2);
+        assert_eq!(server.pool.max_workers, 12);
+        assert_eq!(server.addr, "127.0.0.1:5000");
+        assert_eq!(server.data_dir, Path::new("/var/lib/rlbg # not a comment"));
+        assert_eq!(server.log_level, Level::Warn.into());
+        let cluster = server.cluster.unwrap();
+        assert_eq!(cluster.members, ["127.0.0.1:5000", "127.0.0.1:5001"]);
+        assert_eq!(cluster.advertise, "127.0.0.1:5000");
+        // Untouched keys keep their defaults
+        assert_eq!(
+            server.pool.queue_size,
+            ServerConfig::default().pool.queue_size
+        );
+        assert!(settings.restore.is_none());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_overflow_policy_and_block_timeout() {
+        let server = load(
+            args(&["--overflow-timeout-ms", "250", "--overflow", "block"]),
+            env(&[]),
+        )
+        .unwrap()
+        .server;
+        assert_eq!(
+            server.queue.overflow,
+            OverflowPolicy::Block {
+                timeout: Duration::from_millis(250)
+            }
+        );
+        let described = describe(&server);
+        assert_eq!(described["overflow"], "block");
+        assert_eq!(described["overflow_timeout_ms"], "250");
+
+        let server = load(args(&[]), env(&[("RLBG_OVERFLOW", "drop_oldest")]))
+            .unwrap()
+            .server;
+        assert_eq!(server.queue.overflow, OverflowPolicy::DropOldest);
+        assert_eq!(
+            ServerConfig::default().queue.overflow,
+            OverflowPolicy::Reject
+        );
+
+        let err = load(args(&["--overflow-timeout-ms", "250"]), env(&[])).unwrap_err();
+        assert!(err.to_string().contains("only used with overflow = block"));
+        assert!(load(args(&["--overflow", "wait"]), env(&[])).is_err());
+    }
+
+    #[test]
+    fn test_storage_backend() {
+        let server = load(args(&["--storage", "memory"]), env(&[]))
+            .unwrap()
+            .server;
+        assert_eq!(server.queue.storage, StorageKind::Memory);
+        assert_eq!(describe(&server)["storage"], "memory");
+
+        let server = load(args(&[]), env(&[])).unwrap().server;
+        assert_eq!(server.queue.storage, StorageKind::Wal);
+        assert_eq!(describe(&server)["storage"], "wal");
+        assert!(load(args(&["--storage", "disk"]), env(&[])).is_err());
+        // Records are stored as sent unless compression is asked for
+        assert!(!server.queue.compress);
+    }
+
+    #[test]
+    fn test_replication_needs_a_secret() {
+        let err = load(args(&["--follow", "leader:4001"]), env(&[])).unwrap_err();
+        assert!(err.to_string().contains("need replication_secret"));
+        assert!(load(args(&["--replication-listen", "0.0.0.0:4001"]), env(&[])).is_err());
+
+        let server = load(
+            args(&[
+                "--listen",
+                "10.0.0.2:4000",
+                "--follow",
+                "leader:4001",
+                "--replication-followers",
+                "b2, b3",
+            ]),
+            env(&[("RLBG_REPLICATION_SECRET", "s3cret")]),
+        )
+        .unwrap()
+        .server;
+        let replication = &server.replication;
+        assert_eq!(replication.secret.as_deref(), Some("s3cret"));
+        // Named by the listen address unless given a name
+        assert_eq!(replication.name, "10.0.0.2:4000");
+        assert_eq!(replication.followers, ["b2", "b3"]);
+        assert_eq!(describe(&server)["replication_followers"], "b2,b3");
+
+        let server = load(args(&["--replication-name", "b2"]), env(&[]))
+            .unwrap()
+            .server;
+        assert_eq!(server.replication.name, "b2");
+        assert!(load(args(&["--replication-secret", &"x".repeat(256)]), env(&[])).is_err());
+    }
+
+    #[test]
+    fn test_reports_every_problem_with_its_source() {
+        let path = config_file("invalid", "shards = 0\nbogus = 1\nworkers\n");
+        let err = load(
+            args(&[
+                "--config",
+                path.to_str().unwrap(),
+                "--listen",
+                "nowhere",
+                "--colour",
+                "red",
+            ]),
+            env(&[("RLBG_WAL_BATCH_SIZE", "many"), ("RLBG_LOG_LEVEL", "loud")]),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let message = err.to_string();
+        let lines: Vec<&str> = message.lines().collect();
+        assert_eq!(lines.len(), 6, "{}", message);
+        assert!(message.contains("unknown flag --colour"));
+        assert!(message.contains(":2: unknown key \"bogus\""));
+        assert!(message.contains(":3: expected `key = value`"));
+        assert!(message.contains("flag --listen: listen = \"nowhere\": expected host:port"));
+        assert!(message.contains("RLBG_WAL_BATCH_SIZE: wal_batch_size = \"many\""));
+        assert!(message.contains("unknown log level \"loud\""));
+        // The file was rejected as a whole, so its shard count is not checked
+        assert!(!message.contains("shards"));
+
+        let err = load(args(&["--shards", "0"]), env(&[])).unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("shards = \"0\": must be at least 1")
+        );
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,316 @@
+//! ChaCha20-Poly1305 as specified in RFC 8439.
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn chacha20_block(key: &[u8; KEY_LEN], counter: u32, nonce: &[u8; NONCE_LEN]) -> [u8; 64] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&CONSTANTS);
+    for i in 0..8 {
+        state[4 + i] = le32(&key[i * 4..]);
+    }
+    state[12] = counter;
+    for i in 0..3 {
+        state[13 + i] = le32(&nonce[i * 4..]);
+    }
+
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+
+    let mut out = [0u8; 64];
+    for i in 0..16 {
+        let word = working[i].wrapping_add(state[i]);
+        out[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+/// XORs `data` in place with the keystream starting at block `counter`.
+pub fn chacha20_xor(key: &[u8; KEY_LEN], counter: u32, nonce: &[u8; NONCE_LEN], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let block = chacha20_block(key, counter.wrapping_add(i as u32), nonce);
+        for (b, k) in chunk.iter_mut().zip(block.iter()) {
+            *b ^= k;
+        }
+    }
+}
+
+/// Poly1305 one-time authenticator, using 26-bit limbs.
+struct Poly1305 {
+    r: [u32; 5],
+    h: [u32; 5],
+    pad: [u32; 4],
+    buffer: [u8; 16],
+    buffered: usize,
+}
+
+impl Poly1305 {
+    fn new(key: &[u8; 32]) -> Self {
+        Self {
+            r: [
+                le32(&key[0..]) & 0x03ff_ffff,
+                (le32(&key[3..]) >> 2) & 0x03ff_ff03,
+                (le32(&key[6..]) >> 4) & 0x03ff_c0ff,
+                (le32(&key[9..]) >> 6) & 0x03f0_3fff,
+                (le32(&key[12..]) >> 8) & 0x000f_ffff,
+            ],
+            h: [0; 5],
+            pad: [
+                le32(&key[16..]),
+                le32(&key[20..]),
+                le32(&key[24..]),
+                le32(&key[28..]),
+            ],
+            buffer: [0; 16],
+            buffered: 0,
+        }
+    }
+
+    fn block(&mut self, m: &[u8; 16], hibit: u32) {
+        let [r0, r1, r2, r3, r4] = self.r.map(u64::from);
+        let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+
+        let h0 = (self.h[0] + (le32(&m[0..]) & 0x03ff_ffff)) as u64;
+        let h1 = (self.h[1] + ((le32(&m[3..]) >> 2) & 0x03ff_ffff)) as u64;
+        let h2 = (self.h[2] + ((le32(&m[6..]) >> 4) & 0x03ff_ffff)) as u64;
+        let h3 = (self.h[3] + ((le32(&m[9..]) >> 6) & 0x03ff_ffff)) as u64;
+        let h4 = (self.h[4] + ((le32(&m[12..]) >> 8) | hibit)) as u64;
+
+        let d0 = h0 * r0 + h1 * s4 + h2 * s3 + h3 * s2 + h4 * s1;
+        let mut d1 = h0 * r1 + h1 * r0 + h2 * s4 + h3 * s3 + h4 * s2;
+        let mut d2 = h0 * r2 + h1 * r1 + h2 * r0 + h3 * s4 + h4 * s3;
+        let mut d3 = h0 * r3 + h1 * r2 + h2 * r1 + h3 * r0 + h4 * s4;
+        let mut d4 = h0 * r4 + h1 * r3 + h2 * r2 + h3 * r1 + h4 * r0;
+
+        d1 += d0 >> 26;
+        d2 += d1 >> 26;
+        d3 += d2 >> 26;
+        d4 += d3 >> 26;
+        let mut h0 = (d0 & 0x03ff_ffff) as u32 + (d4 >> 26) as u32 * 5;
+        let h1 = (d1 & 0x03ff_ffff) as u32 + (h0 >> 26);
+        h0 &= 0x03ff_ffff;
+
+        self.h = [
+            h0,
+            h1,
+            (d2 & 0x03ff_ffff) as u32,
+            (d3 & 0x03ff_ffff) as u32,
+            (d4 & 0x03ff_ffff) as u32,
+        ];
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        if self.buffered > 0 {
+            let take = data.len().min(16 - self.buffered);
+            self.buffer[self.buffered..self.buffered + take].copy_from_slice(&data[..take]);
+            self.buffered += take;
+            data = &data[take..];
+            if self.buffered < 16 {
+                return;
+            }
+            let block = self.buffer;
+            self.block(&block, 1 << 24);
+            self.buffered = 0;
+        }
+        let mut chunks = data.chunks_exact(16);
+        for chunk in &mut chunks {
+            self.block(chunk.try_into().unwrap(), 1 << 24);
+        }
+        let rest = chunks.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    /// Feeds zeros up to the next 16-byte boundary, as the AEAD layout asks.
+    fn pad_to_block(&mut self) {
+        if self.buffered > 0 {
+            self.update(&[0u8; 16][..16 - self.buffered]);
+        }
+    }
+
+    fn finish(mut self) -> [u8; TAG_LEN] {
+        if self.buffered > 0 {
+            let mut block = [0u8; 16];
+            block[..self.buffered].copy_from_slice(&self.buffer[..self.buffered]);
+            block[self.buffered] = 1;
+            self.block(&block, 0);
+        }
+
+        let mut h = self.h;
+        let mut carry = h[1] >> 26;
+        h[1] &= 0x03ff_ffff;
+        for limb in &mut h[2..] {
+            *limb += carry;
+            carry = *limb >> 26;
+            *limb &= 0x03ff_ffff;
+        }
+        h[0] += carry * 5;
+        carry = h[0] >> 26;
+        h[0] &= 0x03ff_ffff;
+        h[1] += carry;
+
+        // g = h + 5 - 2^130; use it if it did not go negative
+        let mut g = [0u32; 5];
+        g[0] = h[0] + 5;
+        carry = g[0] >> 26;
+        g[0] &= 0x03ff_ffff;
+        for (g, h) in g[1..4].iter_mut().zip(&h[1..4]) {
+            *g = h + carry;
+            carry = *g >> 26;
+            *g &= 0x03ff_ffff;
+        }
+        g[4] = h[4].wrapping_add(carry).wrapping_sub(1 << 26);
+
+        let use_g = (g[4] >> 31).wrapping_sub(1);
+        for (h, g) in h.iter_mut().zip(g) {
+            *h = (*h & !use_g) | (g & use_g);
+        }
+
+        let words = [
+            h[0] | (h[1] << 26),
+            (h[1] >> 6) | (h[2] << 20),
+            (h[2] >> 12) | (h[3] << 14),
+            (h[3] >> 18) | (h[4] << 8),
+        ];
+        let mut tag = [0u8; TAG_LEN];
+        let mut acc = 0u64;
+        for ((out, word), pad) in tag.chunks_exact_mut(4).zip(words).zip(self.pad) {
+            acc += word as u64 + pad as u64;
+            out.copy_from_slice(&(acc as u32).to_le_bytes());
+            acc >>= 32;
+        }
+        tag
+    }
+}
+
+fn compute_tag(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> [u8; TAG_LEN] {
+    let block = chacha20_block(key, 0, nonce);
+    let mut mac = Poly1305::new(block[..32].try_into().unwrap());
+    mac.update(aad);
+    mac.pad_to_block();
+    mac.update(ciphertext);
+    mac.pad_to_block();
+    mac.update(&(aad.len() as u64).to_le_bytes());
+    mac.update(&(ciphertext.len() as u64).to_le_bytes());
+    mac.finish()
+}
+
+/// Encrypts `data` in place and returns its tag.
+pub fn seal(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    data: &mut [u8],
+) -> [u8; TAG_LEN] {
+    chacha20_xor(key, 1, nonce, data);
+    compute_tag(key, nonce, aad, data)
+}
+
+/// Checks `tag` and decrypts `data` in place. On a mismatch `data` is left
+/// untouched and `false` is returned.
+pub fn open(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    data: &mut [u8],
+    tag: &[u8; TAG_LEN],
+) -> bool {
+    let expected = compute_tag(key, nonce, aad, data);
+    let diff = expected
+        .iter()
+        .zip(tag.iter())
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    if diff != 0 {
+        return false;
+    }
+    chacha20_xor(key, 1, nonce, data);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        let text: String = text.split_whitespace().collect();
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_poly1305_rfc8439_vector() {
+        let key = hex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b");
+        let mut mac = Poly1305::new(key.as_slice().try_into().unwrap());
+        mac.update(b"Cryptographic Forum ");
+        mac.update(b"Research Group");
+        assert_eq!(
+            mac.finish().to_vec(),
+            hex("a8061dc1305136c6c22b8baf0c0127a9")
+        );
+    }
+
+    #[test]
+    fn test_aead_rfc8439_vector() {
+        let key: [u8; KEY_LEN] = core::array::from_fn(|i| 0x80 + i as u8);
+        let nonce: [u8; NONCE_LEN] = hex("070000004041424344454647").try_into().unwrap();
+        let aad = hex("50515253c0c1c2c3c4c5c6c7");
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+                          only one tip for the future, sunscreen would be it.";
+
+        let mut data = plaintext.to_vec();
+        let tag = seal(&key, &nonce, &aad, &mut data);
+        assert_eq!(
+            data,
+            hex(
+                "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6
+                 3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36
+                 92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc
+                 3ff4def08e4b7a9de576d26586cec64b6116"
+            )
+        );
+        assert_eq!(tag.to_vec(), hex("1ae10b594f09e26a7e902ecbd0600691"));
+
+        assert!(open(&key, &nonce, &aad, &mut data, &tag));
+        assert_eq!(data, plaintext);
+
+        let mut tampered = tag;
+        tampered[0] ^= 1;
+        let mut sealed = data.clone();
+        seal(&key, &nonce, &aad, &mut sealed);
+        assert!(!open(&key, &nonce, &aad, &mut sealed, &tampered));
+    }
+}
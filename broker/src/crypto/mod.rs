@@ -0,0 +1,225 @@
+pub mod aead;
+
+use aead::{KEY_LEN, NONCE_LEN, TAG_LEN};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Extra bytes a sealed value carries: its nonce and tag.
+pub const SEAL_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+/// Longest key id that fits a file header.
+pub const MAX_KEY_ID_LEN: usize = u8::MAX as usize;
+
+struct Key {
+    id: String,
+    bytes: [u8; KEY_LEN],
+}
+
+/// Encryption keys for data at rest.
+///
+/// Every key can decrypt, the active one (the last loaded) encrypts. Keys
+/// are named so files can record which key wrote them: to rotate, append a
+/// new key and keep the old ones until every file has been rewritten.
+pub struct Keyring {
+    keys: Vec<Key>,
+    /// Next nonce, seeded randomly and then counted up so no two seals
+    /// under the same key share one.
+    nonce: Mutex<[u8; NONCE_LEN]>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ids: Vec<&str> = self.keys.iter().map(|k| k.id.as_str()).collect();
+        f.debug_struct("Keyring").field("keys", &ids).finish()
+    }
+}
+
+fn invalid(details: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, details)
+}
+
+impl Keyring {
+    /// Parses `<id> <64 hex digits>` lines. Blank lines and `#` comments are
+    /// skipped.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut keys: Vec<Key> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, hex) = line.split_once(char::is_whitespace).ok_or_else(|| {
+                invalid(format!("line {}: expected `<id> <hex key>`", number + 1))
+            })?;
+            let key = parse_key(id, hex.trim())
+                .map_err(|e| invalid(format!("line {}: {}", number + 1, e)))?;
+            if keys.iter().any(|k| k.id == key.id) {
+                return Err(invalid(format!(
+                    "line {}: duplicate key id {}",
+                    number + 1,
+                    id
+                )));
+            }
+            keys.push(key);
+        }
+        if keys.is_empty() {
+            return Err(invalid("no encryption keys".to_string()));
+        }
+        Self::from_keys(keys)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|e| invalid(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Reads keys from `RLBG_ENCRYPTION_KEY_FILE`, or a single `<id>:<hex>`
+    /// key from `RLBG_ENCRYPTION_KEY`. `None` when neither is set.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        Self::from_vars(|var| std::env::var(var).ok())
+    }
+
+    /// Like `from_env`, reading variables through `env`.
+    pub fn from_vars(env: impl Fn(&str) -> Option<String>) -> io::Result<Option<Self>> {
+        if let Some(path) = env("RLBG_ENCRYPTION_KEY_FILE") {
+            return Self::load(Path::new(&path)).map(Some);
+        }
+        match env("RLBG_ENCRYPTION_KEY") {
+            Some(value) => {
+                let (id, hex) = value.split_once(':').ok_or_else(|| {
+                    invalid("RLBG_ENCRYPTION_KEY: expected `<id>:<hex key>`".into())
+                })?;
+                let key = parse_key(id, hex)
+                    .map_err(|e| invalid(format!("RLBG_ENCRYPTION_KEY: {}", e)))?;
+                Self::from_keys(vec![key]).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn from_keys(keys: Vec<Key>) -> io::Result<Self> {
+        let mut nonce = [0u8; NONCE_LEN];
+        File::open("/dev/urandom")?.read_exact(&mut nonce)?;
+        Ok(Self {
+            keys,
+            nonce: Mutex::new(nonce),
+        })
+    }
+
+    /// Id of the key new data is sealed with.
+    pub fn active_id(&self) -> &str {
+        &self.keys.last().unwrap().id
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.keys.iter().any(|k| k.id == id)
+    }
+
+    fn next_nonce(&self) -> [u8; NONCE_LEN] {
+        let mut counter = self.nonce.lock().unwrap();
+        let nonce = *counter;
+        for byte in counter.iter_mut() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+        nonce
+    }
+
+    /// Encrypts `plaintext` under the active key, binding it to `aad`.
+    /// Returns `nonce | ciphertext | tag`.
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let key = self.keys.last().unwrap();
+        let nonce = self.next_nonce();
+        let mut sealed = Vec::with_capacity(plaintext.len() + SEAL_OVERHEAD);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(plaintext);
+        let tag = aead::seal(&key.bytes, &nonce, aad, &mut sealed[NONCE_LEN..]);
+        sealed.extend_from_slice(&tag);
+        sealed
+    }
+
+    /// Reverses `seal` with the key named `key_id`. Fails if the key is
+    /// unknown or the data or `aad` were altered.
+    pub fn open(&self, key_id: &str, aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let key = self
+            .keys
+            .iter()
+            .find(|k| k.id == key_id)
+            .ok_or_else(|| invalid(format!("unknown encryption key {}", key_id)))?;
+        if sealed.len() < SEAL_OVERHEAD {
+            return Err(invalid("sealed data too short".to_string()));
+        }
+        let nonce: [u8; NONCE_LEN] = sealed[..NONCE_LEN].try_into().unwrap();
+        let tag: [u8; TAG_LEN] = sealed[sealed.len() - TAG_LEN..].try_into().unwrap();
+        let mut data = sealed[NONCE_LEN..sealed.len() - TAG_LEN].to_vec();
+        if !aead::open(&key.bytes, &nonce, aad, &mut data, &tag) {
+            return Err(invalid("authentication failed".to_string()));
+        }
+        Ok(data)
+    }
+}
+
+/// Whether `given` is `expected`. Compares every byte so the time taken
+/// does not tell how much of a secret was right.
+pub fn same_secret(expected: &[u8], given: &[u8]) -> bool {
+    expected.len() == given.len()
+        && expected
+            .iter()
+            .zip(given)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn parse_key(id: &str, hex: &str) -> Result<Key, String> {
+    if id.is_empty() || id.len() > MAX_KEY_ID_LEN {
+        return Err(format!("key id must be 1 to {} bytes", MAX_KEY_ID_LEN));
+    }
+    if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+        return Err(format!("key must be {} hex digits", KEY_LEN * 2));
+    }
+    let mut bytes = [0u8; KEY_LEN];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("key must be {} hex digits", KEY_LEN * 2))?;
+    }
+    Ok(Key {
+        id: id.to_string(),
+        bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: &str = "# rotated monthly\n\
+                        old 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n\
+                        new 1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100\n";
+
+    #[test]
+    fn test_seal_open_with_rotation() {
+        let keyring = Keyring::parse(KEYS).unwrap();
+        assert_eq!(keyring.active_id(), "new");
+
+        let first = keyring.seal(b"seq 1", b"prompt");
+        let second = keyring.seal(b"seq 1", b"prompt");
+        assert_ne!(first, second, "nonces must not repeat");
+        assert_eq!(keyring.open("new", b"seq 1", &first).unwrap(), b"prompt");
+        assert!(keyring.open("new", b"seq 2", &first).is_err());
+        assert!(keyring.open("old", b"seq 1", &first).is_err());
+        assert!(keyring.open("missing", b"seq 1", &first).is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_keys() {
+        assert!(Keyring::parse("").is_err());
+        assert!(Keyring::parse("k 00ff").is_err());
+        assert!(Keyring::parse(&format!("k {}", "zz".repeat(32))).is_err());
+        assert!(Keyring::parse(&format!("k {0}\nk {0}", "00".repeat(32))).is_err());
+    }
+}
@@ -0,0 +1,720 @@
+//! Raw DEFLATE (RFC 1951), the format behind zlib and gzip.
+//!
+//! `compress` finds repeats with a hash-chained LZ77 search and writes them
+//! with the fixed Huffman codes, falling back to stored blocks for data that
+//! does not shrink. `decompress` reads any valid stream, including the
+//! dynamic Huffman blocks other encoders produce.
+
+use std::io::{self, ErrorKind};
+
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: usize = 15;
+const MAX_CHAIN: usize = 64;
+const MAX_STORED_BLOCK: usize = u16::MAX as usize;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which dynamic blocks list the code lengths of the code length
+/// alphabet.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            out: Vec::new(),
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    /// Writes the low `count` bits of `value`, least significant first.
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes are packed starting from their most significant bit.
+    fn write_code(&mut self, code: u32, len: u32) {
+        self.write(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+fn write_literal(out: &mut BitWriter, symbol: u16) {
+    let symbol = symbol as u32;
+    match symbol {
+        0..=143 => out.write_code(0x30 + symbol, 8),
+        144..=255 => out.write_code(0x190 + symbol - 144, 9),
+        256..=279 => out.write_code(symbol - 256, 7),
+        _ => out.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(out: &mut BitWriter, len: usize, dist: usize) {
+    let code = LENGTH_BASE
+        .iter()
+        .rposition(|&b| b as usize <= len)
+        .unwrap();
+    write_literal(out, 257 + code as u16);
+    out.write(
+        (len - LENGTH_BASE[code] as usize) as u32,
+        LENGTH_EXTRA[code] as u32,
+    );
+
+    let code = DIST_BASE.iter().rposition(|&b| b as usize <= dist).unwrap();
+    out.write_code(code as u32, 5);
+    out.write(
+        (dist - DIST_BASE[code] as usize) as u32,
+        DIST_EXTRA[code] as u32,
+    );
+}
+
+fn hash(data: &[u8]) -> usize {
+    let v = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+    (v.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Compresses `data` into a raw DEFLATE stream.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter::new();
+    out.write(1, 1); // final block
+    out.write(1, 2); // fixed Huffman codes
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    // Chain links are kept for one window only, indexed by position modulo
+    // its size. A slot is reused a full window later, by which time the
+    // position it linked is too far back to be followed.
+    let window = data.len().next_power_of_two().min(WINDOW_SIZE);
+    let mut prev = vec![usize::MAX; window];
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best_len = 0;
+        let mut best_dist = 0;
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(&data[pos..]);
+            let max_len = MAX_MATCH.min(data.len() - pos);
+            let mut candidate = head[h];
+            let mut chain = 0;
+            while candidate != usize::MAX && pos - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let len = data[candidate..]
+                    .iter()
+                    .zip(&data[pos..pos + max_len])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len {
+                    best_len = len;
+                    best_dist = pos - candidate;
+                    if len == max_len {
+                        break;
+                    }
+                }
+                candidate = prev[candidate & (window - 1)];
+                chain += 1;
+            }
+        }
+
+        let step = if best_len >= MIN_MATCH {
+            write_match(&mut out, best_len, best_dist);
+            best_len
+        } else {
+            write_literal(&mut out, data[pos] as u16);
+            1
+        };
+        for p in pos..pos + step {
+            if p + MIN_MATCH <= data.len() {
+                let h = hash(&data[p..]);
+                prev[p & (window - 1)] = head[h];
+                head[h] = p;
+            }
+        }
+        pos += step;
+    }
+    write_literal(&mut out, 256);
+
+    let compressed = out.finish();
+    if compressed.len() > stored_len(data.len()) {
+        return stored(data);
+    }
+    compressed
+}
+
+fn stored_len(len: usize) -> usize {
+    len + 5 * len.div_ceil(MAX_STORED_BLOCK).max(1)
+}
+
+/// Wraps `data` in uncompressed blocks.
+fn stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(stored_len(data.len()));
+    let mut chunks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        out.push(chunks.peek().is_none() as u8);
+        out.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(!(chunk.len() as u16)).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out
+}
+
+fn corrupt(details: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("deflate: {}", details))
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> io::Result<u32> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| corrupt("unexpected end of stream"))?;
+            value |= (((byte >> self.bit) & 1) as u32) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        if self.bit > 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+/// A canonical Huffman code, as symbol counts per length and the symbols
+/// in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Self> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(corrupt("oversubscribed Huffman code"));
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, input: &mut BitReader) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= input.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(corrupt("bad Huffman code"))
+    }
+}
+
+fn fixed_codes() -> io::Result<(Huffman, Huffman)> {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+fn dynamic_codes(input: &mut BitReader) -> io::Result<(Huffman, Huffman)> {
+    let literals = input.bits(5)? as usize + 257;
+    let distances = input.bits(5)? as usize + 1;
+    let code_lengths = input.bits(4)? as usize + 4;
+
+    let mut lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[i] = input.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&lengths)?;
+
+    let mut lengths = vec![0u8; literals + distances];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = code_length_code.decode(input)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths[..i]
+                    .last()
+                    .ok_or_else(|| corrupt("repeat with no previous length"))?;
+                (previous, 3 + input.bits(2)? as usize)
+            }
+            17 => (0, 3 + input.bits(3)? as usize),
+            _ => (0, 11 + input.bits(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err(corrupt("too many code lengths"));
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(corrupt("no end-of-block code"));
+    }
+    Ok((
+        Huffman::new(&lengths[..literals])?,
+        Huffman::new(&lengths[literals..])?,
+    ))
+}
+
+/// Decompresses a raw DEFLATE stream, failing if the output would exceed
+/// `limit` bytes.
+pub fn decompress(data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    let mut input = BitReader {
+        data,
+        pos: 0,
+        bit: 0,
+    };
+    let mut out = Vec::new();
+    let too_large = || io::Error::new(ErrorKind::InvalidData, "deflate: output too large");
+
+    loop {
+        let last = input.bits(1)? == 1;
+        match input.bits(2)? {
+            0 => {
+                input.align();
+                let header = data
+                    .get(input.pos..input.pos + 4)
+                    .ok_or_else(|| corrupt("truncated stored block"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(corrupt("stored block length mismatch"));
+                }
+                let start = input.pos + 4;
+                let block = data
+                    .get(start..start + len as usize)
+                    .ok_or_else(|| corrupt("truncated stored block"))?;
+                if out.len() + block.len() > limit {
+                    return Err(too_large());
+                }
+                out.extend_from_slice(block);
+                input.pos = start + len as usize;
+            }
+            kind @ (1 | 2) => {
+                let (literals, distances) = if kind == 1 {
+                    fixed_codes()?
+                } else {
+                    dynamic_codes(&mut input)?
+                };
+                loop {
+                    let symbol = literals.decode(&mut input)? as usize;
+                    if symbol < 256 {
+                        if out.len() >= limit {
+                            return Err(too_large());
+                        }
+                        out.push(symbol as u8);
+                        continue;
+                    }
+                    if symbol == 256 {
+                        break;
+                    }
+                    let code = symbol - 257;
+                    if code >= LENGTH_BASE.len() {
+                        return Err(corrupt("bad length code"));
+                    }
+                    let len = LENGTH_BASE[code] as usize
+                        + input.bits(LENGTH_EXTRA[code] as u32)? as usize;
+                    let code = distances.decode(&mut input)? as usize;
+                    if code >= DIST_BASE.len() {
+                        return Err(corrupt("bad distance code"));
+                    }
+                    let dist =
+                        DIST_BASE[code] as usize + input.bits(DIST_EXTRA[code] as u32)? as usize;
+                    if dist > out.len() {
+                        return Err(corrupt("distance too far back"));
+                    }
+                    if out.len() + len > limit {
+                        return Err(too_large());
+                    }
+                    let start = out.len() - dist;
+                    for i in 0..len {
+                        out.push(out[start + i]);
+                    }
+                }
+            }
+            _ => return Err(corrupt("reserved block type")),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift64*, so every run sees the same inputs.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    /// Mixes literals from a random alphabet, runs, and copies of earlier
+    /// stretches, some from beyond the window.
+    fn random_input(rng: &mut Rng) -> Vec<u8> {
+        let len = match rng.below(4) {
+            0 => rng.below(16),
+            1 => rng.below(1000),
+            2 => rng.below(20_000),
+            _ => rng.below(100_000),
+        };
+        let alphabet = 1 + rng.below(256);
+        let mut data = Vec::with_capacity(len + MAX_MATCH);
+        while data.len() < len {
+            match rng.below(4) {
+                0 if !data.is_empty() => {
+                    let dist = 1 + rng.below(data.len().min(WINDOW_SIZE + 1000));
+                    let start = data.len() - dist;
+                    for i in 0..rng.below(MAX_MATCH + 50) {
+                        data.push(data[start + i]);
+                    }
+                }
+                1 => {
+                    let byte = rng.below(alphabet) as u8;
+                    let run = rng.below(MAX_MATCH + 50);
+                    data.extend(std::iter::repeat_n(byte, run));
+                }
+                _ => data.push(rng.below(alphabet) as u8),
+            }
+        }
+        data.truncate(len);
+        data
+    }
+
+    #[test]
+    fn test_round_trips_random_inputs() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..200 {
+            let data = random_input(&mut rng);
+            let compressed = compress(&data);
+            assert!(compressed.len() <= stored_len(data.len()));
+            assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_finds_matches_long_after_the_chain_links_wrap() {
+        let mut rng = Rng(0x5851_f42d_4c95_7f2d);
+        let block = rng.bytes(WINDOW_SIZE / 4 + 3);
+        let data = block.repeat(40);
+        let compressed = compress(&data);
+        assert!(compressed.len() < data.len() / 20, "{}", compressed.len());
+        assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn test_incompressible_inputs_fall_back_to_stored_blocks() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for len in [
+            1000,
+            MAX_STORED_BLOCK - 1,
+            MAX_STORED_BLOCK,
+            MAX_STORED_BLOCK + 1,
+            3 * MAX_STORED_BLOCK + 7,
+        ] {
+            let data = rng.bytes(len);
+            let compressed = compress(&data);
+            assert_eq!(compressed.len(), stored_len(len));
+            assert_eq!(compressed[0] >> 1 & 0b11, 0, "not a stored block");
+            assert_eq!(decompress(&compressed, len).unwrap(), data);
+            assert!(decompress(&compressed, len - 1).is_err());
+        }
+    }
+
+    enum Token {
+        Literal(u8),
+        Match { len: usize, dist: usize },
+    }
+
+    /// Random literals and matches, appending what they stand for to `out`.
+    fn random_tokens(rng: &mut Rng, out: &mut Vec<u8>) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        for _ in 0..rng.below(500) {
+            if out.is_empty() || rng.below(3) > 0 {
+                let byte = rng.next() as u8;
+                out.push(byte);
+                tokens.push(Token::Literal(byte));
+            } else {
+                let len = MIN_MATCH + rng.below(MAX_MATCH - MIN_MATCH + 1);
+                let dist = 1 + rng.below(out.len().min(WINDOW_SIZE));
+                let start = out.len() - dist;
+                for i in 0..len {
+                    out.push(out[start + i]);
+                }
+                tokens.push(Token::Match { len, dist });
+            }
+        }
+        tokens
+    }
+
+    /// Canonical codes for `lengths`, as RFC 1951 assigns them.
+    fn canonical_codes(lengths: &[u8]) -> Vec<u32> {
+        let mut counts = [0u32; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut next = [0u32; 16];
+        for len in 1..16 {
+            next[len] = (next[len - 1] + counts[len - 1]) << 1;
+        }
+        lengths
+            .iter()
+            .map(|&len| {
+                let code = next[len as usize];
+                next[len as usize] += 1;
+                code
+            })
+            .collect()
+    }
+
+    /// Lengths of a complete code over `symbols` symbols that uses only
+    /// `short` and `short + 1` bits, shuffled.
+    fn random_lengths(rng: &mut Rng, symbols: usize, short: u8) -> Vec<u8> {
+        let long_count = 2 * symbols - (1 << (short + 1));
+        let mut lengths = vec![short; symbols];
+        let mut placed = 0;
+        while placed < long_count {
+            let at = rng.below(symbols);
+            if lengths[at] == short {
+                lengths[at] = short + 1;
+                placed += 1;
+            }
+        }
+        lengths
+    }
+
+    /// Writes a dynamic block with random code lengths, sent with repeat
+    /// codes where they run.
+    fn write_dynamic_block(out: &mut BitWriter, rng: &mut Rng, tokens: &[Token]) {
+        let literal_lengths = random_lengths(rng, 286, 8);
+        let distance_lengths = random_lengths(rng, 30, 4);
+        out.write(2, 2);
+        out.write(286 - 257, 5);
+        out.write(30 - 1, 5);
+        out.write(19 - 4, 4);
+        // Code length symbols 4, 5, 8, 9 and repeat 16
+        let mut code_length_lengths = [0u8; 19];
+        code_length_lengths[4] = 3;
+        code_length_lengths[5] = 3;
+        code_length_lengths[8] = 2;
+        code_length_lengths[9] = 2;
+        code_length_lengths[16] = 2;
+        for &i in &CODE_LENGTH_ORDER {
+            out.write(code_length_lengths[i] as u32, 3);
+        }
+        let code_length_codes = canonical_codes(&code_length_lengths);
+        let lengths = [literal_lengths.as_slice(), &distance_lengths].concat();
+        let mut i = 0;
+        while i < lengths.len() {
+            let value = lengths[i] as usize;
+            out.write_code(code_length_codes[value], code_length_lengths[value] as u32);
+            i += 1;
+            let run = lengths[i..].iter().take_while(|&&l| l as usize == value);
+            let repeat = run.count().min(6);
+            if repeat >= 3 {
+                out.write_code(code_length_codes[16], code_length_lengths[16] as u32);
+                out.write(repeat as u32 - 3, 2);
+                i += repeat;
+            }
+        }
+
+        let literal_codes = canonical_codes(&literal_lengths);
+        let distance_codes = canonical_codes(&distance_lengths);
+        let symbol = |out: &mut BitWriter, symbol: usize| {
+            out.write_code(literal_codes[symbol], literal_lengths[symbol] as u32)
+        };
+        for token in tokens {
+            match *token {
+                Token::Literal(byte) => symbol(out, byte as usize),
+                Token::Match { len, dist } => {
+                    let code = LENGTH_BASE.iter().rposition(|&b| b as usize <= len);
+                    let code = code.unwrap();
+                    symbol(out, 257 + code);
+                    let extra = (len - LENGTH_BASE[code] as usize) as u32;
+                    out.write(extra, LENGTH_EXTRA[code] as u32);
+                    let code = DIST_BASE.iter().rposition(|&b| b as usize <= dist);
+                    let code = code.unwrap();
+                    out.write_code(distance_codes[code], distance_lengths[code] as u32);
+                    let extra = (dist - DIST_BASE[code] as usize) as u32;
+                    out.write(extra, DIST_EXTRA[code] as u32);
+                }
+            }
+        }
+        symbol(out, 256);
+    }
+
+    #[test]
+    fn test_decompresses_random_streams_of_every_block_type() {
+        let mut rng = Rng(0x5851_f42d_4c95_7f2d);
+        for _ in 0..200 {
+            let mut out = BitWriter::new();
+            let mut expected = Vec::new();
+            let blocks = 1 + rng.below(4);
+            for block in 0..blocks {
+                out.write((block + 1 == blocks) as u32, 1);
+                match rng.below(3) {
+                    0 => {
+                        let len = rng.below(2000);
+                        let data = rng.bytes(len);
+                        out.write(0, 2);
+                        out.write(0, (8 - out.count % 8) % 8);
+                        out.write(data.len() as u32, 16);
+                        out.write(!(data.len() as u16) as u32, 16);
+                        for &byte in &data {
+                            out.write(byte as u32, 8);
+                        }
+                        expected.extend_from_slice(&data);
+                    }
+                    1 => {
+                        out.write(1, 2);
+                        for token in random_tokens(&mut rng, &mut expected) {
+                            match token {
+                                Token::Literal(byte) => write_literal(&mut out, byte as u16),
+                                Token::Match { len, dist } => write_match(&mut out, len, dist),
+                            }
+                        }
+                        write_literal(&mut out, 256);
+                    }
+                    _ => {
+                        let tokens = random_tokens(&mut rng, &mut expected);
+                        write_dynamic_block(&mut out, &mut rng, &tokens);
+                    }
+                }
+            }
+            let stream = out.finish();
+            assert_eq!(decompress(&stream, expected.len()).unwrap(), expected);
+            // Cut short, a stream fails rather than coming back shorter
+            assert!(decompress(&stream[..stream.len() - 1], usize::MAX).is_err());
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let text = b"{\"prompt\": \"summarise the following document\", \"context\": \
+                     \"the quick brown fox jumps over the lazy dog, the quick brown fox\"}"
+            .repeat(20);
+        let noise: Vec<u8> = (0..5000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        for data in [&b""[..], b"a", b"aaaaaaaaaaaaaaaaaaaaaaaa", &text, &noise] {
+            let compressed = compress(data);
+            assert_eq!(decompress(&compressed, usize::MAX).unwrap(), data);
+        }
+        assert!(compress(&text).len() < text.len() / 10);
+        assert!(compress(&noise).len() <= noise.len() + 5);
+    }
+
+    #[test]
+    fn test_decompress_zlib_dynamic_block() {
+        // Python's zlib at level 9, which picks a dynamic Huffman block here
+        let compressed = [
+            0xbd, 0xcb, 0xd1, 0x0d, 0x80, 0x20, 0x10, 0x04, 0xd1, 0x56, 0xb6, 0x01, 0x6d, 0xc0,
+            0x6a, 0x30, 0x6c, 0x84, 0x80, 0x1c, 0xb9, 0x3b, 0x45, 0xbb, 0x57, 0x9b, 0xf0, 0x6f,
+            0x92, 0x97, 0xf1, 0x44, 0xac, 0x2a, 0x85, 0x8a, 0x42, 0x76, 0x03, 0x4f, 0xea, 0x0d,
+            0x4b, 0x41, 0x23, 0x72, 0x43, 0x76, 0x83, 0x8c, 0x86, 0xa1, 0xd9, 0x39, 0x85, 0xc4,
+            0x10, 0x51, 0x65, 0x5b, 0xe0, 0xbf, 0x8f, 0x5d, 0x65, 0xef, 0x2f, 0x86, 0x16, 0xa1,
+            0xb4, 0xa3, 0x7e, 0xad, 0xc4, 0x2e, 0xe6, 0xf5, 0x86, 0xf3, 0xf2, 0xf9, 0x01,
+        ];
+        let mut expected = b"the broker keeps every shard in its own write-ahead log; ".repeat(3);
+        expected.extend_from_slice(b"prompts and results are mostly text.");
+        assert_eq!(decompress(&compressed, usize::MAX).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_decompress_rejects_bombs_and_garbage() {
+        let zeros = compress(&[0u8; 100_000]);
+        assert!(decompress(&zeros, 1000).is_err());
+        assert!(decompress(&[0xff, 0xff, 0xff], usize::MAX).is_err());
+        assert!(decompress(&[], usize::MAX).is_err());
+    }
+}
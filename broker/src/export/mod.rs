@@ -0,0 +1,207 @@
+//! Portable JSON Lines format for moving queued messages between brokers.
+//!
+//! One message per line:
+//!
+//! {"seq":12,"type":1,"flags":0,"tlvs":[{"tag":1,"text":"job42"},{"tag":3,"hex":"00000005"}]}
+//!
+//! TLV values that are printable UTF-8 are written as `text`, anything else
+//! as `hex`. `seq` is informational; importing assigns fresh seqs.
+
+pub mod json;
+
+use crate::protocol::{Header, MAGIC, Message, MessageType, Tlv, VERSION};
+use json::Value;
+use std::io::{BufRead, Error, ErrorKind, Result, Write};
+
+pub fn write_message(out: &mut impl Write, seq: u64, msg: &Message) -> Result<()> {
+    let mut line = format!(
+        "{{\"seq\":{},\"type\":{},\"flags\":{},\"tlvs\":[",
+        seq, msg.header.msg_type as u8, msg.header.flags
+    );
+    for (i, tlv) in msg.tlvs.iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        line.push_str(&format!("{{\"tag\":{},", tlv.tag));
+        match std::str::from_utf8(&tlv.value) {
+            Ok(text) if !text.chars().any(|c| c.is_control() && !c.is_whitespace()) => {
+                line.push_str("\"text\":");
+                json::escape(&mut line, text);
+            }
+            _ => {
+                let hex: String = tlv.value.iter().map(|b| format!("{:02x}", b)).collect();
+                line.push_str(&format!("\"hex\":\"{}\"", hex));
+            }
+        }
+        line.push('}');
+    }
+    line.push_str("]}\n");
+    out.write_all(line.as_bytes())
+}
+
+/// Reads every message of an export, calling `f` for each in file order, and
+/// returns how many were read.
+pub fn read_messages(
+    input: impl BufRead,
+    mut f: impl FnMut(Message) -> Result<()>,
+) -> Result<usize> {
+    let mut count = 0;
+    for (lineno, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let msg = decode_line(&line)
+            .map_err(|e| Error::new(e.kind(), format!("line {}: {}", lineno + 1, e)))?;
+        f(msg)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn invalid(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, what.to_string())
+}
+
+fn decode_line(line: &str) -> Result<Message> {
+    let value = json::parse(line)?;
+    let msg_type = value
+        .get("type")
+        .and_then(Value::as_u64)
+        .and_then(|t| u8::try_from(t).ok())
+        .and_then(MessageType::from_u8)
+        .ok_or_else(|| invalid("missing or unknown type"))?;
+    let flags = match value.get("flags") {
+        Some(flags) => flags
+            .as_u64()
+            .and_then(|f| u16::try_from(f).ok())
+            .ok_or_else(|| invalid("invalid flags"))?,
+        None => 0,
+    };
+    let tlvs = value
+        .get("tlvs")
+        .and_then(Value::as_array)
+        .ok_or_else(|| invalid("missing tlvs"))?
+        .iter()
+        .map(decode_tlv)
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Message {
+        header: Header {
+            magic: *MAGIC,
+            version: VERSION,
+            msg_type,
+            flags,
+            payload_len: 0,
+        },
+        tlvs,
+    })
+}
+
+fn decode_tlv(value: &Value) -> Result<Tlv> {
+    let tag = value
+        .get("tag")
+        .and_then(Value::as_u64)
+        .and_then(|t| u8::try_from(t).ok())
+        .ok_or_else(|| invalid("missing or invalid tlv tag"))?;
+    let bytes = if let Some(text) = value.get("text").and_then(Value::as_str) {
+        text.as_bytes().to_vec()
+    } else if let Some(hex) = value.get("hex").and_then(Value::as_str) {
+        decode_hex(hex).ok_or_else(|| invalid("invalid hex tlv value"))?
+    } else {
+        return Err(invalid("tlv needs a text or hex value"));
+    };
+    if bytes.len() > u16::MAX as usize {
+        return Err(invalid("tlv value longer than 65535 bytes"));
+    }
+    Ok(Tlv { tag, value: bytes })
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_message() -> Message {
+        Message {
+            header: Header {
+                magic: *MAGIC,
+                version: VERSION,
+                msg_type: MessageType::JobPush,
+                flags: 0,
+                payload_len: 0,
+            },
+            tlvs: vec![
+                Tlv {
+                    tag: 0x01,
+                    value: b"job42".to_vec(),
+                },
+                Tlv {
+                    tag: 0x02,
+                    value: "{\"query\": \"caf\u{e9}\\n\"}\n".as_bytes().to_vec(),
+                },
+                Tlv {
+                    tag: 0x03,
+                    value: 5i32.to_be_bytes().to_vec(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_write_message_format() {
+        let mut out = Vec::new();
+        write_message(&mut out, 7, &make_message()).unwrap();
+        let line = String::from_utf8(out).unwrap();
+        assert!(line.starts_with(
+            "{\"seq\":7,\"type\":1,\"flags\":0,\"tlvs\":[{\"tag\":1,\"text\":\"job42\"},"
+        ));
+        assert!(line.ends_with("{\"tag\":3,\"hex\":\"00000005\"}]}\n"));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let msg = make_message();
+        let mut out = Vec::new();
+        write_message(&mut out, 0, &msg).unwrap();
+        write_message(&mut out, 1, &msg).unwrap();
+
+        let mut read = Vec::new();
+        let count = read_messages(out.as_slice(), |m| {
+            read.push(m);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(read[1].tlvs, msg.tlvs);
+        assert_eq!(read[1].header.msg_type, MessageType::JobPush);
+    }
+
+    #[test]
+    fn test_bad_line_reports_line_number() {
+        let input = b"\n{\"type\":1,\"tlvs\":[]}\n{\"type\":99,\"tlvs\":[]}\n";
+        let err = read_messages(&input[..], |_| Ok(())).unwrap_err();
+        assert!(err.to_string().starts_with("line 3:"));
+    }
+
+    #[test]
+    fn test_deeply_nested_line_is_refused() {
+        let line = "[".repeat(100_000);
+        let err = read_messages(line.as_bytes(), |_| Ok(())).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("nested too deeply"), "{}", err);
+
+        let line = r#"{"type":1,"tlvs":[{"tag":1,"text":[]}]}"#;
+        let err = read_messages(line.as_bytes(), |_| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("nested too deeply"), "{}", err);
+    }
+}
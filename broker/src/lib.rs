@@ -0,0 +1,12 @@
+pub mod broker;
+pub mod checksum;
+pub mod cluster;
+pub mod config;
+pub mod crypto;
+pub mod deflate;
+pub mod export;
+pub mod protocol;
+pub mod replication;
+pub mod shards;
+#[macro_use]
+pub mod logger;
@@ -0,0 +1,349 @@
+//! Log file output with rotation.
+//!
+//! The live file keeps its name; rotated ones get `.1` (the newest) up to
+//! `.<keep>` appended, plus `.gz` when compressed.
+
+use crate::checksum::crc32;
+use crate::deflate;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Input compressed into one gzip member, which bounds the memory a
+/// rotation needs however large the file grew.
+const GZIP_CHUNK: usize = 1024 * 1024;
+const SECS_PER_DAY: u64 = 86_400;
+
+/// When a log file is rotated and what happens to the old ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RotationConfig {
+    /// Rotate once the file holds this many bytes.
+    pub max_bytes: Option<u64>,
+    /// Rotate when the UTC day changes.
+    pub daily: bool,
+    /// Rotated files kept; older ones are deleted.
+    pub keep: usize,
+    /// Compress rotated files with gzip.
+    pub gzip: bool,
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: Some(100 * 1024 * 1024),
+            daily: false,
+            keep: 7,
+            gzip: false,
+        }
+    }
+}
+
+/// An append-only log file that rotates itself as lines are written.
+#[derive(Debug)]
+pub struct FileSink {
+    path: PathBuf,
+    config: RotationConfig,
+    file: File,
+    /// Bytes in the live file.
+    written: u64,
+    /// UTC day, counted from the epoch, the live file was started on.
+    day: u64,
+}
+
+fn day_of(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / SECS_PER_DAY
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl FileSink {
+    /// Opens `path` for appending, carrying on with what is already there.
+    pub fn open(path: impl Into<PathBuf>, config: RotationConfig) -> io::Result<Self> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        let meta = file.metadata()?;
+        let started = meta.modified().unwrap_or_else(|_| SystemTime::now());
+        Ok(Self {
+            path,
+            config,
+            file,
+            written: meta.len(),
+            day: day_of(started),
+        })
+    }
+
+    /// Appends `line` and a newline, rotating first if the file is due.
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.write_line_at(line, SystemTime::now())
+    }
+
+    fn write_line_at(&mut self, line: &str, now: SystemTime) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        let too_big = self
+            .config
+            .max_bytes
+            .is_some_and(|max| self.written > 0 && self.written + len > max);
+        let new_day = self.config.daily && day_of(now) != self.day;
+        if too_big || new_day {
+            self.rotate(now)?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.written += len;
+        Ok(())
+    }
+
+    fn rotated(&self, index: usize, gzip: bool) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        if gzip {
+            name.push(".gz");
+        }
+        PathBuf::from(name)
+    }
+
+    /// Moves the live file to `.1`, shifting older ones up and deleting
+    /// those past `keep`, and starts a new one.
+    fn rotate(&mut self, now: SystemTime) -> io::Result<()> {
+        self.file.flush()?;
+        let keep = self.config.keep;
+        for gzip in [false, true] {
+            let _ = fs::remove_file(self.rotated(keep.max(1), gzip));
+        }
+        for index in (1..keep).rev() {
+            for gzip in [false, true] {
+                let from = self.rotated(index, gzip);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(index + 1, gzip))?;
+                }
+            }
+        }
+        let moved = if keep == 0 {
+            fs::remove_file(&self.path)
+        } else {
+            fs::rename(&self.path, self.rotated(1, false))
+        };
+        match moved {
+            // Gone already, say after a failed reopen: start afresh
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            moved => moved?,
+        }
+        // Logging goes on in a new file whatever happens to the old one
+        self.file = open_append(&self.path)?;
+        self.written = 0;
+        self.day = day_of(now);
+        if keep > 0 && self.config.gzip {
+            self.compress_rotated();
+        }
+        Ok(())
+    }
+
+    /// Replaces `.1` with `.1.gz`. On failure `.1` stays as it is.
+    fn compress_rotated(&self) {
+        let first = self.rotated(1, false);
+        let gz = self.rotated(1, true);
+        let tmp = gz.with_extension("gz.tmp");
+        let compressed = gzip(&first, &tmp)
+            .and_then(|_| fs::rename(&tmp, &gz))
+            .and_then(|_| fs::remove_file(&first));
+        if let Err(e) = compressed {
+            let _ = fs::remove_file(&tmp);
+            // This runs inside the logger, so it can only tell stderr
+            eprintln!(
+                "Failed to gzip {}, keeping it uncompressed: {}",
+                first.display(),
+                e
+            );
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Compresses the file at `from` into a gzip file (RFC 1952) at `to`,
+/// reading `GZIP_CHUNK` bytes at a time and writing each as its own member;
+/// gzip readers join the members back up.
+fn gzip(from: &Path, to: &Path) -> io::Result<()> {
+    let mut input = File::open(from)?;
+    let mut out = BufWriter::new(File::create(to)?);
+    let mut chunk = Vec::with_capacity(GZIP_CHUNK);
+    let mut first = true;
+    loop {
+        chunk.clear();
+        (&mut input)
+            .take(GZIP_CHUNK as u64)
+            .read_to_end(&mut chunk)?;
+        // An empty file still gets one member, so it stays a valid gzip
+        if chunk.is_empty() && !first {
+            break;
+        }
+        write_member(&mut out, &chunk)?;
+        first = false;
+    }
+    out.into_inner()?.sync_all()
+}
+
+fn write_member(out: &mut impl Write, chunk: &[u8]) -> io::Result<()> {
+    // Magic, deflate, no flags, no mtime, no extra flags, unknown OS
+    out.write_all(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff])?;
+    out.write_all(&deflate::compress(chunk))?;
+    out.write_all(&crc32(chunk).to_le_bytes())?;
+    out.write_all(&(chunk.len() as u32).to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rbq_log_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Reads a gzip file holding one member with no optional header fields.
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        assert_eq!(&data[..4], &[0x1f, 0x8b, 8, 0]);
+        let trailer = &data[data.len() - 8..];
+        let size = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+        let inflated = deflate::decompress(&data[10..data.len() - 8], size as usize).unwrap();
+        assert_eq!(inflated.len(), size as usize);
+        assert_eq!(crc32(&inflated).to_le_bytes(), trailer[..4]);
+        inflated
+    }
+
+    #[test]
+    fn test_rotates_by_size_and_keeps_the_newest() {
+        let dir = test_dir("size");
+        let path = dir.join("broker.log");
+        let config = RotationConfig {
+            max_bytes: Some(20),
+            keep: 2,
+            ..Default::default()
+        };
+        let mut sink = FileSink::open(&path, config).unwrap();
+        for line in ["line one", "line two", "line three", "line four"] {
+            sink.write_line(line).unwrap();
+        }
+        sink.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line four\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("broker.log.1")).unwrap(),
+            "line three\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("broker.log.2")).unwrap(),
+            "line one\nline two\n"
+        );
+        assert!(!dir.join("broker.log.3").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_rotates_daily_into_gzip() {
+        let dir = test_dir("daily");
+        let path = dir.join("broker.log");
+        let config = RotationConfig {
+            max_bytes: None,
+            daily: true,
+            keep: 3,
+            gzip: true,
+        };
+        let mut sink = FileSink::open(&path, config).unwrap();
+        let today = SystemTime::now();
+        let tomorrow = today + Duration::from_secs(SECS_PER_DAY);
+        sink.write_line_at("today", today).unwrap();
+        sink.write_line_at("still today", today).unwrap();
+        sink.write_line_at("tomorrow", tomorrow).unwrap();
+        sink.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "tomorrow\n");
+        assert!(!dir.join("broker.log.1").exists());
+        let gz = fs::read(dir.join("broker.log.1.gz")).unwrap();
+        assert_eq!(gunzip(&gz), b"today\nstill today\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_keeps_logging_when_gzip_fails() {
+        let dir = test_dir("gzip_fails");
+        let path = dir.join("broker.log");
+        // A directory where the compressed file would be written
+        fs::create_dir(dir.join("broker.log.1.gz.tmp")).unwrap();
+        let config = RotationConfig {
+            max_bytes: Some(20),
+            keep: 3,
+            gzip: true,
+            ..Default::default()
+        };
+        let mut sink = FileSink::open(&path, config).unwrap();
+        for line in ["line one", "line two", "line three", "line four"] {
+            sink.write_line(line).unwrap();
+        }
+        sink.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line four\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("broker.log.1")).unwrap(),
+            "line three\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("broker.log.2")).unwrap(),
+            "line one\nline two\n"
+        );
+
+        // Once the target is writable again rotation compresses as usual
+        fs::remove_dir(dir.join("broker.log.1.gz.tmp")).unwrap();
+        sink.write_line("line five is longer").unwrap();
+        let gz = fs::read(dir.join("broker.log.1.gz")).unwrap();
+        assert_eq!(gunzip(&gz), b"line four\n");
+        assert!(dir.join("broker.log.2").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_gzips_large_files_a_chunk_at_a_time() {
+        let dir = test_dir("chunks");
+        let path = dir.join("broker.log");
+        let config = RotationConfig {
+            max_bytes: Some(GZIP_CHUNK as u64 * 5 / 2),
+            keep: 1,
+            gzip: true,
+            ..Default::default()
+        };
+        let mut sink = FileSink::open(&path, config).unwrap();
+        let mut logged = Vec::new();
+        let mut line = 0;
+        while logged.len() < GZIP_CHUNK * 2 {
+            let text = format!("line {} of a log that outgrows one gzip chunk", line);
+            sink.write_line(&text).unwrap();
+            logged.extend_from_slice(text.as_bytes());
+            logged.push(b'\n');
+            line += 1;
+        }
+        sink.rotate(SystemTime::now()).unwrap();
+
+        // One member per chunk, as if compressed from memory
+        let gz = fs::read(dir.join("broker.log.1.gz")).unwrap();
+        let mut expected = Vec::new();
+        for chunk in logged.chunks(GZIP_CHUNK) {
+            write_member(&mut expected, chunk).unwrap();
+        }
+        assert!(gz == expected, "gzip members differ");
+        let first = deflate::compress(&logged[..GZIP_CHUNK]).len() + 18;
+        assert_eq!(gunzip(&gz[..first]), &logged[..GZIP_CHUNK]);
+        assert!(!dir.join("broker.log.1.gz.tmp").exists());
+        assert!(!dir.join("broker.log.1").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,19 +1,29 @@
+mod file;
+
+pub use file::{FileSink, RotationConfig};
+
+use crate::export::json;
 use std::collections::VecDeque;
 use std::fmt;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::OnceLock;
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::{self, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const RESET: &str = "\x1b[0m";
 const RED: &str = "\x1b[31m";
 const YELLOW: &str = "\x1b[33m";
 const GREEN: &str = "\x1b[32m";
-const BLUE: &str = "\x1b[34m";
 
-#[derive(Clone, Copy, Debug)]
+/// Crate prefix left off module paths in filters.
+const CRATE_PREFIX: &str = "rlbg::";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Level {
     Info,
     Warn,
@@ -21,6 +31,35 @@ pub enum Level {
     Debug,
 }
 
+impl Level {
+    /// Orders levels by severity, Debug lowest.
+    fn severity(self) -> u8 {
+        match self {
+            Level::Debug => 0,
+            Level::Info => 1,
+            Level::Warn => 2,
+            Level::Error => 3,
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "debug" => Ok(Level::Debug),
+            "info" => Ok(Level::Info),
+            "warn" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            _ => Err(format!(
+                "unknown log level {:?}, expected debug, info, warn or error",
+                s
+            )),
+        }
+    }
+}
+
 impl fmt::Display for Level {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -32,57 +71,404 @@ impl fmt::Display for Level {
     }
 }
 
+/// How log lines are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `[timestamp][level] message key=value`, coloured on a terminal.
+    Text,
+    /// One JSON object per line, for log aggregators.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?}, expected text or json", s)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// A key/value pair attached to a log message, like the peer address.
+pub type Field<'a> = (&'a str, &'a dyn fmt::Display);
+
+/// Which messages get logged: a default level, overridden for modules
+/// and everything under them. Written as `info,shards=warn,broker=debug`;
+/// the default may be left out and is then `info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilter {
+    default: Level,
+    /// Module paths without the crate name, most specific first.
+    modules: Vec<(String, Level)>,
+}
+
+impl LogFilter {
+    /// The level that applies to `module`, a `module_path!()`.
+    pub fn level_for(&self, module: &str) -> Level {
+        let module = module.strip_prefix(CRATE_PREFIX).unwrap_or(module);
+        self.modules
+            .iter()
+            .find(|(prefix, _)| {
+                module
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// Severity of the least severe level anything is logged at.
+    fn min_severity(&self) -> u8 {
+        self.modules
+            .iter()
+            .map(|(_, level)| level.severity())
+            .fold(self.default.severity(), u8::min)
+    }
+}
+
+impl From<Level> for LogFilter {
+    fn from(level: Level) -> Self {
+        Self {
+            default: level,
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = LogFilter::from(Level::Info);
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match part.split_once('=') {
+                Some((module, level)) => {
+                    let module = module.trim().replace('.', "::");
+                    if module.is_empty() {
+                        return Err(format!("missing module name in {:?}", part));
+                    }
+                    let level = level.trim().parse()?;
+                    filter.modules.retain(|(m, _)| *m != module);
+                    filter.modules.push((module, level));
+                }
+                None => filter.default = part.parse()?,
+            }
+        }
+        // Longer paths are more specific, so they are matched first
+        filter
+            .modules
+            .sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then(a.cmp(b)));
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default.to_string().to_ascii_lowercase())?;
+        for (module, level) in &self.modules {
+            write!(f, ",{}={}", module, level.to_string().to_ascii_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+/// What a logging thread does when the buffer of unwritten lines is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogOverflow {
+    /// Wait for the writer to make room, so no line is lost.
+    Block,
+    /// Drop the line and count it, so logging never holds up a thread.
+    Drop,
+}
+
+impl FromStr for LogOverflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "block" => Ok(LogOverflow::Block),
+            "drop" => Ok(LogOverflow::Drop),
+            _ => Err(format!(
+                "unknown overflow policy {:?}, expected block or drop",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LogOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogOverflow::Block => write!(f, "block"),
+            LogOverflow::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// Lines buffered for the writer thread at most, unless changed.
+pub const DEFAULT_BUFFER: usize = 10_000;
+
+/// Lines waiting for the writer thread.
+#[derive(Debug)]
+struct Buffer {
+    lines: VecDeque<String>,
+    capacity: usize,
+    overflow: LogOverflow,
+    /// Lines dropped since the last notice about it was buffered.
+    dropped: u64,
+    /// Set by `shutdown`: the writer writes what is left and exits.
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Shared {
+    buffer: Mutex<Buffer>,
+    /// Signalled when a line is buffered or the buffer closes.
+    ready: Condvar,
+    /// Signalled when the writer takes lines out of the buffer.
+    space: Condvar,
+}
+
 #[derive(Debug)]
 pub struct Logger {
-    sender: Arc<(Mutex<VecDeque<String>>, Condvar)>,
-    _handle: JoinHandle<()>,
+    shared: Arc<Shared>,
+    filter: RwLock<LogFilter>,
+    /// Severity of the least severe level the filter lets through, so most
+    /// dropped messages skip the lock.
+    min_severity: AtomicU8,
+    json: AtomicBool,
+    /// Whether lines go to a terminal that shows colours.
+    colour: AtomicBool,
+    /// Where lines go instead of stdout, if set.
+    file: Arc<Mutex<Option<FileSink>>>,
+    /// Lines dropped because the buffer was full, ever.
+    dropped: AtomicU64,
+    handle: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl Logger {
     pub fn new() -> Self {
-        let queue = Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
-        let queue_clone = queue.clone();
+        let shared = Arc::new(Shared {
+            buffer: Mutex::new(Buffer {
+                lines: VecDeque::new(),
+                capacity: DEFAULT_BUFFER,
+                overflow: LogOverflow::Block,
+                dropped: 0,
+                closed: false,
+            }),
+            ready: Condvar::new(),
+            space: Condvar::new(),
+        });
+        let shared_clone = shared.clone();
+        let file: Arc<Mutex<Option<FileSink>>> = Arc::new(Mutex::new(None));
+        let file_clone = file.clone();
 
         let handle = thread::spawn(move || {
-            let (lock, cvar) = &*queue_clone;
             loop {
-                let mut q = lock.lock().unwrap();
-                while q.is_empty() {
-                    q = cvar.wait(q).unwrap();
-                }
-                while let Some(msg) = q.pop_front() {
-                    let _ = writeln!(io::stdout(), "{}", msg);
+                // Write outside the lock, so a slow disk or a rotation does
+                // not hold up the threads logging
+                let (batch, closed) = {
+                    let mut buffer = shared_clone.buffer.lock().unwrap();
+                    while buffer.lines.is_empty() && !buffer.closed {
+                        buffer = shared_clone.ready.wait(buffer).unwrap();
+                    }
+                    shared_clone.space.notify_all();
+                    (std::mem::take(&mut buffer.lines), buffer.closed)
+                };
+                write_lines(&file_clone, &batch);
+                // Nothing is buffered once closed, so the batch was the last
+                if closed {
+                    return;
                 }
             }
         });
         Self {
-            sender: queue,
-            _handle: handle,
+            shared,
+            filter: RwLock::new(LogFilter::from(Level::Debug)),
+            min_severity: AtomicU8::new(Level::Debug.severity()),
+            json: AtomicBool::new(false),
+            colour: AtomicBool::new(io::stdout().is_terminal()),
+            file,
+            dropped: AtomicU64::new(0),
+            handle: Mutex::new(Some(handle)),
+        }
+    }
+
+    /// Buffers `capacity` lines at most from now on, and handles a full
+    /// buffer by `overflow`.
+    pub fn set_buffer(&self, capacity: usize, overflow: LogOverflow) {
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        buffer.capacity = capacity.max(1);
+        buffer.overflow = overflow;
+        self.shared.space.notify_all();
+    }
+
+    /// Lines waiting to be written.
+    pub fn buffered(&self) -> usize {
+        self.shared.buffer.lock().unwrap().lines.len()
+    }
+
+    /// Lines dropped because the buffer was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Writes every buffered line, flushes, and waits for the writer thread
+    /// to exit. Lines logged afterwards are written by the thread logging
+    /// them. Calling it again does nothing.
+    pub fn shutdown(&self) {
+        {
+            let mut buffer = self.shared.buffer.lock().unwrap();
+            if !buffer.closed {
+                self.note_dropped(&mut buffer);
+                buffer.closed = true;
+                self.shared.ready.notify_all();
+                self.shared.space.notify_all();
+            }
         }
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+
+    /// Buffers a warning about the lines dropped since the last one.
+    fn note_dropped(&self, buffer: &mut Buffer) {
+        if buffer.dropped == 0 {
+            return;
+        }
+        let count = std::mem::take(&mut buffer.dropped);
+        let notice = self.line(
+            module_path!(),
+            Level::Warn,
+            &[("dropped", &count)],
+            format_args!("Log buffer was full, lines were dropped"),
+        );
+        buffer.lines.push_back(notice);
+    }
+
+    /// Writes to `path` instead of stdout from now on, rotating it as
+    /// `rotation` says.
+    pub fn log_to_file(
+        &self,
+        path: impl Into<PathBuf>,
+        rotation: RotationConfig,
+    ) -> io::Result<()> {
+        let sink = FileSink::open(path, rotation)?;
+        *self.file.lock().unwrap() = Some(sink);
+        self.colour.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn set_format(&self, format: LogFormat) {
+        self.json
+            .store(format == LogFormat::Json, Ordering::Relaxed);
     }
 
-    fn color_for_level(&self, level: Level) -> &'static str {
-        match level {
-            Level::Info => GREEN,
-            Level::Error => RED,
-            Level::Warn => YELLOW,
-            Level::Debug => RESET,
+    pub fn format(&self) -> LogFormat {
+        if self.json.load(Ordering::Relaxed) {
+            LogFormat::Json
+        } else {
+            LogFormat::Text
         }
     }
 
-    pub fn log_fmt(&self, level: Level, args: fmt::Arguments) {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+    /// Drops messages less severe than `level` from now on, in every
+    /// module.
+    pub fn set_level(&self, level: Level) {
+        self.set_filter(LogFilter::from(level));
+    }
+
+    /// Applies `filter` to every message from now on.
+    pub fn set_filter(&self, filter: LogFilter) {
+        let mut current = self.filter.write().unwrap();
+        self.min_severity
+            .store(filter.min_severity(), Ordering::Relaxed);
+        *current = filter;
+    }
+
+    pub fn filter(&self) -> LogFilter {
+        self.filter.read().unwrap().clone()
+    }
+
+    /// Whether a message at `level` from `module` would be logged.
+    pub fn enabled(&self, module: &str, level: Level) -> bool {
+        level.severity() >= self.min_severity.load(Ordering::Relaxed)
+            && level.severity() >= self.filter.read().unwrap().level_for(module).severity()
+    }
+
+    /// Logs a message from `module`, a `module_path!()`, with `fields`
+    /// attached, if the filter lets it through.
+    pub fn log_fmt(&self, module: &str, level: Level, fields: &[Field], args: fmt::Arguments) {
+        if !self.enabled(module, level) {
+            return;
+        }
+        let formatted = self.line(module, level, fields, args);
+
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        while buffer.lines.len() >= buffer.capacity && !buffer.closed {
+            match buffer.overflow {
+                LogOverflow::Block => buffer = self.shared.space.wait(buffer).unwrap(),
+                LogOverflow::Drop => {
+                    buffer.dropped += 1;
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
+        }
+        if buffer.closed {
+            drop(buffer);
+            write_lines(&self.file, &[formatted]);
+            return;
+        }
+        self.note_dropped(&mut buffer);
+        buffer.lines.push_back(formatted);
+        self.shared.ready.notify_one();
+    }
 
-        let color = self.color_for_level(level);
-        let formatted = format!("[{}{}][{}] {} {}", color, now, level, RESET, args);
+    fn line(&self, module: &str, level: Level, fields: &[Field], args: fmt::Arguments) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        match self.format() {
+            LogFormat::Text => {
+                let colour = self.colour.load(Ordering::Relaxed);
+                text_line(now, level, colour, fields, args)
+            }
+            LogFormat::Json => json_line(now, level, module, fields, args),
+        }
+    }
+}
 
-        let (lock, cvar) = &*self.sender;
-        let mut q = lock.lock().unwrap();
-        q.push_back(formatted);
-        cvar.notify_one();
+/// Writes `lines` to the log file if there is one, or else stdout, and
+/// flushes.
+fn write_lines<'a>(file: &Mutex<Option<FileSink>>, lines: impl IntoIterator<Item = &'a String>) {
+    let mut file = file.lock().unwrap();
+    match file.as_mut() {
+        Some(sink) => {
+            for msg in lines {
+                if let Err(e) = sink.write_line(msg) {
+                    eprintln!("Failed to write the log file: {}", e);
+                    let _ = writeln!(io::stdout(), "{}", msg);
+                }
+            }
+            let _ = sink.flush();
+        }
+        None => {
+            let mut stdout = io::stdout().lock();
+            for msg in lines {
+                let _ = writeln!(stdout, "{}", msg);
+            }
+            let _ = stdout.flush();
+        }
     }
 }
 
@@ -92,40 +478,294 @@ impl Default for Logger {
     }
 }
 
+fn color_for_level(level: Level) -> &'static str {
+    match level {
+        Level::Info => GREEN,
+        Level::Error => RED,
+        Level::Warn => YELLOW,
+        Level::Debug => RESET,
+    }
+}
+
+fn text_line(
+    now: Duration,
+    level: Level,
+    colour: bool,
+    fields: &[Field],
+    args: fmt::Arguments,
+) -> String {
+    let mut line = if colour {
+        let color = color_for_level(level);
+        format!("[{}{}][{}] {} {}", color, now.as_secs(), level, RESET, args)
+    } else {
+        format!("[{}][{}] {}", now.as_secs(), level, args)
+    };
+    for (key, value) in fields {
+        line.push_str(&format!(" {}={}", key, value));
+    }
+    line
+}
+
+fn json_line(
+    now: Duration,
+    level: Level,
+    module: &str,
+    fields: &[Field],
+    args: fmt::Arguments,
+) -> String {
+    let mut line = String::from("{\"ts\":");
+    json::escape(&mut line, &rfc3339(now));
+    line.push_str(",\"level\":");
+    json::escape(&mut line, &level.to_string().to_ascii_lowercase());
+    line.push_str(",\"module\":");
+    json::escape(
+        &mut line,
+        module.strip_prefix(CRATE_PREFIX).unwrap_or(module),
+    );
+    line.push_str(",\"message\":");
+    json::escape(&mut line, &args.to_string());
+    for (key, value) in fields {
+        line.push(',');
+        json::escape(&mut line, key);
+        line.push(':');
+        json::escape(&mut line, &value.to_string());
+    }
+    line.push('}');
+    line
+}
+
+/// `since_epoch` as a UTC timestamp like `2024-03-01T12:00:00.250Z`.
+fn rfc3339(since_epoch: Duration) -> String {
+    let secs = since_epoch.as_secs();
+    let (days, time) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since 1970-01-01, after Howard Hinnant
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
 static LOGGER: OnceLock<Logger> = OnceLock::new();
 
 pub fn init_logger() {
-    LOGGER.set(Logger::new()).unwrap();
+    LOGGER.get_or_init(Logger::new);
 }
 
 pub fn global_loger() -> &'static Logger {
-    LOGGER.get().expect("Logger not initialized")
+    LOGGER.get_or_init(Logger::new)
+}
+
+/// Logs through `logger` with the calling module attached. Key/value fields
+/// may come first in braces: `log_warn!(logger, { peer = addr }, "...")`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log {
+    ($level:ident, $logger:expr, { $($key:ident = $value:expr),+ $(,)? }, $($arg:tt)*) => {
+        $logger.log_fmt(
+            module_path!(),
+            $crate::logger::Level::$level,
+            &[$((stringify!($key), &$value as &dyn ::std::fmt::Display)),+],
+            format_args!($($arg)*),
+        );
+    };
+    ($level:ident, $logger:expr, $($arg:tt)*) => {
+        $logger.log_fmt(
+            module_path!(),
+            $crate::logger::Level::$level,
+            &[],
+            format_args!($($arg)*),
+        );
+    };
 }
 
 #[macro_export]
 macro_rules! log_info {
-    ($logger:expr, $($arg:tt)*) => {
-        $logger.log_fmt($crate::logger::Level::Info, format_args!($($arg)*));
+    ($($arg:tt)*) => {
+        $crate::__log!(Info, $($arg)*)
     };
 }
 
 #[macro_export]
 macro_rules! log_warn {
-    ($logger:expr, $($arg:tt)*) => {
-        $logger.log_fmt($crate::logger::Level::Warn, format_args!($($arg)*));
+    ($($arg:tt)*) => {
+        $crate::__log!(Warn, $($arg)*)
     };
 }
 
 #[macro_export]
 macro_rules! log_error {
-    ($logger:expr, $($arg:tt)*) => {
-        $logger.log_fmt($crate::logger::Level::Error, format_args!($($arg)*));
+    ($($arg:tt)*) => {
+        $crate::__log!(Error, $($arg)*)
     };
 }
 
 #[macro_export]
 macro_rules! log_debug {
-    ($logger:expr, $($arg:tt)*) => {
-        $logger.log_fmt($crate::logger::Level::Debug, format_args!($($arg)*));
+    ($($arg:tt)*) => {
+        $crate::__log!(Debug, $($arg)*)
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_picks_the_most_specific_module() {
+        let filter: LogFilter = "shards=warn, broker=debug,shards::storage=error"
+            .parse()
+            .unwrap();
+        assert_eq!(filter.level_for("rlbg::cluster"), Level::Info);
+        assert_eq!(filter.level_for("rlbg::shards"), Level::Warn);
+        assert_eq!(filter.level_for("rlbg::shards::backup"), Level::Warn);
+        assert_eq!(filter.level_for("rlbg::shards::storage"), Level::Error);
+        assert_eq!(filter.level_for("rlbg::broker::event_loop"), Level::Debug);
+        // A prefix only matches whole path segments
+        assert_eq!(filter.level_for("rlbg::shardsx"), Level::Info);
+        assert_eq!(
+            filter.to_string(),
+            "info,shards::storage=error,broker=debug,shards=warn"
+        );
+        assert_eq!(filter.to_string().parse::<LogFilter>(), Ok(filter));
+
+        assert_eq!("warn".parse(), Ok(LogFilter::from(Level::Warn)));
+        assert!("shards=loud".parse::<LogFilter>().is_err());
+        assert!("=warn".parse::<LogFilter>().is_err());
+    }
+
+    #[test]
+    fn test_enabled_follows_the_filter() {
+        let logger = Logger::new();
+        logger.set_filter("error,broker=info".parse().unwrap());
+        assert!(logger.enabled("rlbg::broker::server", Level::Info));
+        assert!(!logger.enabled("rlbg::broker::server", Level::Debug));
+        assert!(!logger.enabled("rlbg::shards", Level::Warn));
+        assert!(logger.enabled("rlbg::shards", Level::Error));
+
+        logger.set_level(Level::Debug);
+        assert!(logger.enabled("rlbg::shards", Level::Debug));
+    }
+
+    fn test_log(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rbq_{}_{}.log", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_full_buffer_drops_and_counts_lines() {
+        let path = test_log("drop");
+        let logger = Logger::new();
+        logger.set_level(Level::Info);
+        logger
+            .log_to_file(&path, RotationConfig::default())
+            .unwrap();
+        logger.set_buffer(2, LogOverflow::Drop);
+        {
+            // The writer takes one batch at most and then waits for the file
+            let _file = logger.file.lock().unwrap();
+            for i in 0..10 {
+                log_info!(logger, "line {}", i);
+            }
+            assert!(logger.dropped() >= 6);
+        }
+        logger.shutdown();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let lines = written.lines().filter(|l| l.contains("] line ")).count();
+        assert_eq!(lines as u64 + logger.dropped(), 10);
+        assert!(written.contains(&format!(
+            "Log buffer was full, lines were dropped dropped={}",
+            logger.dropped()
+        )));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_shutdown_writes_everything_buffered() {
+        let path = test_log("shutdown");
+        let logger = Logger::new();
+        logger
+            .log_to_file(&path, RotationConfig::default())
+            .unwrap();
+        logger.set_buffer(4, LogOverflow::Block);
+        for i in 0..100 {
+            log_info!(logger, "line {}", i);
+        }
+        logger.shutdown();
+        assert_eq!(logger.buffered(), 0);
+        // Written straight away now the writer is gone
+        log_info!(logger, "after shutdown");
+        logger.shutdown();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 101);
+        assert!(lines[99].ends_with("line 99"));
+        assert!(lines[100].ends_with("after shutdown"));
+        assert_eq!(logger.dropped(), 0);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(Duration::ZERO), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            rfc3339(Duration::from_millis(951_827_696_789)),
+            "2000-02-29T12:34:56.789Z"
+        );
+        assert_eq!(
+            rfc3339(Duration::from_secs(4_102_444_799)),
+            "2099-12-31T23:59:59.000Z"
+        );
+    }
+
+    #[test]
+    fn test_json_and_plain_lines() {
+        let now = Duration::from_secs(1_700_000_000);
+        let peer = "10.0.0.1:5000";
+        let shard = 3;
+        let fields: [Field; 2] = [("peer", &peer), ("shard", &shard)];
+        let line = json_line(
+            now,
+            Level::Warn,
+            "rlbg::broker::event_loop",
+            &fields,
+            format_args!("said \"{}\"\n", "hi"),
+        );
+        let value = json::parse(&line).unwrap();
+        assert_eq!(
+            value.get("ts").unwrap().as_str(),
+            Some("2023-11-14T22:13:20.000Z")
+        );
+        assert_eq!(value.get("level").unwrap().as_str(), Some("warn"));
+        assert_eq!(
+            value.get("module").unwrap().as_str(),
+            Some("broker::event_loop")
+        );
+        assert_eq!(
+            value.get("message").unwrap().as_str(),
+            Some("said \"hi\"\n")
+        );
+        assert_eq!(value.get("peer").unwrap().as_str(), Some(peer));
+        assert_eq!(value.get("shard").unwrap().as_str(), Some("3"));
+
+        let line = text_line(now, Level::Info, false, &fields, format_args!("hello"));
+        assert_eq!(line, "[1700000000][INFO] hello peer=10.0.0.1:5000 shard=3");
+    }
+}
@@ -1,15 +1,47 @@
-mod broker;
-mod protocol;
-mod shards;
-#[macro_use]
-pub mod logger;
+use rlbg::broker::server;
+use rlbg::config;
+use rlbg::logger::global_loger;
+use rlbg::shards::backup;
+use std::process::ExitCode;
 
-use crate::broker::server;
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        print!("{}", config::usage());
+        return ExitCode::SUCCESS;
+    }
+    let settings = match config::load(args.clone(), |var| std::env::var(var).ok()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("invalid configuration:");
+            for line in e.to_string().lines() {
+                eprintln!("  {}", line);
+            }
+            eprintln!("run with --help to list the settings");
+            return ExitCode::from(2);
+        }
+    };
 
-fn main() -> std::io::Result<()> {
-    let addr = "0.0.0.0:4000"; // TCP bind address
-    let shard_count = 4;
-    let pool_size = 32;
-    let queue_size = 100;
-    server::run(addr, shard_count, pool_size, queue_size)
+    let server = settings.server;
+    // `--restore <dir>` replaces the data directory with a backup taken by
+    // the `backup` admin command before the broker starts serving.
+    if let Some(dir) = settings.restore
+        && let Err(e) = backup::restore(&dir, &server.data_dir, &server.queue)
+    {
+        eprintln!("restore from {} failed: {}", dir.display(), e);
+        return ExitCode::FAILURE;
+    }
+    // Reloads read the file and environment again; flags stay as given
+    let reload = Box::new(move || {
+        config::load(args.clone(), |var| std::env::var(var).ok()).map(|s| s.server)
+    });
+    match server::run(server, reload) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // What was logged before the failure explains it
+            global_loger().shutdown();
+            eprintln!("broker failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
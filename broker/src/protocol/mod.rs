@@ -1,3 +1,4 @@
+use crate::deflate;
 use std::io::ErrorKind;
 use std::io::{Error, Result};
 
@@ -19,10 +20,25 @@ Tag=03 Len=0004 Val= [00 00 00 05]
 Tag=07 Len=0008 Val= [00000000670E1FA0]
    07 00 08 00 00 00 00 67 0E 1F A0
 
+A push may carry Tag=F0 Len=0001 Val=[N]: reply only once N replicas
+hold the job. The broker strips it before storing the job.
+
+A failed Control reply with error code Redirect (Tag=04) carries
+Tag=05 Val="<host:port>" of the cluster member owning the job's shard.
+
+With FLAG_COMPRESSED set in Flags the payload is the raw DEFLATE stream
+(RFC 1951) of the TLV sequence, and PayloadLen its compressed size.
 */
 pub const MAGIC: &[u8; 4] = b"RBQ1";
 pub const VERSION: u8 = 1;
 
+/// Header flag marking a DEFLATE-compressed payload.
+pub const FLAG_COMPRESSED: u16 = 0x8000;
+/// Push TLV holding how many replicas must ack the job before the reply.
+pub const TAG_MIN_REPLICAS: u8 = 0xF0;
+/// Largest TLV sequence a compressed payload may expand to.
+pub const MAX_INFLATED_LEN: usize = 16 * 1024 * 1024;
+
 /// Message types
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageType {
@@ -50,6 +66,41 @@ impl MessageType {
     }
 }
 
+/// Error codes carried in tag 0x04 of failed Control responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Internal = 0x01,
+    QueueFull = 0x02,
+    /// The broker is a replication follower and takes no writes.
+    ReadOnly = 0x03,
+    /// Too few replicas acked a push in time; the job is stored on this
+    /// broker but may not survive losing it.
+    ReplicationTimeout = 0x04,
+    /// Another cluster member owns the job's shard; tag 0x05 of the reply
+    /// holds its address.
+    Redirect = 0x05,
+    /// The broker is at its connection limit and closed the connection;
+    /// tag 0x06 holds how many milliseconds to wait before retrying.
+    ServerBusy = 0x06,
+    /// An admin command lacked the admin token, or the broker has none.
+    Unauthorized = 0x07,
+}
+
+impl ErrorCode {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0x01 => Some(ErrorCode::Internal),
+            0x02 => Some(ErrorCode::QueueFull),
+            0x03 => Some(ErrorCode::ReadOnly),
+            0x04 => Some(ErrorCode::ReplicationTimeout),
+            0x05 => Some(ErrorCode::Redirect),
+            0x06 => Some(ErrorCode::ServerBusy),
+            0x07 => Some(ErrorCode::Unauthorized),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Header {
     pub magic: [u8; 4],
@@ -142,21 +193,51 @@ pub struct Message {
 }
 
 impl Message {
+    /// Encodes the message with a plain TLV payload, whatever its flags say.
     pub fn encode(&self) -> Vec<u8> {
         let mut payload = Vec::new();
         for tlv in &self.tlvs {
             tlv.encode(&mut payload);
         }
+        self.frame(self.header.flags & !FLAG_COMPRESSED, &payload)
+    }
 
-        let mut buf = Vec::new();
+    /// Encodes the message with a compressed payload, unless compressing
+    /// would not make it smaller.
+    pub fn encode_compressed(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for tlv in &self.tlvs {
+            tlv.encode(&mut payload);
+        }
+        let compressed = deflate::compress(&payload);
+        if compressed.len() >= payload.len() {
+            return self.frame(self.header.flags & !FLAG_COMPRESSED, &payload);
+        }
+        self.frame(self.header.flags | FLAG_COMPRESSED, &compressed)
+    }
+
+    fn frame(&self, flags: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(12 + payload.len());
         let header = Header {
+            flags,
             payload_len: payload.len() as u32,
             ..self.header.clone()
         };
         header.encode(&mut buf);
-        buf.extend_from_slice(&payload);
+        buf.extend_from_slice(payload);
         buf
     }
+
+    /// Whether the message arrived with a compressed payload. Decoding keeps
+    /// the flag so a reply can be sent the same way.
+    pub fn is_compressed(&self) -> bool {
+        self.header.flags & FLAG_COMPRESSED != 0
+    }
+
+    /// Size of the encoded message in bytes, without encoding it.
+    pub fn encoded_len(&self) -> usize {
+        12 + self.tlvs.iter().map(|t| 3 + t.value.len()).sum::<usize>()
+    }
     pub fn decode(buf: &[u8]) -> Result<Self> {
         if buf.len() < 12 {
             return Err(Error::new(ErrorKind::InvalidData, "buffer too sort"));
@@ -167,7 +248,11 @@ impl Message {
         }
 
         let payload = &buf[12..12 + header.payload_len as usize];
-        let tlvs = Tlv::decode(payload)?;
+        let tlvs = if header.flags & FLAG_COMPRESSED != 0 {
+            Tlv::decode(&deflate::decompress(payload, MAX_INFLATED_LEN)?)?
+        } else {
+            Tlv::decode(payload)?
+        };
         Ok(Message { header, tlvs })
     }
 }
@@ -309,4 +394,46 @@ mod tests {
         assert_eq!(decoded.tlvs[0].tag, 0x01);
         assert_eq!(String::from_utf8_lossy(&decoded.tlvs[0].value), "job1");
     }
+
+    #[test]
+    fn test_roundtrip_compressed() {
+        let msg = Message {
+            header: Header {
+                magic: *MAGIC,
+                version: VERSION,
+                msg_type: MessageType::JobPush,
+                flags: 0,
+                payload_len: 0,
+            },
+            tlvs: vec![
+                Tlv {
+                    tag: 0x01,
+                    value: b"job1".to_vec(),
+                },
+                Tlv {
+                    tag: 0x02,
+                    value: b"{\"prompt\": \"summarise\"} ".repeat(50),
+                },
+            ],
+        };
+
+        let encoded = msg.encode_compressed();
+        assert!(encoded.len() < msg.encoded_len() / 4);
+        let decoded = Message::decode(&encoded).unwrap();
+        assert!(decoded.is_compressed());
+        assert_eq!(decoded.tlvs, msg.tlvs);
+        // A plain re-encode drops the flag along with the compression
+        assert_eq!(decoded.encode(), msg.encode());
+
+        // Tiny payloads are not worth compressing
+        let small = Message {
+            tlvs: msg.tlvs[..1].to_vec(),
+            ..msg
+        };
+        assert!(
+            !Message::decode(&small.encode_compressed())
+                .unwrap()
+                .is_compressed()
+        );
+    }
 }
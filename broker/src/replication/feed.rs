@@ -0,0 +1,224 @@
+use crate::shards::{ShardStorage, SnapshotSource, StoredMessage, WalOp};
+use std::io;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// What a follower is sent, in order.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// Drop everything in `shard`; the next push gets `next_seq`.
+    Reset { shard: u32, next_seq: u64 },
+    /// One WAL record of `shard`. Records that rebuild a shard after a
+    /// reset carry lsn 0.
+    Record {
+        shard: u32,
+        lsn: u64,
+        op: WalOp,
+        seq: u64,
+        data: Arc<[u8]>,
+    },
+    /// Every shard has been reset and everything up to `lsn` is included.
+    Synced { lsn: u64 },
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    id: u64,
+    addr: String,
+    /// Name the follower gave in its hello.
+    name: String,
+    /// Shards whose live records this subscriber gets; set once the shard
+    /// has been sent in full.
+    active: Vec<bool>,
+    acked: u64,
+    tx: Sender<Event>,
+}
+
+#[derive(Debug, Default)]
+struct FeedState {
+    /// Log sequence number of the last published record, counted across
+    /// all shards of the queue.
+    lsn: u64,
+    next_id: u64,
+    subscribers: Vec<Subscriber>,
+}
+
+/// How far one follower has got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FollowerStats {
+    pub addr: String,
+    pub name: String,
+    pub acked: u64,
+    pub lag: u64,
+}
+
+/// Fans the WAL records of every shard of a queue out to followers.
+///
+/// Records are numbered and handed to subscribers under one lock, so every
+/// follower sees them in the order they were logged.
+#[derive(Debug, Default)]
+pub struct Feed {
+    state: Mutex<FeedState>,
+    /// Signalled whenever a follower acks.
+    acked: Condvar,
+}
+
+impl Feed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The lsn of the last published record.
+    pub fn head(&self) -> u64 {
+        self.state.lock().unwrap().lsn
+    }
+
+    /// Registers a subscriber that gets no live records until each shard
+    /// is activated. Returns its id.
+    pub fn subscribe(
+        &self,
+        addr: String,
+        name: String,
+        shard_count: usize,
+        tx: Sender<Event>,
+    ) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.subscribers.push(Subscriber {
+            id,
+            addr,
+            name,
+            active: vec![false; shard_count],
+            acked: 0,
+            tx,
+        });
+        id
+    }
+
+    pub fn unsubscribe(&self, id: u64) {
+        self.state
+            .lock()
+            .unwrap()
+            .subscribers
+            .retain(|s| s.id != id);
+    }
+
+    /// Starts sending `shard`'s live records to subscriber `id`. The caller
+    /// must hold the shard's state lock since it sent the shard's contents.
+    pub fn activate(&self, id: u64, shard: usize) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(subscriber) = state.subscribers.iter_mut().find(|s| s.id == id) {
+            subscriber.active[shard] = true;
+        }
+    }
+
+    /// Records that follower `id` has applied everything up to `lsn`.
+    pub fn ack(&self, id: u64, lsn: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(subscriber) = state.subscribers.iter_mut().find(|s| s.id == id) {
+            subscriber.acked = subscriber.acked.max(lsn);
+        }
+        self.acked.notify_all();
+    }
+
+    /// Waits up to `timeout` for `count` of the followers named in `voters`
+    /// to ack `lsn`. Returns whether they did. Other followers' acks do not
+    /// count, and two connections under one name count once.
+    pub fn wait_for_acks(
+        &self,
+        lsn: u64,
+        count: usize,
+        voters: &[String],
+        timeout: Duration,
+    ) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let mut holders: Vec<&str> = state
+                .subscribers
+                .iter()
+                .filter(|s| s.acked >= lsn && voters.contains(&s.name))
+                .map(|s| s.name.as_str())
+                .collect();
+            holders.sort_unstable();
+            holders.dedup();
+            if holders.len() >= count {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.acked.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    pub fn followers(&self) -> Vec<FollowerStats> {
+        let state = self.state.lock().unwrap();
+        state
+            .subscribers
+            .iter()
+            .map(|s| FollowerStats {
+                addr: s.addr.clone(),
+                name: s.name.clone(),
+                acked: s.acked,
+                lag: state.lsn.saturating_sub(s.acked),
+            })
+            .collect()
+    }
+
+    fn publish(&self, shard: usize, op: WalOp, seq: u64, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.lsn += 1;
+        if state.subscribers.is_empty() {
+            return;
+        }
+        let event = Event::Record {
+            shard: shard as u32,
+            lsn: state.lsn,
+            op,
+            seq,
+            data: data.into(),
+        };
+        // A subscriber whose connection is gone has dropped its receiver
+        state.subscribers.retain(|s| {
+            !s.active.get(shard).copied().unwrap_or(false) || s.tx.send(event.clone()).is_ok()
+        });
+    }
+}
+
+/// Wraps a shard's backend so every record it logs is also published.
+#[derive(Debug)]
+pub struct FeedStorage {
+    shard: usize,
+    inner: Box<dyn ShardStorage>,
+    feed: Arc<Feed>,
+}
+
+impl FeedStorage {
+    pub fn new(shard: usize, inner: Box<dyn ShardStorage>, feed: Arc<Feed>) -> Self {
+        Self { shard, inner, feed }
+    }
+}
+
+impl ShardStorage for FeedStorage {
+    fn recover(&mut self, f: &mut dyn FnMut(StoredMessage) -> io::Result<()>) -> io::Result<u64> {
+        self.inner.recover(f)
+    }
+
+    fn append(&mut self, op: WalOp, seq: u64, data: &[u8]) -> io::Result<()> {
+        self.inner.append(op, seq, data)?;
+        self.feed.publish(self.shard, op, seq, data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn checkpoint(&mut self, next_seq: u64, source: &mut dyn SnapshotSource) -> io::Result<()> {
+        self.inner.checkpoint(next_seq, source)
+    }
+}
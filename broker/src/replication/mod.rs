@@ -0,0 +1,656 @@
+//! Leader/follower replication of shard logs.
+//!
+//! A leader accepts followers on its replication address. Each follower is
+//! first sent the full contents of every shard, then tails the WAL records
+//! of all shards as they are logged, applying them to its own data
+//! directory and acking how far it has synced to disk. Followers refuse
+//! client writes until promoted with the `promote` admin command, after
+//! which they stop following and serve followers of their own.
+//!
+//! Both ends must be given the same secret, which a follower sends in its
+//! hello; the leader drops peers without it. A push that asks for replica
+//! acks only counts acks from the follower names the leader was given.
+//!
+//! Records are numbered with a queue-wide lsn; lag is the number of
+//! records a follower is behind its leader.
+
+pub mod feed;
+pub mod wire;
+
+use crate::crypto::same_secret;
+use crate::logger::global_loger;
+use crate::shards::ShardedQueue;
+use crate::{log_info, log_warn};
+pub use feed::{Event, Feed, FeedStorage, FollowerStats};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use wire::{Frame, Hello};
+
+/// How long an idle stream waits before the leader sends a heartbeat.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a follower waits before reconnecting to its leader.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How long a push that asks for replica acks waits for them by default.
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct ReplicationConfig {
+    /// Address followers connect to. `None` serves no followers.
+    pub listen: Option<String>,
+    /// Replication address of the leader to follow on startup.
+    pub follow: Option<String>,
+    /// How long a push waits for the replica acks it asked for.
+    pub ack_timeout: Duration,
+    /// Secret both ends send in their hello. Without one this broker
+    /// neither serves nor follows.
+    pub secret: Option<String>,
+    /// Name this broker gives its leader when following.
+    pub name: String,
+    /// Names of the followers whose acks count toward a push's replicas.
+    pub followers: Vec<String>,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            follow: None,
+            ack_timeout: DEFAULT_ACK_TIMEOUT,
+            secret: None,
+            name: String::new(),
+            followers: Vec::new(),
+        }
+    }
+}
+
+/// A follower's view of its leader.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaderStats {
+    pub addr: String,
+    pub connected: bool,
+    /// Lsn of the last record applied.
+    pub applied: u64,
+    /// Highest lsn the leader has reported.
+    pub leader_lsn: u64,
+    pub lag: u64,
+    pub last_contact_ms: u64,
+}
+
+#[derive(Debug)]
+struct Link {
+    leader: String,
+    /// Our hello to the leader.
+    hello_name: String,
+    secret: String,
+    stopped: AtomicBool,
+    stream: Mutex<Option<TcpStream>>,
+    progress: Mutex<Progress>,
+}
+
+#[derive(Debug)]
+struct Progress {
+    connected: bool,
+    applied: u64,
+    leader_lsn: u64,
+    last_contact: Instant,
+}
+
+/// This process's replication role.
+#[derive(Debug)]
+pub struct Node {
+    /// Set while following a leader.
+    link: Mutex<Option<Arc<Link>>>,
+    config: ReplicationConfig,
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self::with_config(ReplicationConfig::default())
+    }
+}
+
+impl Node {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: ReplicationConfig) -> Self {
+        Self {
+            link: Mutex::new(None),
+            config,
+        }
+    }
+
+    /// Waits until `count` of the configured followers hold everything
+    /// logged so far, which includes a push the caller just made. Fails
+    /// with `TimedOut` once the ack timeout passes; the push itself stays
+    /// stored either way.
+    pub fn wait_for_replicas(&self, feed: &Feed, count: usize) -> io::Result<()> {
+        let timeout = self.config.ack_timeout;
+        if feed.wait_for_acks(feed.head(), count, &self.config.followers, timeout) {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("not acked by {} replicas within {:?}", count, timeout),
+        ))
+    }
+
+    fn secret(&self) -> io::Result<&str> {
+        self.config.secret.as_deref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "no replication secret is configured",
+            )
+        })
+    }
+
+    pub fn is_follower(&self) -> bool {
+        self.link.lock().unwrap().is_some()
+    }
+
+    /// Starts following the leader whose replication address is `leader`,
+    /// rebuilding `queue` from it.
+    pub fn follow(&self, leader: &str, queue: Arc<ShardedQueue>) -> io::Result<()> {
+        let secret = self.secret()?.to_string();
+        let mut current = self.link.lock().unwrap();
+        if current.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "already following a leader",
+            ));
+        }
+        let link = Arc::new(Link {
+            leader: leader.to_string(),
+            hello_name: self.config.name.clone(),
+            secret,
+            stopped: AtomicBool::new(false),
+            stream: Mutex::new(None),
+            progress: Mutex::new(Progress {
+                connected: false,
+                applied: 0,
+                leader_lsn: 0,
+                last_contact: Instant::now(),
+            }),
+        });
+        *current = Some(link.clone());
+        thread::spawn(move || follow_leader(link, queue));
+        Ok(())
+    }
+
+    /// Stops following and makes this process a leader. Records already
+    /// applied stay; anything still in flight from the old leader is lost.
+    pub fn promote(&self) -> io::Result<()> {
+        let link = self
+            .link
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "already the leader"))?;
+        link.stopped.store(true, Ordering::Release);
+        if let Some(stream) = link.stream.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        log_info!(
+            global_loger(),
+            "Promoted to leader, stopped following {}",
+            link.leader
+        );
+        Ok(())
+    }
+
+    /// Where this process stands against its leader; `None` on a leader.
+    pub fn leader_stats(&self) -> Option<LeaderStats> {
+        let link = self.link.lock().unwrap().clone()?;
+        let progress = link.progress.lock().unwrap();
+        Some(LeaderStats {
+            addr: link.leader.clone(),
+            connected: progress.connected,
+            applied: progress.applied,
+            leader_lsn: progress.leader_lsn,
+            lag: progress.leader_lsn.saturating_sub(progress.applied),
+            last_contact_ms: progress.last_contact.elapsed().as_millis() as u64,
+        })
+    }
+
+    /// Accepts followers on `listener` in a background thread.
+    pub fn serve(self: &Arc<Self>, listener: TcpListener, queue: Arc<ShardedQueue>) {
+        let node = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let node = node.clone();
+                        let queue = queue.clone();
+                        thread::spawn(move || {
+                            let addr = peer_addr(&stream);
+                            if let Err(e) = serve_follower(stream, &addr, &node, &queue) {
+                                log_warn!(global_loger(), "Follower {} dropped: {}", addr, e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        log_warn!(global_loger(), "Replication accept failed: {}", e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn peer_addr(stream: &TcpStream) -> String {
+    stream
+        .peer_addr()
+        .map_or_else(|_| "unknown".to_string(), |a| a.to_string())
+}
+
+/// Streams the queue to one follower until either side goes away.
+fn serve_follower(
+    stream: TcpStream,
+    addr: &str,
+    node: &Node,
+    queue: &ShardedQueue,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    let hello = wire::read_hello(&mut reader)?;
+    // A peer without the secret is not even sent our hello
+    let secret = node.secret()?;
+    if !same_secret(secret.as_bytes(), hello.secret.as_bytes()) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "wrong replication secret",
+        ));
+    }
+    let ours = Hello {
+        shard_count: queue.shard_count(),
+        name: node.config.name.clone(),
+        secret: secret.to_string(),
+    };
+    wire::write_hello(&mut writer, &ours)?;
+    writer.flush()?;
+    if node.is_follower() {
+        return Err(io::Error::other("this broker is a follower itself"));
+    }
+    if hello.shard_count != queue.shard_count() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "follower has {} shards, leader has {}",
+                hello.shard_count,
+                queue.shard_count()
+            ),
+        ));
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let feed = queue.feed().clone();
+    let id = queue.subscribe(addr.to_string(), hello.name.clone(), tx)?;
+    log_info!(
+        global_loger(),
+        "Follower {} at {} subscribed",
+        hello.name,
+        addr
+    );
+    if !node.config.followers.contains(&hello.name) {
+        log_warn!(
+            global_loger(),
+            "Follower {} is not in replication_followers, its acks do not count",
+            hello.name
+        );
+    }
+
+    let acks = {
+        let feed = feed.clone();
+        thread::spawn(move || {
+            while let Ok(Frame::Ack { lsn }) = wire::read_frame(&mut reader) {
+                feed.ack(id, lsn);
+            }
+            // Closing our end ends the writer below too
+            let _ = stream.shutdown(Shutdown::Both);
+        })
+    };
+
+    let result = loop {
+        let first = match rx.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(event) => Frame::Event(event),
+            Err(RecvTimeoutError::Timeout) => Frame::Heartbeat { lsn: feed.head() },
+            Err(RecvTimeoutError::Disconnected) => break Ok(()),
+        };
+        // Batch whatever else is already queued into one write
+        let sent = wire::write_frame(&mut writer, &first)
+            .and_then(|_| {
+                rx.try_iter()
+                    .try_for_each(|event| wire::write_frame(&mut writer, &Frame::Event(event)))
+            })
+            .and_then(|_| writer.flush());
+        if let Err(e) = sent {
+            break Err(e);
+        }
+    };
+    feed.unsubscribe(id);
+    let _ = writer.get_ref().shutdown(Shutdown::Both);
+    let _ = acks.join();
+    result
+}
+
+/// Follows `link.leader` until promoted, reconnecting after failures.
+fn follow_leader(link: Arc<Link>, queue: Arc<ShardedQueue>) {
+    while !link.stopped.load(Ordering::Acquire) {
+        let result = TcpStream::connect(&link.leader).and_then(|stream| {
+            *link.stream.lock().unwrap() = Some(stream.try_clone()?);
+            // Promotion may have raced the connect
+            if link.stopped.load(Ordering::Acquire) {
+                return Ok(());
+            }
+            apply_stream(&link, stream, &queue)
+        });
+        link.progress.lock().unwrap().connected = false;
+        if link.stopped.load(Ordering::Acquire) {
+            break;
+        }
+        if let Err(e) = result {
+            log_warn!(
+                global_loger(),
+                "Replication from {} failed: {}",
+                link.leader,
+                e
+            );
+        }
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+/// Applies one connection's worth of the leader's stream.
+fn apply_stream(link: &Link, stream: TcpStream, queue: &ShardedQueue) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let ours = Hello {
+        shard_count: queue.shard_count(),
+        name: link.hello_name.clone(),
+        secret: link.secret.clone(),
+    };
+    wire::write_hello(&mut writer, &ours)?;
+    let hello = wire::read_hello(&mut reader).map_err(|e| match e.kind() {
+        // The leader hangs up on a hello without its secret
+        io::ErrorKind::UnexpectedEof => io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "leader closed the stream, check replication_secret",
+        ),
+        _ => e,
+    })?;
+    if !same_secret(link.secret.as_bytes(), hello.secret.as_bytes()) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "leader sent the wrong replication secret",
+        ));
+    }
+    let shard_count = hello.shard_count;
+    if shard_count != queue.shard_count() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "leader has {} shards, this broker has {}",
+                shard_count,
+                queue.shard_count()
+            ),
+        ));
+    }
+    log_info!(global_loger(), "Following {}", link.leader);
+    link.progress.lock().unwrap().connected = true;
+
+    // Lsns only mean "everything before is here" once every shard is synced
+    let mut synced = false;
+    // Applied but not yet on disk, so not acked yet
+    let mut unacked = None;
+    loop {
+        let frame = wire::read_frame(&mut reader)?;
+        let shard_index = |shard: u32| {
+            let shard = shard as usize;
+            if shard < shard_count {
+                Ok(shard)
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("record for unknown shard {}", shard),
+                ))
+            }
+        };
+        let applied = match frame {
+            Frame::Event(Event::Reset { shard, next_seq }) => {
+                queue.reset(shard_index(shard)?, next_seq)?;
+                None
+            }
+            Frame::Event(Event::Record {
+                shard,
+                lsn,
+                op,
+                seq,
+                data,
+            }) => {
+                queue.apply(shard_index(shard)?, op, seq, &data)?;
+                (synced && lsn > 0).then_some(lsn)
+            }
+            Frame::Event(Event::Synced { lsn }) => {
+                synced = true;
+                Some(lsn)
+            }
+            Frame::Heartbeat { lsn } => {
+                let mut progress = link.progress.lock().unwrap();
+                progress.leader_lsn = progress.leader_lsn.max(lsn);
+                progress.last_contact = Instant::now();
+                None
+            }
+            Frame::Ack { .. } => None,
+        };
+        if let Some(lsn) = applied {
+            let mut progress = link.progress.lock().unwrap();
+            progress.applied = lsn;
+            progress.leader_lsn = progress.leader_lsn.max(lsn);
+            progress.last_contact = Instant::now();
+            unacked = Some(lsn);
+        }
+        // An ack promises the records survive a crash here, so the logs are
+        // synced first. One sync covers every frame already received.
+        if reader.buffer().is_empty()
+            && let Some(lsn) = unacked.take()
+        {
+            queue.flush()?;
+            wire::write_frame(&mut writer, &Frame::Ack { lsn })?;
+        }
+    }
+}
+
+static NODE: OnceLock<Arc<Node>> = OnceLock::new();
+
+pub fn init_global_node(config: &ReplicationConfig) -> io::Result<()> {
+    NODE.set(Arc::new(Node::with_config(config.clone())))
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Global node already initialized",
+            )
+        })
+}
+
+/// The process-wide node; a leader unless told to follow.
+pub fn global_node() -> Arc<Node> {
+    NODE.get_or_init(|| Arc::new(Node::new())).clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Header, MAGIC, Message, MessageType, Tlv, VERSION};
+    use crate::shards::compute_shard_key;
+    use std::io::Read;
+
+    const SECRET: &str = "s3cret";
+
+    /// A node with the test secret that counts acks from `replica`.
+    fn node(name: &str, ack_timeout: Duration) -> Node {
+        Node::with_config(ReplicationConfig {
+            ack_timeout,
+            secret: Some(SECRET.to_string()),
+            name: name.to_string(),
+            followers: vec!["replica".to_string()],
+            ..ReplicationConfig::default()
+        })
+    }
+
+    fn job(id: usize) -> Message {
+        Message {
+            header: Header {
+                magic: *MAGIC,
+                version: VERSION,
+                msg_type: MessageType::JobPush,
+                flags: 0,
+                payload_len: 0,
+            },
+            tlvs: vec![Tlv {
+                tag: 0x01,
+                value: format!("job{}", id).into_bytes(),
+            }],
+        }
+    }
+
+    fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_follower_tails_leader_and_promotes() {
+        let data_dir = std::env::temp_dir().join(format!("rbq_repl_{}", std::process::id()));
+        let leader = Arc::new(ShardedQueue::in_memory(2));
+        let push = |id: usize| {
+            let msg = job(id);
+            leader.push(compute_shard_key(&msg, 2), msg).unwrap();
+        };
+        // Some messages before the follower exists, some after
+        (0..4).for_each(push);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        Arc::new(node("leader", DEFAULT_ACK_TIMEOUT)).serve(listener, leader.clone());
+
+        let replica = Arc::new(ShardedQueue::new(2, &data_dir).unwrap());
+        let node = node("replica", DEFAULT_ACK_TIMEOUT);
+        node.follow(&addr, replica.clone()).unwrap();
+        assert!(node.is_follower());
+
+        (4..10).for_each(push);
+        let popped = leader.pop(compute_shard_key(&job(0), 2)).unwrap().unwrap();
+        assert_eq!(popped.tlvs[0].value, b"job0");
+
+        let head = leader.feed().head();
+        wait_until("the follower to catch up", || {
+            node.leader_stats().unwrap().applied == head
+        });
+        let stats = node.leader_stats().unwrap();
+        assert!(stats.connected);
+        assert_eq!(stats.lag, 0);
+        wait_until("the leader to see the ack", || {
+            let followers = leader.feed().followers();
+            followers.len() == 1 && followers[0].acked == head && followers[0].lag == 0
+        });
+
+        node.promote().unwrap();
+        assert!(!node.is_follower());
+        assert!(node.leader_stats().is_none());
+        assert!(node.promote().is_err());
+        for shard in 0..2 {
+            let expected = leader.pop_batch(shard, usize::MAX).unwrap();
+            let replicated = replica.pop_batch(shard, usize::MAX).unwrap();
+            assert!(!expected.is_empty());
+            assert_eq!(
+                replicated.iter().map(|m| &m.tlvs).collect::<Vec<_>>(),
+                expected.iter().map(|m| &m.tlvs).collect::<Vec<_>>()
+            );
+        }
+
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    #[test]
+    fn test_push_waits_for_replica_acks() {
+        let leader = Arc::new(ShardedQueue::in_memory(1));
+        let waiter = node("leader", Duration::from_millis(200));
+        // No follower yet: nobody can ack
+        leader.push(0, job(0)).unwrap();
+        let err = waiter.wait_for_replicas(leader.feed(), 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        Arc::new(node("leader", DEFAULT_ACK_TIMEOUT)).serve(listener, leader.clone());
+        let follower = node("replica", DEFAULT_ACK_TIMEOUT);
+        follower
+            .follow(&addr, Arc::new(ShardedQueue::in_memory(1)))
+            .unwrap();
+
+        let waiter = node("leader", Duration::from_secs(10));
+        for id in 1..20 {
+            leader.push(0, job(id)).unwrap();
+            waiter.wait_for_replicas(leader.feed(), 1).unwrap();
+            assert_eq!(leader.feed().followers()[0].acked, leader.feed().head());
+        }
+        let waiter = node("leader", Duration::from_millis(100));
+        assert!(waiter.wait_for_replicas(leader.feed(), 2).is_err());
+        follower.promote().unwrap();
+    }
+
+    #[test]
+    fn test_refuses_peers_without_the_secret_and_ignores_unlisted_acks() {
+        let leader = Arc::new(ShardedQueue::in_memory(1));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        Arc::new(node("leader", DEFAULT_ACK_TIMEOUT)).serve(listener, leader.clone());
+
+        // A wrong secret is hung up on without a hello back
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        let guess = Hello {
+            shard_count: 1,
+            name: "replica".to_string(),
+            secret: "guess".to_string(),
+        };
+        wire::write_hello(&mut stream, &guess).unwrap();
+        let mut answer = Vec::new();
+        stream.read_to_end(&mut answer).unwrap();
+        assert!(answer.is_empty());
+        assert!(leader.feed().followers().is_empty());
+
+        // Nor does a broker without a secret follow anyone
+        let err = Node::new()
+            .follow(&addr, Arc::new(ShardedQueue::in_memory(1)))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        // The right secret under a name the leader does not count gets the
+        // stream, but its acks do not satisfy a push
+        let stranger = node("stranger", DEFAULT_ACK_TIMEOUT);
+        stranger
+            .follow(&addr, Arc::new(ShardedQueue::in_memory(1)))
+            .unwrap();
+        leader.push(0, job(0)).unwrap();
+        let head = leader.feed().head();
+        wait_until("the stranger to ack", || {
+            let followers = leader.feed().followers();
+            followers.len() == 1 && followers[0].name == "stranger" && followers[0].acked == head
+        });
+        let waiter = node("leader", Duration::from_millis(100));
+        let err = waiter.wait_for_replicas(leader.feed(), 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        stranger.promote().unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+//! Framing of the replication stream.
+//!
+//! Both ends open with a hello:
+//! `"RBQR" | version u8 | shard_count u32 | NameLen u8 | Name | SecretLen u8 | Secret`.
+//! The follower sends its hello first and the leader only answers one that
+//! carries the shared secret. After that every frame is `kind u8 | len u32 | body`, integers big
+//! endian like the client protocol:
+//!
+//! 1 Reset      shard u32 | next_seq u64
+//! 2 Record     shard u32 | lsn u64 | op u8 | seq u64 | data
+//! 3 Synced     lsn u64
+//! 4 Heartbeat  lsn u64   leader's head, sent when the stream is idle
+//! 5 Ack        lsn u64   follower to leader: applied up to lsn
+
+use crate::replication::feed::Event;
+use crate::shards::WalOp;
+use std::io::{self, Read, Write};
+
+pub const MAGIC: &[u8; 4] = b"RBQR";
+pub const VERSION: u8 = 2;
+
+/// Largest frame body accepted, so a corrupt length cannot exhaust memory.
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+const RESET: u8 = 1;
+const RECORD: u8 = 2;
+const SYNCED: u8 = 3;
+const HEARTBEAT: u8 = 4;
+const ACK: u8 = 5;
+
+#[derive(Debug, Clone)]
+pub enum Frame {
+    Event(Event),
+    Heartbeat { lsn: u64 },
+    Ack { lsn: u64 },
+}
+
+fn invalid(details: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, details)
+}
+
+/// What each end says about itself when a stream opens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub shard_count: usize,
+    /// Who the sender is. A leader counts acks by follower name.
+    pub name: String,
+    pub secret: String,
+}
+
+/// Longest name or secret a hello can carry.
+pub const MAX_HELLO_FIELD: usize = u8::MAX as usize;
+
+pub fn write_hello(out: &mut impl Write, hello: &Hello) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(11 + hello.name.len() + hello.secret.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(VERSION);
+    bytes.extend_from_slice(&(hello.shard_count as u32).to_be_bytes());
+    for field in [&hello.name, &hello.secret] {
+        if field.len() > MAX_HELLO_FIELD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("hello field of {} bytes", field.len()),
+            ));
+        }
+        bytes.push(field.len() as u8);
+        bytes.extend_from_slice(field.as_bytes());
+    }
+    out.write_all(&bytes)
+}
+
+/// Reads the peer's hello.
+pub fn read_hello(input: &mut impl Read) -> io::Result<Hello> {
+    let mut fixed = [0u8; 9];
+    input.read_exact(&mut fixed)?;
+    if &fixed[..4] != MAGIC {
+        return Err(invalid("not a replication peer".to_string()));
+    }
+    if fixed[4] != VERSION {
+        return Err(invalid(format!(
+            "unsupported replication version {}",
+            fixed[4]
+        )));
+    }
+    let mut field = || -> io::Result<String> {
+        let mut len = [0u8; 1];
+        input.read_exact(&mut len)?;
+        let mut bytes = vec![0u8; len[0] as usize];
+        input.read_exact(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|_| invalid("hello field is not UTF-8".to_string()))
+    };
+    Ok(Hello {
+        shard_count: u32::from_be_bytes(fixed[5..9].try_into().unwrap()) as usize,
+        name: field()?,
+        secret: field()?,
+    })
+}
+
+pub fn write_frame(out: &mut impl Write, frame: &Frame) -> io::Result<()> {
+    let mut body = Vec::new();
+    let kind = match frame {
+        Frame::Event(Event::Reset { shard, next_seq }) => {
+            body.extend_from_slice(&shard.to_be_bytes());
+            body.extend_from_slice(&next_seq.to_be_bytes());
+            RESET
+        }
+        Frame::Event(Event::Record {
+            shard,
+            lsn,
+            op,
+            seq,
+            data,
+        }) => {
+            body.extend_from_slice(&shard.to_be_bytes());
+            body.extend_from_slice(&lsn.to_be_bytes());
+            body.push(*op as u8);
+            body.extend_from_slice(&seq.to_be_bytes());
+            body.extend_from_slice(data);
+            RECORD
+        }
+        Frame::Event(Event::Synced { lsn }) => {
+            body.extend_from_slice(&lsn.to_be_bytes());
+            SYNCED
+        }
+        Frame::Heartbeat { lsn } => {
+            body.extend_from_slice(&lsn.to_be_bytes());
+            HEARTBEAT
+        }
+        Frame::Ack { lsn } => {
+            body.extend_from_slice(&lsn.to_be_bytes());
+            ACK
+        }
+    };
+    let mut header = [0u8; 5];
+    header[0] = kind;
+    header[1..].copy_from_slice(&(body.len() as u32).to_be_bytes());
+    out.write_all(&header)?;
+    out.write_all(&body)
+}
+
+pub fn read_frame(input: &mut impl Read) -> io::Result<Frame> {
+    let mut header = [0u8; 5];
+    input.read_exact(&mut header)?;
+    let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid(format!("replication frame of {} bytes", len)));
+    }
+    let mut body = vec![0u8; len];
+    input.read_exact(&mut body)?;
+
+    let short = || invalid(format!("short replication frame of kind {}", header[0]));
+    let u32_at = |at: usize| -> io::Result<u32> {
+        let bytes = body.get(at..at + 4).ok_or_else(short)?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    let u64_at = |at: usize| -> io::Result<u64> {
+        let bytes = body.get(at..at + 8).ok_or_else(short)?;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    let frame = match header[0] {
+        RESET => Frame::Event(Event::Reset {
+            shard: u32_at(0)?,
+            next_seq: u64_at(4)?,
+        }),
+        RECORD => {
+            let op = body
+                .get(12)
+                .copied()
+                .and_then(WalOp::from_byte)
+                .ok_or_else(|| invalid("bad op in replicated record".to_string()))?;
+            Frame::Event(Event::Record {
+                shard: u32_at(0)?,
+                lsn: u64_at(4)?,
+                op,
+                seq: u64_at(13)?,
+                data: body[21..].into(),
+            })
+        }
+        SYNCED => Frame::Event(Event::Synced { lsn: u64_at(0)? }),
+        HEARTBEAT => Frame::Heartbeat { lsn: u64_at(0)? },
+        ACK => Frame::Ack { lsn: u64_at(0)? },
+        kind => return Err(invalid(format!("unknown replication frame kind {}", kind))),
+    };
+    Ok(frame)
+}
@@ -0,0 +1,188 @@
+//! Point-in-time backups of a running queue.
+//!
+//! A backup directory holds a `shard_N.snap` per shard, in the data
+//! directory's snapshot format, and a `backup.meta` naming the shard count
+//! and where each shard's WAL stood. The meta file is written last, so a
+//! directory without one is an unfinished backup and is never restored.
+
+use crate::log_info;
+use crate::logger::global_loger;
+use crate::shards::record::Codec;
+use crate::shards::reshard::{self, STAGING_DIR};
+use crate::shards::storage::SnapshotWriter;
+use crate::shards::{QueueConfig, SnapshotSource, WalStorage, snapshot_path};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+const MANIFEST: &str = "backup.meta";
+
+/// Where one shard stood when it was backed up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardPosition {
+    /// The seq the shard's next push would have got.
+    pub next_seq: u64,
+    pub messages: usize,
+}
+
+fn invalid(details: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, details)
+}
+
+/// Fails if `dir` already holds a finished backup, so one is never
+/// overwritten piecemeal.
+pub(super) fn prepare(dir: &Path) -> io::Result<()> {
+    if dir.as_os_str().is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "backup needs a directory",
+        ));
+    }
+    fs::create_dir_all(dir)?;
+    if dir.join(MANIFEST).exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already holds a backup", dir.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Writes every message of `source` as the snapshot of shard `id` in `dir`.
+pub(super) fn write_shard(
+    dir: &Path,
+    id: usize,
+    codec: Codec,
+    compress: bool,
+    next_seq: u64,
+    source: &mut dyn SnapshotSource,
+) -> io::Result<ShardPosition> {
+    let path = snapshot_path(dir, id);
+    let temp = path.with_extension("snap.tmp");
+    let mut writer = SnapshotWriter::create(&temp, codec, compress)?;
+    let mut messages = 0;
+    source.for_each(&mut |stored| {
+        messages += 1;
+        writer.write(stored.seq, &stored.msg)
+    })?;
+    writer.finish(next_seq)?;
+    fs::rename(temp, path)?;
+    Ok(ShardPosition { next_seq, messages })
+}
+
+/// Marks the backup in `dir` complete.
+pub(super) fn write_manifest(dir: &Path, positions: &[ShardPosition]) -> io::Result<()> {
+    let temp = dir.join(format!("{}.tmp", MANIFEST));
+    let mut file = File::create(&temp)?;
+    writeln!(file, "shard_count={}", positions.len())?;
+    for (id, position) in positions.iter().enumerate() {
+        writeln!(
+            file,
+            "shard_{}=next_seq:{},messages:{}",
+            id, position.next_seq, position.messages
+        )?;
+    }
+    file.sync_all()?;
+    fs::rename(temp, dir.join(MANIFEST))
+}
+
+/// Reads the shard positions recorded in a finished backup.
+pub fn read_manifest(dir: &Path) -> io::Result<Vec<ShardPosition>> {
+    let text = match fs::read_to_string(dir.join(MANIFEST)) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(invalid(format!(
+                "{} is not a finished backup (no {})",
+                dir.display(),
+                MANIFEST
+            )));
+        }
+        Err(e) => return Err(e),
+    };
+    let mut shard_count = None;
+    let mut positions = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(count) = line.strip_prefix("shard_count=") {
+            shard_count = Some(
+                count
+                    .parse()
+                    .map_err(|_| invalid("bad shard_count".into()))?,
+            );
+        } else if let Some(rest) = line.strip_prefix("shard_") {
+            positions.push(
+                parse_position(rest)
+                    .ok_or_else(|| invalid(format!("bad {} line: {}", MANIFEST, line)))?,
+            );
+        }
+    }
+    if shard_count != Some(positions.len()) {
+        return Err(invalid(format!(
+            "{} lists {} shards, expected {:?}",
+            MANIFEST,
+            positions.len(),
+            shard_count
+        )));
+    }
+    Ok(positions)
+}
+
+/// Parses `N=next_seq:S,messages:M`; shards must be listed in id order.
+fn parse_position(text: &str) -> Option<ShardPosition> {
+    let (_, fields) = text.split_once('=')?;
+    let (next_seq, messages) = fields.split_once(',')?;
+    Some(ShardPosition {
+        next_seq: next_seq.strip_prefix("next_seq:")?.parse().ok()?,
+        messages: messages.strip_prefix("messages:")?.parse().ok()?,
+    })
+}
+
+/// Replaces the contents of `data_dir` with the backup in `backup_dir`.
+///
+/// Every snapshot is read back and checked against the manifest before the
+/// data directory is touched. The swap then goes through the reshard
+/// staging directory, so a crash half way is finished on the next start.
+/// When the queue opens with a different shard count it is resharded as
+/// usual. Returns how many messages were restored.
+pub fn restore(backup_dir: &Path, data_dir: &Path, config: &QueueConfig) -> io::Result<usize> {
+    let keys = config.encryption.as_ref();
+    let positions = read_manifest(backup_dir)?;
+    for (id, expected) in positions.iter().enumerate() {
+        let mut messages = 0;
+        let next_seq = WalStorage::read_snapshot(&snapshot_path(backup_dir, id), id, keys, |_| {
+            messages += 1;
+            Ok(())
+        })?;
+        let found = ShardPosition { next_seq, messages };
+        if found != *expected {
+            return Err(invalid(format!(
+                "shard {} of the backup holds {:?}, manifest says {:?}",
+                id, found, expected
+            )));
+        }
+    }
+
+    fs::create_dir_all(data_dir)?;
+    let staging = data_dir.join(STAGING_DIR);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+    for id in 0..positions.len() {
+        let staged = snapshot_path(&staging, id);
+        fs::copy(snapshot_path(backup_dir, id), &staged)?;
+        File::open(&staged)?.sync_all()?;
+    }
+    reshard::write_meta(&staging, positions.len())?;
+    reshard::commit(data_dir, &staging)?;
+
+    let restored = positions.iter().map(|p| p.messages).sum();
+    log_info!(
+        global_loger(),
+        "Restored {} messages in {} shards from {}",
+        restored,
+        positions.len(),
+        backup_dir.display()
+    );
+    Ok(restored)
+}
@@ -1,24 +1,42 @@
-use crate::log_info;
+pub mod backup;
+pub mod record;
+mod reshard;
+mod spill;
+pub mod storage;
+mod upgrade;
+
+use crate::crypto::Keyring;
+use crate::export;
 use crate::logger::global_loger;
 use crate::protocol::Message;
+use crate::replication::{Event, Feed, FeedStorage};
+use crate::{log_error, log_warn};
+use backup::ShardPosition;
+use record::{Codec, encode_message};
+pub use reshard::{existing_shard_ids, read_shard_count};
+use spill::SpillLog;
 use std::collections::VecDeque;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufRead, Write};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Condvar, Mutex, OnceLock};
-
-const WAL_BATCH_SIZE: usize = 100;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+pub use storage::{MemoryStorage, ShardStorage, SnapshotSource, StorageKind, WalStorage};
+
+/// Queue operations between looking for shards due a background checkpoint
+/// unless configured otherwise.
 const CHECKPOUNT_THRESHOLD: usize = 100;
 
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
-enum WalOp {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalOp {
     Push = 1,
     Pop = 2,
 }
 
 impl WalOp {
-    fn from_byte(b: u8) -> Option<Self> {
+    pub fn from_byte(b: u8) -> Option<Self> {
         match b {
             1 => Some(WalOp::Push),
             2 => Some(WalOp::Pop),
@@ -35,346 +53,830 @@ impl std::fmt::Display for WalOp {
     }
 }
 
+/// A queued message together with the per-shard sequence id assigned on push.
+#[derive(Debug, Clone)]
+pub struct StoredMessage {
+    pub seq: u64,
+    pub msg: Message,
+}
+
+/// A decoded WAL record. `data` is empty for records that carry no payload.
+#[derive(Debug, Clone)]
+pub struct WalRecord {
+    pub op: WalOp,
+    pub seq: u64,
+    pub data: Vec<u8>,
+    /// Byte offset of the record in its WAL file.
+    pub offset: u64,
+}
+
+pub fn wal_path(data_dir: &Path, id: usize) -> PathBuf {
+    data_dir.join(format!("shard_{}.wal", id))
+}
+
+pub fn snapshot_path(data_dir: &Path, id: usize) -> PathBuf {
+    data_dir.join(format!("shard_{}.snap", id))
+}
+
+pub fn spill_path(data_dir: &Path, id: usize) -> PathBuf {
+    data_dir.join(format!("shard_{}.spill", id))
+}
+
+/// Decoded snapshot contents.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub next_seq: u64,
+    pub messages: VecDeque<StoredMessage>,
+}
+
+/// Per-shard depth split between the in-memory window and the spill file.
+#[derive(Debug, Clone, Default)]
+pub struct ShardStats {
+    pub id: usize,
+    pub in_memory: usize,
+    pub in_memory_bytes: usize,
+    pub on_disk: usize,
+    pub on_disk_bytes: usize,
+}
+
+type StorageLock = Mutex<Box<dyn ShardStorage>>;
+
 #[derive(Debug)]
-struct WalWriter {
-    file: File,
-    path: PathBuf,
-    entries_since_flish: usize,
+struct ShardState {
+    queue: VecDeque<StoredMessage>,
+    queue_bytes: usize,
+    /// Only shards with a data directory can spill.
+    spill: Option<SpillLog>,
+    config: QueueConfig,
+    next_seq: u64,
+    /// Encoded bytes pushed since the last checkpoint.
+    logged_bytes: usize,
 }
 
-impl WalWriter {
-    fn new(path: &Path) -> io::Result<Self> {
-        let file = OpenOptions::new().create(true).append(true).open(path)?;
-        Ok(Self {
-            file,
-            path: path.to_path_buf(),
-            entries_since_flish: 0,
-        })
+impl ShardState {
+    fn spilled(&self) -> usize {
+        self.spill.as_ref().map_or(0, SpillLog::len)
     }
 
-    fn append(&mut self, op: WalOp, data: Option<&[u8]>) -> io::Result<()> {
-        self.file.write_all(&[op as u8])?;
+    fn spilled_bytes(&self) -> usize {
+        self.spill.as_ref().map_or(0, SpillLog::bytes)
+    }
 
-        if let Some(d) = data {
-            let mut len = (d.len() as u32).to_le_bytes();
-            self.file.write_all(&len)?;
-            self.file.write_all(d)?;
-            log_info!(
-                global_loger(),
-                "Write msg to the WalWriter with WalOp {} and len {}",
-                op,
-                d.len()
-            );
-        } else {
-            self.file.write_all(&0u32.to_le_bytes())?;
-            log_info!(
-                global_loger(),
-                "Write msg to the WalWriter with WalOp {}",
-                op
-            );
-        }
+    fn depth(&self) -> usize {
+        self.queue.len() + self.spilled()
+    }
 
-        self.entries_since_flish += 1;
+    fn bytes(&self) -> usize {
+        self.queue_bytes + self.spilled_bytes()
+    }
 
-        if self.entries_since_flish > WAL_BATCH_SIZE {
-            self.flush()?;
-        }
+    fn has_room(&self, count: usize, bytes: usize) -> bool {
+        self.config
+            .max_depth
+            .is_none_or(|max| self.depth() + count <= max)
+            && self
+                .config
+                .max_bytes
+                .is_none_or(|max| self.bytes() + bytes <= max)
+    }
 
-        Ok(())
+    /// Whether `count` messages of `bytes` could fit even in an empty shard.
+    fn could_ever_fit(&self, count: usize, bytes: usize) -> bool {
+        self.config.max_depth.is_none_or(|max| count <= max)
+            && self.config.max_bytes.is_none_or(|max| bytes <= max)
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.file.sync_data()?;
-        self.file.seek(SeekFrom::Start(0))?;
-        self.entries_since_flish = 0;
+    /// Appends to the hot window, or to the spill file once the window is
+    /// over budget. Anything already spilled forces later messages to spill
+    /// too so the queue stays in seq order.
+    fn enqueue(&mut self, stored: StoredMessage, encoded: &[u8]) -> io::Result<()> {
+        let len = stored.msg.encoded_len();
+        let over_budget = self
+            .config
+            .memory_budget
+            .is_some_and(|budget| self.queue_bytes + len > budget);
+        if let Some(spill) = self.spill.as_mut()
+            && (!spill.is_empty() || (over_budget && !self.queue.is_empty()))
+        {
+            return spill.append(&stored, encoded);
+        }
+        self.queue_bytes += len;
+        self.queue.push_back(stored);
         Ok(())
     }
 
-    fn truncate(&mut self) -> io::Result<()> {
-        self.file.set_len(0)?;
-        self.file.seek(SeekFrom::Start(0))?;
-        self.entries_since_flish = 0;
+    /// Pages in before removing anything, so a spill file that cannot be
+    /// read fails the pop instead of losing the message or hiding the rest
+    /// of the backlog.
+    fn remove_front(&mut self, storage: &StorageLock) -> io::Result<Option<Message>> {
+        self.refill()?;
+        let Some(stored) = self.queue.pop_front() else {
+            return Ok(None);
+        };
+        self.queue_bytes -= stored.msg.encoded_len();
+        {
+            let mut storage = storage.lock().unwrap();
+            let _ = storage.append(WalOp::Pop, stored.seq, &[]);
+        }
+        Ok(Some(stored.msg))
+    }
+
+    /// Pages spilled messages back in once the hot window has drained to
+    /// half of its budget.
+    fn refill(&mut self) -> io::Result<()> {
+        let (Some(budget), Some(spill)) = (self.config.memory_budget, self.spill.as_mut()) else {
+            return Ok(());
+        };
+        if self.queue_bytes > budget / 2 {
+            return Ok(());
+        }
+        while !spill.is_empty() && (self.queue.is_empty() || self.queue_bytes < budget) {
+            let Some(stored) = spill.pop_front()? else {
+                break;
+            };
+            self.queue_bytes += stored.msg.encoded_len();
+            self.queue.push_back(stored);
+        }
         Ok(())
     }
 }
 
+impl SnapshotSource for ShardState {
+    fn for_each(&mut self, f: &mut dyn FnMut(&StoredMessage) -> io::Result<()>) -> io::Result<()> {
+        for stored in self.queue.iter() {
+            f(stored)?;
+        }
+        match self.spill.as_mut() {
+            Some(spill) => spill.for_each(|stored| f(&stored)),
+            None => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Shard {
-    queue: Mutex<VecDeque<Message>>,
+    state: Mutex<ShardState>,
     codvar: Condvar,
-    wal: Mutex<WalWriter>,
+    space: Condvar,
+    storage: StorageLock,
     id: usize,
 }
 
 impl Shard {
     pub fn new(id: usize, data_dir: &Path) -> io::Result<Self> {
-        let wal_path = data_dir.join(format!("shard_{}.wal", id));
-        let snapshot_path = data_dir.join(format!("shard_{}.snap", id));
-        let mut queue = VecDeque::new();
-        if snapshot_path.exists() {
-            queue = Self::load_snapshoot(&snapshot_path)?;
-        }
-        let mut wal = WalWriter::new(&wal_path)?;
-        if wal_path.exists() {
-            let wal_messages = Self::replay_wal(&wal_path)?;
-            queue.extend(wal_messages);
+        Self::with_config(id, data_dir, QueueConfig::default())
+    }
+
+    /// Opens a shard with its share of a queue's settings; every limit in
+    /// `config` applies to this shard alone.
+    pub fn with_config(id: usize, data_dir: &Path, config: QueueConfig) -> io::Result<Self> {
+        match config.storage {
+            StorageKind::Wal => {
+                let codec = Codec::for_writing(config.encryption.as_ref(), id);
+                let spill = SpillLog::create(&spill_path(data_dir, id), codec)?;
+                let storage = Box::new(WalStorage::open(data_dir, id, &config)?);
+                Self::open(id, config, storage, Some(spill))
+            }
+            StorageKind::Memory => Self::with_storage(id, config, Box::new(MemoryStorage)),
         }
+    }
+
+    /// Opens a shard on a custom backend. Such shards never spill, so the
+    /// memory budget of `config` is ignored.
+    pub fn with_storage(
+        id: usize,
+        config: QueueConfig,
+        storage: Box<dyn ShardStorage>,
+    ) -> io::Result<Self> {
+        Self::open(id, config, storage, None)
+    }
+
+    fn open(
+        id: usize,
+        config: QueueConfig,
+        mut storage: Box<dyn ShardStorage>,
+        spill: Option<SpillLog>,
+    ) -> io::Result<Self> {
+        let mut state = ShardState {
+            queue: VecDeque::new(),
+            queue_bytes: 0,
+            spill,
+            config,
+            next_seq: 0,
+            logged_bytes: 0,
+        };
+        state.next_seq = storage.recover(&mut |stored| {
+            let encoded = encode_message(&stored.msg, state.config.compress);
+            state.enqueue(stored, &encoded)
+        })?;
 
         Ok(Self {
-            queue: Mutex::new(queue),
+            state: Mutex::new(state),
             codvar: Condvar::new(),
-            wal: Mutex::new(wal),
+            space: Condvar::new(),
+            storage: Mutex::new(storage),
             id,
         })
     }
 
-    pub fn push(&self, msg: Message) {
-        let encoded = msg.encode();
-        {
-            let mut wal = self.wal.lock().unwrap();
-            let _ = wal.append(WalOp::Push, Some(&encoded));
+    /// Waits for, or makes, room for `count` more messages totalling `bytes`
+    /// according to the shard's overflow policy.
+    fn reserve<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, ShardState>,
+        count: usize,
+        bytes: usize,
+    ) -> io::Result<MutexGuard<'a, ShardState>> {
+        if !state.could_ever_fit(count, bytes) {
+            return Err(queue_full());
+        }
+        match state.config.overflow {
+            OverflowPolicy::Reject => {
+                if !state.has_room(count, bytes) {
+                    return Err(queue_full());
+                }
+            }
+            OverflowPolicy::Block { timeout } => {
+                let deadline = Instant::now() + timeout;
+                while !state.has_room(count, bytes) {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(queue_full());
+                    }
+                    state = self.space.wait_timeout(state, deadline - now).unwrap().0;
+                }
+            }
+            OverflowPolicy::DropOldest => {
+                let mut dropped = 0;
+                while !state.has_room(count, bytes) && state.remove_front(&self.storage)?.is_some()
+                {
+                    dropped += 1;
+                }
+                if dropped > 0 {
+                    log_warn!(
+                        global_loger(),
+                        "Shard {} full, dropped {} oldest messages",
+                        self.id,
+                        dropped
+                    );
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    pub fn push(&self, msg: Message) -> io::Result<()> {
+        let state = self.state.lock().unwrap();
+        let mut state = self.reserve(state, 1, msg.encoded_len())?;
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        let encoded = encode_message(&msg, state.config.compress);
+        let mut storage = self.storage.lock().unwrap();
+        storage.append(WalOp::Push, seq, &encoded)?;
+        state.logged_bytes += msg.encoded_len();
+        if let Err(e) = state.enqueue(StoredMessage { seq, msg }, &encoded) {
+            let _ = storage.append(WalOp::Pop, seq, &[]);
+            return Err(e);
         }
-        let mut q = self.queue.lock().unwrap();
-        q.push_back(msg);
         self.codvar.notify_one();
+        Ok(())
     }
 
-    pub fn push_batch(&self, msgs: Vec<Message>) {
+    pub fn push_batch(&self, msgs: Vec<Message>) -> io::Result<()> {
         if msgs.is_empty() {
-            return;
+            return Ok(());
         }
-        {
-            let mut wal = self.wal.lock().unwrap();
-            for msg in &msgs {
-                let encoded = msg.encode();
-                let _ = wal.append(WalOp::Push, Some(&encoded));
+        let bytes = msgs.iter().map(Message::encoded_len).sum();
+        let state = self.state.lock().unwrap();
+        let mut state = self.reserve(state, msgs.len(), bytes)?;
+        let first_seq = state.next_seq;
+        state.next_seq += msgs.len() as u64;
+        let encoded: Vec<Vec<u8>> = msgs
+            .iter()
+            .map(|msg| encode_message(msg, state.config.compress))
+            .collect();
+        let mut storage = self.storage.lock().unwrap();
+        for (seq, data) in (first_seq..).zip(&encoded) {
+            storage.append(WalOp::Push, seq, data)?;
+        }
+        storage.flush()?;
+        state.logged_bytes += bytes;
+        for ((seq, msg), data) in (first_seq..).zip(msgs).zip(&encoded) {
+            if let Err(e) = state.enqueue(StoredMessage { seq, msg }, data) {
+                for unstored in seq..first_seq + encoded.len() as u64 {
+                    let _ = storage.append(WalOp::Pop, unstored, &[]);
+                }
+                return Err(e);
             }
-            let _ = wal.flush();
         }
-        let mut q = self.queue.lock().unwrap();
-        q.extend(msgs);
         self.codvar.notify_all();
+        Ok(())
     }
 
-    pub fn pop(&self) -> Option<Message> {
-        let mut q = self.queue.lock().unwrap();
-        let msg = q.pop_front();
+    pub fn pop(&self) -> io::Result<Option<Message>> {
+        let mut state = self.state.lock().unwrap();
+        let msg = state.remove_front(&self.storage)?;
         if msg.is_some() {
-            let mut wal = self.wal.lock().unwrap();
-            let _ = wal.append(WalOp::Pop, None);
+            self.space.notify_one();
         }
-        msg
+        Ok(msg)
     }
-    pub fn pop_batch(&self, max: usize) -> Vec<Message> {
-        let mut q = self.queue.lock().unwrap();
+
+    /// Fails only if not even the first message could be read; a later
+    /// failure ends the batch early and fails the next pop instead.
+    pub fn pop_batch(&self, max: usize) -> io::Result<Vec<Message>> {
+        let mut state = self.state.lock().unwrap();
         let mut batch = Vec::new();
         for _ in 0..max {
-            if let Some(msg) = q.pop_front() {
-                batch.push(msg);
-            } else {
-                break;
+            match state.remove_front(&self.storage) {
+                Ok(Some(msg)) => batch.push(msg),
+                Ok(None) => break,
+                Err(e) if batch.is_empty() => return Err(e),
+                Err(_) => break,
             }
         }
-        batch
+        if !batch.is_empty() {
+            self.space.notify_all();
+        }
+        Ok(batch)
     }
 
-    pub fn try_pop(&self) -> Option<Message> {
-        self.queue.lock().unwrap().pop_front()
+    pub fn try_pop(&self) -> io::Result<Option<Message>> {
+        self.pop()
     }
 
-    pub fn load_snapshoot(path: &Path) -> io::Result<VecDeque<Message>> {
-        let mut file = File::open(path)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-
-        let mut queue = VecDeque::new();
-        let mut offset = 0;
+    /// Calls `f` for every queued message in seq order without removing
+    /// anything. Pushes and pops on this shard wait until it returns.
+    pub fn for_each(&self, mut f: impl FnMut(&StoredMessage) -> io::Result<()>) -> io::Result<()> {
+        self.state.lock().unwrap().for_each(&mut f)
+    }
 
-        while offset < buffer.len() {
-            if offset + 4 > buffer.len() {
-                break;
-            }
+    pub fn stats(&self) -> ShardStats {
+        let state = self.state.lock().unwrap();
+        ShardStats {
+            id: self.id,
+            in_memory: state.queue.len(),
+            in_memory_bytes: state.queue_bytes,
+            on_disk: state.spilled(),
+            on_disk_bytes: state.spilled_bytes(),
+        }
+    }
 
-            let msg_len = u32::from_le_bytes([
-                buffer[offset],
-                buffer[offset + 1],
-                buffer[offset + 2],
-                buffer[offset + 3],
-            ]) as usize;
+    /// Changes the shard's capacity. Messages already over a lowered limit
+    /// stay; pushes that now fit stop waiting.
+    pub fn set_limits(&self, max_depth: Option<usize>, max_bytes: Option<usize>) {
+        let mut state = self.state.lock().unwrap();
+        state.config.max_depth = max_depth;
+        state.config.max_bytes = max_bytes;
+        self.space.notify_all();
+    }
 
-            offset += 4;
+    /// Handles pushes to a full shard by `overflow` from now on.
+    pub fn set_overflow(&self, overflow: OverflowPolicy) {
+        self.state.lock().unwrap().config.overflow = overflow;
+        self.space.notify_all();
+    }
 
-            if offset + msg_len > buffer.len() {
-                break;
-            }
+    /// Forces records logged since the last sync to disk.
+    pub fn flush(&self) -> io::Result<()> {
+        self.storage.lock().unwrap().flush()
+    }
 
-            if let Ok(msg) = Message::decode(&buffer[offset..offset + msg_len]) {
-                queue.push_back(msg);
-            }
+    pub fn checkpoint(&self) -> io::Result<()> {
+        // Hold the state lock for the whole checkpoint so no record can land
+        // in the log between the snapshot and its compaction.
+        let mut state = self.state.lock().unwrap();
+        Self::checkpoint_locked(&mut state, &self.storage)
+    }
 
-            offset += msg_len
+    /// Checkpoints only once the shard has logged at least as many bytes
+    /// as it holds, spill file included, so rewriting a long backlog costs
+    /// no more than the writes that led up to it. Returns whether it did.
+    pub fn checkpoint_if_due(&self) -> io::Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        if state.logged_bytes == 0 || state.logged_bytes < state.bytes() {
+            return Ok(false);
         }
+        Self::checkpoint_locked(&mut state, &self.storage)?;
+        Ok(true)
+    }
 
-        Ok(queue)
+    fn checkpoint_locked(state: &mut ShardState, storage: &StorageLock) -> io::Result<()> {
+        let next_seq = state.next_seq;
+        storage.lock().unwrap().checkpoint(next_seq, state)?;
+        state.logged_bytes = 0;
+        Ok(())
     }
 
-    pub fn replay_wal(path: &Path) -> io::Result<VecDeque<Message>> {
-        let mut file = File::open(path)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
+    /// Wraps the shard's backend so every record it logs is also published
+    /// to `feed`.
+    fn publishing_to(self, feed: &Arc<Feed>) -> Self {
+        let inner = self.storage.into_inner().unwrap();
+        Self {
+            storage: Mutex::new(Box::new(FeedStorage::new(self.id, inner, feed.clone()))),
+            ..self
+        }
+    }
 
-        let mut queue = VecDeque::new();
-        let mut offset = 0;
+    /// Sends everything queued to a replication subscriber as a reset and
+    /// a push per message, then switches it to live records. Holding the
+    /// state lock throughout means no record is missed or sent twice.
+    fn sync_subscriber(&self, feed: &Feed, id: u64, tx: &Sender<Event>) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let shard = self.id as u32;
+        let gone = || io::Error::new(io::ErrorKind::BrokenPipe, "subscriber went away");
+        tx.send(Event::Reset {
+            shard,
+            next_seq: state.next_seq,
+        })
+        .map_err(|_| gone())?;
+        state.for_each(&mut |stored| {
+            tx.send(Event::Record {
+                shard,
+                lsn: 0,
+                op: WalOp::Push,
+                seq: stored.seq,
+                data: stored.msg.encode().into(),
+            })
+            .map_err(|_| gone())
+        })?;
+        feed.activate(id, self.id);
+        Ok(())
+    }
 
-        while offset < buffer.len() {
-            if offset + 1 > buffer.len() {
-                break;
-            }
-            let op = WalOp::from_byte(buffer[offset]);
-            offset += 1;
+    /// Drops every queued message and continues numbering at `next_seq`,
+    /// as a follower does before rebuilding the shard from its leader.
+    pub fn reset(&self, next_seq: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.queue.clear();
+        state.queue_bytes = 0;
+        if let Some(spill) = state.spill.as_mut() {
+            spill.clear()?;
+        }
+        state.next_seq = next_seq;
+        Self::checkpoint_locked(&mut state, &self.storage)?;
+        self.space.notify_all();
+        Ok(())
+    }
 
-            if offset + 4 > buffer.len() {
-                break;
+    /// Applies a record replicated from a leader's shard, keeping its seq.
+    pub fn apply(&self, op: WalOp, seq: u64, data: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match op {
+            WalOp::Push => {
+                let msg = Message::decode(data)?;
+                let encoded = encode_message(&msg, state.config.compress);
+                state.next_seq = state.next_seq.max(seq + 1);
+                let mut storage = self.storage.lock().unwrap();
+                storage.append(WalOp::Push, seq, &encoded)?;
+                state.logged_bytes += msg.encoded_len();
+                state.enqueue(StoredMessage { seq, msg }, &encoded)?;
+                self.codvar.notify_one();
             }
-
-            let msg_len = u32::from_le_bytes([
-                buffer[offset],
-                buffer[offset + 1],
-                buffer[offset + 2],
-                buffer[offset + 3],
-            ]) as usize;
-            offset += 4;
-
-            match op {
-                Some(WalOp::Push) => {
-                    if offset + msg_len > buffer.len() {
-                        break;
-                    }
-                    if let Ok(msg) = Message::decode(&buffer[offset..offset + msg_len]) {
-                        queue.push_back(msg);
+            WalOp::Pop => {
+                if state.queue.front().is_some_and(|front| front.seq == seq) {
+                    state.remove_front(&self.storage)?;
+                } else {
+                    // Only a push the leader failed to store is popped out
+                    // of order, and it may already have been spilled.
+                    if let Some(at) = state.queue.iter().position(|s| s.seq == seq) {
+                        let stored = state.queue.remove(at).unwrap();
+                        state.queue_bytes -= stored.msg.encoded_len();
+                    } else if let Some(spill) = state.spill.as_mut() {
+                        spill.remove(seq)?;
                     }
-                    offset += msg_len;
-                }
-                Some(WalOp::Pop) => {
-                    let _ = queue.pop_front();
+                    self.storage.lock().unwrap().append(WalOp::Pop, seq, &[])?;
                 }
-                None => break,
+                self.space.notify_one();
             }
         }
-        Ok(queue)
+        Ok(())
     }
-    pub fn checkpoint(&self, data_dir: &Path) -> io::Result<()> {
-        let snapshot_path = data_dir.join(format!("shard_{}.snapshot", self.id));
-        let temp_path = data_dir.join(format!("shard_{}.snap.tmp", self.id));
-
-        let queue = self.queue.lock().unwrap();
 
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&temp_path)?;
+    /// Writes the shard as it stands into `dir` in snapshot format, leaving
+    /// its own files alone. Pushes and pops wait until it is written.
+    pub fn backup(&self, dir: &Path) -> io::Result<ShardPosition> {
+        let mut state = self.state.lock().unwrap();
+        let codec = Codec::for_writing(state.config.encryption.as_ref(), self.id);
+        let compress = state.config.compress;
+        let next_seq = state.next_seq;
+        backup::write_shard(dir, self.id, codec, compress, next_seq, &mut *state)
+    }
+}
 
-        for msg in queue.iter() {
-            let encoded = msg.encode();
-            let len = (encoded.len() as u32).to_le_bytes();
-            file.write_all(&len)?;
-            file.write_all(&encoded)?;
-        }
+/// What a push does when its shard is at capacity.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Fail the push with a queue-full error.
+    #[default]
+    Reject,
+    /// Wait up to `timeout` for consumers to free space, then reject.
+    Block { timeout: Duration },
+    /// Discard the oldest messages until the new ones fit.
+    DropOldest,
+}
 
-        file.sync_all()?;
-        drop(file);
+/// Per-queue settings.
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    /// Bytes of encoded messages the whole queue may keep in memory, split
+    /// evenly across shards. Anything beyond that waits in the shard's spill
+    /// file. `None` keeps every message in memory.
+    pub memory_budget: Option<usize>,
+    /// Maximum number of messages a single shard may hold.
+    pub max_depth: Option<usize>,
+    /// Maximum encoded bytes a single shard may hold.
+    pub max_bytes: Option<usize>,
+    pub overflow: OverflowPolicy,
+    pub storage: StorageKind,
+    /// Seals every persisted record when set. Ignored by the memory backend.
+    pub encryption: Option<Arc<Keyring>>,
+    /// Compresses persisted records. Existing records stay readable either
+    /// way.
+    pub compress: bool,
+    /// WAL records appended between syncs to disk.
+    pub wal_batch_size: usize,
+    /// Pushes across the queue between looking for shards due a background
+    /// checkpoint.
+    pub checkpoint_threshold: usize,
+}
 
-        std::fs::rename(&temp_path, &snapshot_path)?;
-        let mut wal = self.wal.lock().unwrap();
-        wal.truncate()?;
-        Ok(())
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            memory_budget: None,
+            max_depth: None,
+            max_bytes: None,
+            overflow: OverflowPolicy::default(),
+            storage: StorageKind::default(),
+            encryption: None,
+            compress: false,
+            wal_batch_size: storage::WAL_BATCH_SIZE,
+            checkpoint_threshold: CHECKPOUNT_THRESHOLD,
+        }
     }
 }
 
+fn queue_full() -> io::Error {
+    io::Error::new(io::ErrorKind::StorageFull, "queue full")
+}
+
 #[derive(Debug)]
 pub struct ShardedQueue {
     shards: Vec<Arc<Shard>>,
     shard_count: usize,
-    data_dir: PathBuf,
     checkpoint_counter: Mutex<usize>,
+    checkpoint_threshold: usize,
+    /// Set while a background checkpoint runs, so at most one does.
+    checkpointing: Arc<AtomicBool>,
+    feed: Arc<Feed>,
 }
 
 impl ShardedQueue {
     pub fn new(shard_count: usize, data_dir: impl AsRef<Path>) -> io::Result<Self> {
+        Self::with_config(shard_count, data_dir, QueueConfig::default())
+    }
+
+    pub fn with_config(
+        shard_count: usize,
+        data_dir: impl AsRef<Path>,
+        config: QueueConfig,
+    ) -> io::Result<Self> {
         let data_dir = data_dir.as_ref();
-        std::fs::create_dir_all(data_dir)?;
+        if config.storage == StorageKind::Wal {
+            std::fs::create_dir_all(data_dir)?;
+            upgrade::upgrade(data_dir, &config)?;
+            reshard::ensure_layout(data_dir, shard_count, &config)?;
+        }
 
+        let checkpoint_threshold = config.checkpoint_threshold;
+        let shard_config = QueueConfig {
+            memory_budget: config.memory_budget.map(|b| b / shard_count.max(1)),
+            ..config
+        };
+        let feed = Arc::new(Feed::new());
         let mut shards = Vec::new();
         for i in 0..shard_count {
-            shards.push(Arc::new(Shard::new(i, data_dir)?));
+            let shard = Shard::with_config(i, data_dir, shard_config.clone())?;
+            shards.push(Arc::new(shard.publishing_to(&feed)));
         }
         Ok(Self {
             shards,
             shard_count,
-            data_dir: data_dir.to_path_buf(),
             checkpoint_counter: Mutex::new(0),
+            checkpoint_threshold,
+            checkpointing: Arc::new(AtomicBool::new(false)),
+            feed,
         })
     }
 
+    /// A queue that persists nothing, for tests and ephemeral workloads.
+    pub fn in_memory(shard_count: usize) -> Self {
+        let config = QueueConfig {
+            storage: StorageKind::Memory,
+            ..Default::default()
+        };
+        Self::with_config(shard_count, "", config).expect("in-memory shards cannot fail to open")
+    }
+
     fn pick_shard(&self, key: usize) -> &Arc<Shard> {
         &self.shards[key % self.shard_count]
     }
 
-    pub fn push(&self, key: usize, msg: Message) {
-        self.pick_shard(key).push(msg);
+    pub fn push(&self, key: usize, msg: Message) -> io::Result<()> {
+        self.pick_shard(key).push(msg)?;
         self.maybe_checkpoint();
+        Ok(())
     }
 
-    pub fn push_batch(&self, key: usize, msgs: Vec<Message>) {
-        self.pick_shard(key).push_batch(msgs);
+    pub fn push_batch(&self, key: usize, msgs: Vec<Message>) -> io::Result<()> {
+        self.pick_shard(key).push_batch(msgs)?;
         self.maybe_checkpoint();
+        Ok(())
     }
 
-    pub fn pop(&self, key: usize) -> Option<Message> {
+    pub fn pop(&self, key: usize) -> io::Result<Option<Message>> {
         self.pick_shard(key).pop()
     }
 
-    pub fn pop_batch(&self, key: usize, max: usize) -> Vec<Message> {
+    pub fn pop_batch(&self, key: usize, max: usize) -> io::Result<Vec<Message>> {
         self.pick_shard(key).pop_batch(max)
     }
 
-    pub fn try_pop(&self, key: usize) -> Option<Message> {
+    pub fn try_pop(&self, key: usize) -> io::Result<Option<Message>> {
         self.pick_shard(key).try_pop()
     }
 
+    pub fn stats(&self) -> Vec<ShardStats> {
+        self.shards.iter().map(|shard| shard.stats()).collect()
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shard_count
+    }
+
+    /// Writes every pending message, shard by shard, in the export format.
+    /// Returns how many were written.
+    pub fn export(&self, out: &mut impl Write) -> io::Result<usize> {
+        let mut count = 0;
+        for shard in &self.shards {
+            shard.for_each(|stored| {
+                count += 1;
+                export::write_message(out, stored.seq, &stored.msg)
+            })?;
+        }
+        Ok(count)
+    }
+
+    /// Pushes every message of an export, routing each by its key for this
+    /// queue's shard count. Stops at the first message that cannot be
+    /// stored; messages before it stay imported.
+    pub fn import(&self, input: impl BufRead) -> io::Result<usize> {
+        let mut imported = 0;
+        export::read_messages(input, |msg| {
+            let key = compute_shard_key(&msg, self.shard_count);
+            self.push(key, msg).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("{} (after importing {} messages)", e, imported),
+                )
+            })?;
+            imported += 1;
+            Ok(())
+        })
+    }
+
+    /// Backs every shard up into `dir` while the queue keeps serving. Each
+    /// shard is captured at its own instant, which keeps per-key order since
+    /// a key never spans shards. Returns how many messages were written.
+    pub fn backup(&self, dir: impl AsRef<Path>) -> io::Result<usize> {
+        let dir = dir.as_ref();
+        backup::prepare(dir)?;
+        let mut positions = Vec::with_capacity(self.shard_count);
+        for shard in &self.shards {
+            positions.push(shard.backup(dir)?);
+        }
+        backup::write_manifest(dir, &positions)?;
+        Ok(positions.iter().map(|p| p.messages).sum())
+    }
+
+    /// Every record logged by any shard, for replication.
+    pub fn feed(&self) -> &Arc<Feed> {
+        &self.feed
+    }
+
+    /// Registers the follower `name` at `addr`: `tx` receives every
+    /// shard's current contents, then a `Synced` event, then live records.
+    /// Returns the subscriber id for acks.
+    pub fn subscribe(&self, addr: String, name: String, tx: Sender<Event>) -> io::Result<u64> {
+        let id = self
+            .feed
+            .subscribe(addr, name, self.shard_count, tx.clone());
+        for shard in &self.shards {
+            if let Err(e) = shard.sync_subscriber(&self.feed, id, &tx) {
+                self.feed.unsubscribe(id);
+                return Err(e);
+            }
+        }
+        let lsn = self.feed.head();
+        if tx.send(Event::Synced { lsn }).is_err() {
+            self.feed.unsubscribe(id);
+        }
+        Ok(id)
+    }
+
+    /// Drops everything in `shard` ahead of a replicated rebuild.
+    pub fn reset(&self, shard: usize, next_seq: u64) -> io::Result<()> {
+        self.shards[shard].reset(next_seq)
+    }
+
+    /// Applies a record replicated from the same shard of a leader.
+    pub fn apply(&self, shard: usize, op: WalOp, seq: u64, data: &[u8]) -> io::Result<()> {
+        self.shards[shard].apply(op, seq, data)?;
+        self.maybe_checkpoint();
+        Ok(())
+    }
+
     fn maybe_checkpoint(&self) {
         let mut counter = self.checkpoint_counter.lock().unwrap();
         *counter += 1;
 
-        if *counter > CHECKPOUNT_THRESHOLD {
+        // While one checkpoint runs the counter keeps growing, so the next
+        // push after it finishes starts another.
+        if *counter > self.checkpoint_threshold && !self.checkpointing.swap(true, Ordering::AcqRel)
+        {
             *counter = 0;
             drop(counter);
 
             let shards = self.shards.clone();
-            let data_dir = self.data_dir.clone();
+            let checkpointing = self.checkpointing.clone();
 
             std::thread::spawn(move || {
                 for shard in shards {
-                    let _ = shard.checkpoint(&data_dir);
+                    if let Err(e) = shard.checkpoint_if_due() {
+                        log_error!(
+                            global_loger(),
+                            "Shard {} checkpoint failed: {}",
+                            shard.id,
+                            e
+                        );
+                    }
                 }
+                checkpointing.store(false, Ordering::Release);
             });
         }
     }
 
+    pub fn flush(&self) -> io::Result<()> {
+        for shard in &self.shards {
+            shard.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Changes the per-shard capacity of every shard.
+    pub fn set_limits(&self, max_depth: Option<usize>, max_bytes: Option<usize>) {
+        for shard in &self.shards {
+            shard.set_limits(max_depth, max_bytes);
+        }
+    }
+
+    pub fn set_overflow(&self, overflow: OverflowPolicy) {
+        for shard in &self.shards {
+            shard.set_overflow(overflow);
+        }
+    }
+
     pub fn force_checkpoint(&self) -> io::Result<()> {
         for shard in &self.shards {
-            shard.checkpoint(&self.data_dir)?;
+            shard.checkpoint()?;
         }
         Ok(())
     }
 }
 
+/// Hashes the first TLV (the job id) so every message of a key lands on the
+/// same shard.
+pub fn compute_shard_key(msg: &Message, shard_count: usize) -> usize {
+    if let Some(tlv) = msg.tlvs.first() {
+        let mut hash = 0usize;
+        for b in &tlv.value {
+            hash = hash.wrapping_mul(31).wrapping_add(*b as usize);
+        }
+        return hash % shard_count;
+    }
+    0
+}
+
 // Global queues
 static GLOBAL_QUEUE: OnceLock<Arc<ShardedQueue>> = OnceLock::new();
 
-pub fn init_global_queue(shard_count: usize, data_dir: impl AsRef<Path>) -> io::Result<()> {
-    let queue = Arc::new(ShardedQueue::new(shard_count, data_dir)?);
+pub fn init_global_queue(
+    shard_count: usize,
+    data_dir: impl AsRef<Path>,
+    config: QueueConfig,
+) -> io::Result<()> {
+    let queue = Arc::new(ShardedQueue::with_config(shard_count, data_dir, config)?);
     GLOBAL_QUEUE.set(queue).map_err(|_| {
         io::Error::new(
             io::ErrorKind::AlreadyExists,
@@ -412,6 +914,13 @@ mod tests {
         path
     }
 
+    fn budget_config(memory_budget: usize) -> QueueConfig {
+        QueueConfig {
+            memory_budget: Some(memory_budget),
+            ..Default::default()
+        }
+    }
+
     fn cleanup_test_dir(path: &Path) {
         let _ = std::fs::remove_dir_all(path);
     }
@@ -440,21 +949,19 @@ mod tests {
 
     #[test]
     fn test_shard_push_pop() {
-        let temp_dir = make_test_dir();
-        let queue = ShardedQueue::new(2, &temp_dir).unwrap();
+        let queue = ShardedQueue::in_memory(2);
         let message = make_mesages(42);
-        queue.push(0, message.clone());
-        let pop = queue.pop(0).unwrap();
+        queue.push(0, message.clone()).unwrap();
+        let pop = queue.pop(0).unwrap().unwrap();
         assert_eq!(pop.tlvs[0].value, message.tlvs[0].value);
     }
 
     #[test]
     fn test_shard_push_pop_batch() {
-        let temp_dir = make_test_dir();
-        let queue = ShardedQueue::new(2, &temp_dir).unwrap();
+        let queue = ShardedQueue::in_memory(2);
         let batch: Vec<Message> = (0..5).map(make_mesages).collect();
-        queue.push_batch(1, batch.clone());
-        let popped = queue.pop_batch(1, 5);
+        queue.push_batch(1, batch.clone()).unwrap();
+        let popped = queue.pop_batch(1, 5).unwrap();
         assert_eq!(popped.len(), 5);
         for (b, p) in batch.iter().zip(popped.iter()) {
             assert_eq!(b.tlvs[0].value, p.tlvs[0].value);
@@ -463,8 +970,7 @@ mod tests {
 
     #[test]
     fn test_multi_threaded_producers_consumers() {
-        let temp_dir = make_test_dir();
-        let queue = Arc::new(ShardedQueue::new(4, &temp_dir).unwrap());
+        let queue = Arc::new(ShardedQueue::in_memory(4));
         let mut handles: Vec<_> = vec![];
 
         // Producers first
@@ -472,7 +978,7 @@ mod tests {
             let q = Arc::clone(&queue);
             handles.push(thread::spawn(move || {
                 let batch: Vec<Message> = (0..10).map(|j| make_mesages(i * 10 + j)).collect();
-                q.push_batch(i, batch)
+                q.push_batch(i, batch).unwrap()
             }))
         }
 
@@ -486,7 +992,7 @@ mod tests {
         for i in 0..4 {
             let q = Arc::clone(&queue);
             handles.push(thread::spawn(move || {
-                let batch = q.pop_batch(i, 10);
+                let batch = q.pop_batch(i, 10).unwrap();
                 assert_eq!(batch.len(), 10);
             }))
         }
@@ -494,8 +1000,28 @@ mod tests {
         for h in handles {
             h.join().unwrap();
         }
+    }
+    #[test]
+    fn test_memory_storage_writes_nothing() {
+        let temp_dir = make_test_dir();
+        let config = QueueConfig {
+            memory_budget: Some(1),
+            storage: StorageKind::Memory,
+            ..Default::default()
+        };
+        let queue = ShardedQueue::with_config(2, temp_dir.join("queue"), config).unwrap();
+        queue
+            .push_batch(0, (0..5).map(make_mesages).collect())
+            .unwrap();
+        queue.force_checkpoint().unwrap();
+
+        // No spill file either: everything stays in memory
+        assert_eq!(queue.stats()[0].in_memory, 5);
+        assert!(!temp_dir.join("queue").exists());
+        assert_eq!(queue.pop_batch(0, 5).unwrap().len(), 5);
         cleanup_test_dir(&temp_dir);
     }
+
     #[test]
     fn test_wal_replay_after_push() {
         let temp_dir = make_test_dir();
@@ -506,17 +1032,19 @@ mod tests {
         let msg1 = make_mesages(1);
         let msg2 = make_mesages(2);
 
-        shard.push(msg1.clone());
-        shard.push(msg2.clone());
+        shard.push(msg1.clone()).unwrap();
+        shard.push(msg2.clone()).unwrap();
 
         // Force flush WAL
-        shard.wal.lock().unwrap().flush().unwrap();
+        shard.storage.lock().unwrap().flush().unwrap();
 
         // Replay WAL manually
-        let replayed = Shard::replay_wal(&shard_path).unwrap();
+        let replayed = WalStorage::replay_wal(&shard_path, shard_id, None).unwrap();
         assert_eq!(replayed.len(), 2);
-        assert_eq!(replayed[0].tlvs[0].value, msg1.tlvs[0].value);
-        assert_eq!(replayed[1].tlvs[0].value, msg2.tlvs[0].value);
+        assert_eq!(replayed[0].msg.tlvs[0].value, msg1.tlvs[0].value);
+        assert_eq!(replayed[1].msg.tlvs[0].value, msg2.tlvs[0].value);
+        assert_eq!(replayed[0].seq, 0);
+        assert_eq!(replayed[1].seq, 1);
 
         cleanup_test_dir(&temp_dir);
     }
@@ -531,17 +1059,881 @@ mod tests {
         let msg1 = make_mesages(1);
         let msg2 = make_mesages(2);
 
-        shard.push(msg1.clone());
-        shard.push(msg2.clone());
-        shard.pop(); // pop first message
+        shard.push(msg1.clone()).unwrap();
+        shard.push(msg2.clone()).unwrap();
+        shard.pop().unwrap(); // pop first message
 
-        shard.wal.lock().unwrap().flush().unwrap();
+        shard.storage.lock().unwrap().flush().unwrap();
 
         // Replay WAL manually
-        let replayed_queue = Shard::replay_wal(&shard_path).unwrap();
+        let replayed_queue = WalStorage::replay_wal(&shard_path, shard_id, None).unwrap();
         assert_eq!(replayed_queue.len(), 1);
-        assert_eq!(replayed_queue[0].tlvs[0].value, msg2.tlvs[0].value);
+        assert_eq!(replayed_queue[0].msg.tlvs[0].value, msg2.tlvs[0].value);
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_wal_pop_removes_by_seq() {
+        let temp_dir = make_test_dir();
+        let shard_path = temp_dir.join("shard_0.wal");
+        {
+            let mut wal =
+                storage::WalWriter::new(&shard_path, Codec::Plain, storage::WAL_BATCH_SIZE)
+                    .unwrap();
+            for seq in 0..3 {
+                let encoded = make_mesages(seq as usize).encode();
+                wal.append(WalOp::Push, seq, Some(&encoded)).unwrap();
+            }
+            // Remove the middle message, which a FIFO replay would get wrong
+            wal.append(WalOp::Pop, 1, None).unwrap();
+            wal.flush().unwrap();
+        }
+
+        let replayed = WalStorage::replay_wal(&shard_path, 0, None).unwrap();
+        let seqs: Vec<u64> = replayed.iter().map(|m| m.seq).collect();
+        assert_eq!(seqs, vec![0, 2]);
+        assert_eq!(replayed[1].msg.tlvs[0].value, b"job2".to_vec());
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_recovery_after_checkpoint_keeps_seq() {
+        let temp_dir = make_test_dir();
+        {
+            let shard = Shard::new(0, &temp_dir).unwrap();
+            shard.push(make_mesages(1)).unwrap();
+            shard.push(make_mesages(2)).unwrap();
+            shard.checkpoint().unwrap();
+            shard.pop().unwrap();
+            shard.push(make_mesages(3)).unwrap();
+            shard.storage.lock().unwrap().flush().unwrap();
+        }
+
+        let shard = Shard::new(0, &temp_dir).unwrap();
+        {
+            let state = shard.state.lock().unwrap();
+            let seqs: Vec<u64> = state.queue.iter().map(|m| m.seq).collect();
+            assert_eq!(seqs, vec![1, 2]);
+            assert_eq!(state.next_seq, 3);
+        }
+        assert_eq!(
+            shard.pop().unwrap().unwrap().tlvs[0].value,
+            b"job2".to_vec()
+        );
+        assert_eq!(
+            shard.pop().unwrap().unwrap().tlvs[0].value,
+            b"job3".to_vec()
+        );
+        assert!(shard.pop().unwrap().is_none());
 
         cleanup_test_dir(&temp_dir);
     }
+
+    #[test]
+    fn test_spill_keeps_order_and_reports_depth() {
+        let temp_dir = make_test_dir();
+        let msg_len = make_mesages(0).encoded_len();
+        let shard = Shard::with_config(0, &temp_dir, budget_config(msg_len * 4)).unwrap();
+
+        for i in 0..20 {
+            shard.push(make_mesages(i)).unwrap();
+        }
+        let stats = shard.stats();
+        assert_eq!(stats.in_memory, 4);
+        assert_eq!(stats.on_disk, 16);
+        let spilled_bytes: usize = (4..20).map(|i| make_mesages(i).encoded_len()).sum();
+        assert_eq!(stats.on_disk_bytes, spilled_bytes);
+
+        for i in 0..20 {
+            let msg = shard.pop().unwrap().unwrap();
+            assert_eq!(msg.tlvs[0].value, format!("job{}", i).into_bytes());
+        }
+        assert!(shard.pop().unwrap().is_none());
+        assert_eq!(shard.stats().on_disk, 0);
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_unreadable_spill_fails_pops_instead_of_emptying_the_shard() {
+        let temp_dir = make_test_dir();
+        let msg_len = make_mesages(0).encoded_len();
+        let shard = Shard::with_config(0, &temp_dir, budget_config(msg_len * 2)).unwrap();
+        for i in 0..6 {
+            shard.push(make_mesages(i)).unwrap();
+        }
+        let spill = spill_path(&temp_dir, 0);
+        let mut bytes = std::fs::read(&spill).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&spill, &bytes).unwrap();
+
+        let mut popped = 0;
+        let err = loop {
+            match shard.pop() {
+                Ok(Some(msg)) => {
+                    assert_eq!(msg.tlvs[0].value, format!("job{}", popped).into_bytes());
+                    popped += 1;
+                }
+                Ok(None) => panic!("the shard looked empty after {} pops", popped),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(popped < 6);
+        assert!(shard.pop().is_err());
+        assert!(shard.pop_batch(10).is_err());
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_checkpoint_includes_spilled_messages() {
+        let temp_dir = make_test_dir();
+        let msg_len = make_mesages(0).encoded_len();
+        {
+            let shard = Shard::with_config(0, &temp_dir, budget_config(msg_len * 2)).unwrap();
+            for i in 0..6 {
+                shard.push(make_mesages(i)).unwrap();
+            }
+            shard.pop().unwrap();
+            shard.checkpoint().unwrap();
+        }
+
+        let shard = Shard::with_config(0, &temp_dir, budget_config(msg_len * 2)).unwrap();
+        let stats = shard.stats();
+        assert_eq!(stats.in_memory + stats.on_disk, 5);
+        assert_eq!(stats.in_memory, 2);
+        let popped: Vec<Vec<u8>> = shard
+            .pop_batch(10)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.tlvs[0].value.clone())
+            .collect();
+        let expected: Vec<Vec<u8>> = (1..6).map(|i| format!("job{}", i).into_bytes()).collect();
+        assert_eq!(popped, expected);
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_out_of_order_pop_of_a_spilled_message_survives_a_checkpoint() {
+        let temp_dir = make_test_dir();
+        let msg_len = make_mesages(0).encoded_len();
+        let config = QueueConfig {
+            checkpoint_threshold: 1,
+            ..budget_config(msg_len * 2)
+        };
+        {
+            let follower = ShardedQueue::with_config(1, &temp_dir, config.clone()).unwrap();
+            for i in 0..6 {
+                let data = make_mesages(i).encode();
+                follower.apply(0, WalOp::Push, i as u64, &data).unwrap();
+            }
+            assert_eq!(follower.shards[0].stats().on_disk, 4);
+            // A push the leader failed to store, long since spilled here
+            follower.apply(0, WalOp::Pop, 4, &[]).unwrap();
+            assert_eq!(follower.shards[0].stats().on_disk, 3);
+            follower.force_checkpoint().unwrap();
+
+            let mut seqs = Vec::new();
+            follower.shards[0]
+                .for_each(|stored| {
+                    seqs.push(stored.seq);
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(seqs, vec![0, 1, 2, 3, 5]);
+        }
+
+        let promoted = ShardedQueue::with_config(1, &temp_dir, config).unwrap();
+        let popped: Vec<Vec<u8>> = promoted.shards[0]
+            .pop_batch(10)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.tlvs[0].value.clone())
+            .collect();
+        let expected: Vec<Vec<u8>> = [0, 1, 2, 3, 5]
+            .iter()
+            .map(|i| format!("job{}", i).into_bytes())
+            .collect();
+        assert_eq!(popped, expected);
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_checkpoints_wait_until_a_shard_logged_what_it_holds() {
+        let temp_dir = make_test_dir();
+        let msg_len = make_mesages(0).encoded_len();
+        let shard = Shard::with_config(0, &temp_dir, budget_config(msg_len * 2)).unwrap();
+        for i in 0..10 {
+            shard.push(make_mesages(i)).unwrap();
+        }
+        assert!(shard.checkpoint_if_due().unwrap());
+        assert!(!shard.checkpoint_if_due().unwrap());
+
+        // Five pushes are not worth rewriting a spilled backlog of fifteen
+        for i in 10..15 {
+            shard.push(make_mesages(i)).unwrap();
+        }
+        assert!(!shard.checkpoint_if_due().unwrap());
+        assert_eq!(shard.pop_batch(12).unwrap().len(), 12);
+        assert!(shard.checkpoint_if_due().unwrap());
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_one_background_checkpoint_at_a_time() {
+        let temp_dir = make_test_dir();
+        let config = QueueConfig {
+            checkpoint_threshold: 1,
+            ..Default::default()
+        };
+        let queue = ShardedQueue::with_config(1, &temp_dir, config).unwrap();
+        queue.checkpointing.store(true, Ordering::Release);
+        for i in 0..5 {
+            queue.push(0, make_mesages(i)).unwrap();
+        }
+        assert_eq!(*queue.checkpoint_counter.lock().unwrap(), 5);
+
+        queue.checkpointing.store(false, Ordering::Release);
+        queue.push(0, make_mesages(5)).unwrap();
+        assert_eq!(*queue.checkpoint_counter.lock().unwrap(), 0);
+        let start = Instant::now();
+        while queue.checkpointing.load(Ordering::Acquire) {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_reject_when_full() {
+        let temp_dir = make_test_dir();
+        let config = QueueConfig {
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        let shard = Shard::with_config(0, &temp_dir, config).unwrap();
+        shard.push(make_mesages(1)).unwrap();
+        shard.push(make_mesages(2)).unwrap();
+
+        let err = shard.push(make_mesages(3)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert!(shard.push_batch(vec![make_mesages(4)]).is_err());
+
+        shard.pop().unwrap();
+        shard.push(make_mesages(3)).unwrap();
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_drop_oldest_when_full() {
+        let temp_dir = make_test_dir();
+        let config = QueueConfig {
+            max_depth: Some(2),
+            overflow: OverflowPolicy::DropOldest,
+            ..Default::default()
+        };
+        let shard = Shard::with_config(0, &temp_dir, config).unwrap();
+        for i in 1..=3 {
+            shard.push(make_mesages(i)).unwrap();
+        }
+
+        assert_eq!(
+            shard.pop().unwrap().unwrap().tlvs[0].value,
+            b"job2".to_vec()
+        );
+        assert_eq!(
+            shard.pop().unwrap().unwrap().tlvs[0].value,
+            b"job3".to_vec()
+        );
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_block_until_space_frees() {
+        let temp_dir = make_test_dir();
+        let msg_len = make_mesages(1).encoded_len();
+        let config = QueueConfig {
+            max_bytes: Some(msg_len),
+            overflow: OverflowPolicy::Block {
+                timeout: Duration::from_secs(5),
+            },
+            ..Default::default()
+        };
+        let shard = Arc::new(Shard::with_config(0, &temp_dir, config).unwrap());
+        shard.push(make_mesages(1)).unwrap();
+
+        let consumer = {
+            let shard = Arc::clone(&shard);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                shard.pop().unwrap()
+            })
+        };
+        shard.push(make_mesages(2)).unwrap();
+        assert!(consumer.join().unwrap().is_some());
+        assert_eq!(
+            shard.pop().unwrap().unwrap().tlvs[0].value,
+            b"job2".to_vec()
+        );
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_block_times_out() {
+        let temp_dir = make_test_dir();
+        let config = QueueConfig {
+            max_depth: Some(1),
+            overflow: OverflowPolicy::Block {
+                timeout: Duration::from_millis(20),
+            },
+            ..Default::default()
+        };
+        let shard = Shard::with_config(0, &temp_dir, config).unwrap();
+        shard.push(make_mesages(1)).unwrap();
+        let err = shard.push(make_mesages(2)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_raising_limits_wakes_blocked_push() {
+        let config = QueueConfig {
+            max_depth: Some(1),
+            overflow: OverflowPolicy::Block {
+                timeout: Duration::from_secs(10),
+            },
+            storage: StorageKind::Memory,
+            ..Default::default()
+        };
+        let shard = Arc::new(Shard::with_config(0, Path::new(""), config).unwrap());
+        shard.push(make_mesages(1)).unwrap();
+        let blocked = {
+            let shard = shard.clone();
+            thread::spawn(move || shard.push(make_mesages(2)))
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert!(!blocked.is_finished());
+        shard.set_limits(Some(2), None);
+        blocked.join().unwrap().unwrap();
+        assert_eq!(shard.stats().in_memory, 2);
+
+        // Lowering keeps what is already queued
+        shard.set_limits(Some(1), None);
+        assert_eq!(shard.stats().in_memory, 2);
+    }
+
+    #[test]
+    fn test_wal_checksum_mismatch_stops_replay() {
+        let temp_dir = make_test_dir();
+        let shard_path = temp_dir.join("shard_0.wal");
+        {
+            let shard = Shard::new(0, &temp_dir).unwrap();
+            shard.push(make_mesages(1)).unwrap();
+            shard.push(make_mesages(2)).unwrap();
+            shard.storage.lock().unwrap().flush().unwrap();
+        }
+
+        // Flip a payload byte of the second record
+        let mut bytes = std::fs::read(&shard_path).unwrap();
+        let last = bytes.len() - 6;
+        bytes[last] ^= 0xFF;
+        std::fs::write(&shard_path, &bytes).unwrap();
+
+        let scan = WalStorage::scan_wal(&shard_path, 0, None).unwrap();
+        assert_eq!(scan.records.len(), 1);
+        assert!(matches!(
+            scan.damage,
+            Some(record::WalDamage::BadChecksum { seq: 1, .. })
+        ));
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_pushes_after_a_torn_tail_survive_the_next_restart() {
+        let temp_dir = make_test_dir();
+        let shard_path = wal_path(&temp_dir, 0);
+        {
+            let shard = Shard::new(0, &temp_dir).unwrap();
+            shard.push(make_mesages(1)).unwrap();
+            shard.push(make_mesages(2)).unwrap();
+            shard.storage.lock().unwrap().flush().unwrap();
+        }
+        // A crash part way through the second record
+        let bytes = std::fs::read(&shard_path).unwrap();
+        std::fs::write(&shard_path, &bytes[..bytes.len() - 3]).unwrap();
+
+        {
+            let shard = Shard::new(0, &temp_dir).unwrap();
+            assert_eq!(shard.stats().in_memory, 1);
+            shard.push(make_mesages(3)).unwrap();
+            shard.storage.lock().unwrap().flush().unwrap();
+        }
+        assert!(
+            WalStorage::scan_wal(&shard_path, 0, None)
+                .unwrap()
+                .damage
+                .is_none()
+        );
+
+        let shard = Shard::new(0, &temp_dir).unwrap();
+        let popped: Vec<Vec<u8>> = shard
+            .pop_batch(10)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.tlvs[0].value.clone())
+            .collect();
+        assert_eq!(popped, [b"job1", b"job3"]);
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_upgrades_files_from_before_the_header() {
+        let temp_dir = make_test_dir();
+        // The legacy layouts: `Len | Payload` checkpoints in `.snapshot`,
+        // and `Op | Len | Payload` WAL records ending in a torn one
+        let mut snapshot = Vec::new();
+        for id in [1, 2] {
+            let encoded = make_mesages(id).encode();
+            snapshot.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+            snapshot.extend_from_slice(&encoded);
+        }
+        std::fs::write(temp_dir.join("shard_0.snapshot"), &snapshot).unwrap();
+        let mut wal = Vec::new();
+        for id in [3, 4] {
+            let encoded = make_mesages(id).encode();
+            wal.push(WalOp::Push as u8);
+            wal.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+            wal.extend_from_slice(&encoded);
+        }
+        wal.push(WalOp::Pop as u8);
+        wal.extend_from_slice(&0u32.to_le_bytes());
+        wal.extend_from_slice(&[WalOp::Push as u8, 9]);
+        std::fs::write(wal_path(&temp_dir, 0), &wal).unwrap();
+
+        drop(ShardedQueue::new(1, &temp_dir).unwrap());
+        assert!(!temp_dir.join("shard_0.snapshot").exists());
+        for (path, kind) in [
+            (wal_path(&temp_dir, 0), record::FileKind::Wal),
+            (snapshot_path(&temp_dir, 0), record::FileKind::Snapshot),
+        ] {
+            assert_eq!(
+                record::read_file_version(&path, kind).unwrap(),
+                record::FORMAT_VERSION
+            );
+        }
+
+        let queue = ShardedQueue::new(1, &temp_dir).unwrap();
+        let popped: Vec<Vec<u8>> = queue
+            .pop_batch(0, 10)
+            .unwrap()
+            .into_iter()
+            .map(|msg| msg.tlvs[0].value.clone())
+            .collect();
+        assert_eq!(popped, [b"job2", b"job3", b"job4"]);
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_refuses_files_from_a_newer_format() {
+        let temp_dir = make_test_dir();
+        std::fs::write(wal_path(&temp_dir, 0), b"RLBG\x01\x09").unwrap();
+        let err = ShardedQueue::new(1, &temp_dir).unwrap_err();
+        assert!(err.to_string().contains("format version 9"), "{}", err);
+        // Nor is one kind of file read as another
+        std::fs::write(wal_path(&temp_dir, 0), b"RLBG\x02\x01").unwrap();
+        assert!(ShardedQueue::new(1, &temp_dir).is_err());
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_export_import_reshards() {
+        let source_dir = make_test_dir();
+        let target_dir = make_test_dir();
+        let msg_len = make_mesages(0).encoded_len();
+        let source = ShardedQueue::with_config(2, &source_dir, budget_config(msg_len * 2)).unwrap();
+        for i in 0..10 {
+            let msg = make_mesages(i);
+            source.push(compute_shard_key(&msg, 2), msg).unwrap();
+        }
+
+        let mut exported = Vec::new();
+        assert_eq!(source.export(&mut exported).unwrap(), 10);
+        // Exporting does not consume anything
+        let depth: usize = source.stats().iter().map(|s| s.in_memory + s.on_disk).sum();
+        assert_eq!(depth, 10);
+
+        let target = ShardedQueue::new(3, &target_dir).unwrap();
+        assert_eq!(target.import(exported.as_slice()).unwrap(), 10);
+        let mut imported = 0;
+        for shard in 0..3 {
+            for msg in target.pop_batch(shard, 10).unwrap() {
+                assert_eq!(compute_shard_key(&msg, 3), shard);
+                imported += 1;
+            }
+        }
+        assert_eq!(imported, 10);
+
+        cleanup_test_dir(&source_dir);
+        cleanup_test_dir(&target_dir);
+    }
+
+    fn queued_ids(queue: &ShardedQueue, shard: usize) -> Vec<String> {
+        queue
+            .pop_batch(shard, usize::MAX)
+            .unwrap()
+            .into_iter()
+            .map(|m| String::from_utf8(m.tlvs[1].value.clone()).unwrap())
+            .collect()
+    }
+
+    fn keyed_message(key: &str, n: usize) -> Message {
+        let mut msg = make_mesages(n);
+        msg.tlvs[0].value = key.as_bytes().to_vec();
+        msg.tlvs[1].value = format!("{}-{}", key, n).into_bytes();
+        msg
+    }
+
+    #[test]
+    fn test_reshard_on_shard_count_change() {
+        let temp_dir = make_test_dir();
+        let keys = ["alpha", "beta", "gamma", "delta", "epsilon"];
+        {
+            let queue = ShardedQueue::new(2, &temp_dir).unwrap();
+            for n in 0..4 {
+                for key in keys {
+                    let msg = keyed_message(key, n);
+                    queue.push(compute_shard_key(&msg, 2), msg).unwrap();
+                }
+                if n == 1 {
+                    // Half the messages in snapshots, half only in WALs
+                    queue.force_checkpoint().unwrap();
+                }
+            }
+            for shard in &queue.shards {
+                shard.storage.lock().unwrap().flush().unwrap();
+            }
+        }
+
+        let queue = ShardedQueue::new(3, &temp_dir).unwrap();
+        assert_eq!(read_shard_count(&temp_dir).unwrap(), Some(3));
+        assert!(!temp_dir.join(reshard::STAGING_DIR).exists());
+        let mut total = 0;
+        for shard in 0..3 {
+            let ids = queued_ids(&queue, shard);
+            total += ids.len();
+            for key in keys {
+                let for_key: Vec<&String> = ids
+                    .iter()
+                    .filter(|id| id.starts_with(&format!("{}-", key)))
+                    .collect();
+                if for_key.is_empty() {
+                    continue;
+                }
+                let probe = keyed_message(key, 0);
+                assert_eq!(compute_shard_key(&probe, 3), shard);
+                let expected: Vec<String> = (0..4).map(|n| format!("{}-{}", key, n)).collect();
+                assert_eq!(for_key, expected.iter().collect::<Vec<_>>());
+            }
+        }
+        assert_eq!(total, 20);
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_reshard_resumes_interrupted_commit() {
+        let temp_dir = make_test_dir();
+        {
+            let queue = ShardedQueue::new(4, &temp_dir).unwrap();
+            for n in 0..8 {
+                let msg = make_mesages(n);
+                queue.push(compute_shard_key(&msg, 4), msg).unwrap();
+            }
+            queue.force_checkpoint().unwrap();
+        }
+
+        // Crash right after staging: the old files are still in place
+        let staging = temp_dir.join(reshard::STAGING_DIR);
+        let ids = existing_shard_ids(&temp_dir).unwrap();
+        reshard::stage(&temp_dir, &staging, &ids, 2, &QueueConfig::default()).unwrap();
+
+        let queue = ShardedQueue::new(2, &temp_dir).unwrap();
+        let total: usize = (0..2)
+            .map(|s| queue.pop_batch(s, usize::MAX).unwrap().len())
+            .sum();
+        assert_eq!(total, 8);
+        assert!(!snapshot_path(&temp_dir, 3).exists());
+
+        cleanup_test_dir(&temp_dir);
+    }
+
+    const KEY_A: &str = "a 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_B: &str = "b 1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    fn encrypted_config(keys: &str) -> QueueConfig {
+        QueueConfig {
+            memory_budget: Some(64),
+            encryption: Some(Arc::new(Keyring::parse(keys).unwrap())),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_encrypted_files_and_key_rotation() {
+        let temp_dir = make_test_dir();
+        {
+            let queue = ShardedQueue::with_config(1, &temp_dir, encrypted_config(KEY_A)).unwrap();
+            queue
+                .push_batch(0, (0..5).map(make_mesages).collect())
+                .unwrap();
+            queue.force_checkpoint().unwrap();
+            queue
+                .push_batch(0, (5..10).map(make_mesages).collect())
+                .unwrap();
+            assert!(queue.stats()[0].on_disk > 0);
+            queue.shards[0].storage.lock().unwrap().flush().unwrap();
+        }
+        for path in [
+            wal_path(&temp_dir, 0),
+            snapshot_path(&temp_dir, 0),
+            spill_path(&temp_dir, 0),
+        ] {
+            let bytes = std::fs::read(&path).unwrap();
+            assert!(!bytes.windows(4).any(|w| w == b"job7" || w == b"job2"));
+        }
+        assert_eq!(
+            record::file_key_id(&wal_path(&temp_dir, 0), record::FileKind::Wal).unwrap(),
+            Some("a".to_string())
+        );
+
+        // Without the key nothing can be read
+        assert!(ShardedQueue::new(1, &temp_dir).is_err());
+
+        // Adding a key makes it the active one and rewrites the shard with it
+        let rotated = format!("{}\n{}", KEY_A, KEY_B);
+        {
+            let queue =
+                ShardedQueue::with_config(1, &temp_dir, encrypted_config(&rotated)).unwrap();
+            assert_eq!(queue.stats()[0].in_memory + queue.stats()[0].on_disk, 10);
+        }
+        for (path, kind) in [
+            (wal_path(&temp_dir, 0), record::FileKind::Wal),
+            (snapshot_path(&temp_dir, 0), record::FileKind::Snapshot),
+        ] {
+            assert_eq!(
+                record::file_key_id(&path, kind).unwrap(),
+                Some("b".to_string())
+            );
+        }
+
+        let queue = ShardedQueue::with_config(1, &temp_dir, encrypted_config(KEY_B)).unwrap();
+        let popped: Vec<Message> = queue.pop_batch(0, 10).unwrap();
+        assert_eq!(popped.len(), 10);
+        for (i, msg) in popped.iter().enumerate() {
+            assert_eq!(msg.tlvs[0].value, format!("job{}", i).into_bytes());
+        }
+        cleanup_test_dir(&temp_dir);
+    }
+
+    /// Flips the last byte of the sealed payload of the record at `at`,
+    /// whose length sits `len_at` bytes into it, and fixes up the checksum
+    /// so only the seal can tell.
+    fn tamper_record(bytes: &mut [u8], at: usize, len_at: usize) {
+        let len = &bytes[at + len_at..at + len_at + 4];
+        let end = at + len_at + 4 + u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        bytes[end - 1] ^= 0x01;
+        let crc = crate::checksum::crc32(&bytes[at..end]);
+        bytes[end..end + 4].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn test_sealed_files_refuse_tampering_truncation_and_other_keys() {
+        let temp_dir = make_test_dir();
+        let config = encrypted_config(KEY_A);
+        {
+            let queue = ShardedQueue::with_config(2, &temp_dir, config.clone()).unwrap();
+            for shard in 0..2 {
+                queue
+                    .push_batch(shard, (0..3).map(make_mesages).collect())
+                    .unwrap();
+            }
+            queue.force_checkpoint().unwrap();
+            for shard in 0..2 {
+                queue
+                    .push_batch(shard, (3..6).map(make_mesages).collect())
+                    .unwrap();
+            }
+            queue.flush().unwrap();
+        }
+        let keys = config.encryption.as_ref();
+        let records = Codec::for_writing(keys, 0).header_len() as usize;
+        let (wal, snapshot) = (wal_path(&temp_dir, 0), snapshot_path(&temp_dir, 0));
+        let (clean_wal, clean_snapshot) = (
+            std::fs::read(&wal).unwrap(),
+            std::fs::read(&snapshot).unwrap(),
+        );
+        let first_wal_record = Some(record::WalDamage::AuthFailed {
+            offset: records as u64,
+            seq: 3,
+        });
+
+        assert_eq!(WalStorage::scan_wal(&wal, 0, keys).unwrap().damage, None);
+        let snap = WalStorage::load_snapshoot(&snapshot, 0, keys).unwrap();
+        assert_eq!(snap.messages.len(), 3);
+
+        // Read as another shard's files
+        let scan = WalStorage::scan_wal(&wal, 1, keys).unwrap();
+        assert_eq!(scan.damage, first_wal_record);
+        assert!(WalStorage::load_snapshoot(&snapshot, 1, keys).is_err());
+
+        // A flipped bit under a valid checksum
+        let mut bytes = clean_wal.clone();
+        tamper_record(&mut bytes, records, 9);
+        std::fs::write(&wal, &bytes).unwrap();
+        let scan = WalStorage::scan_wal(&wal, 0, keys).unwrap();
+        assert_eq!(scan.damage, first_wal_record);
+        let mut bytes = clean_snapshot.clone();
+        tamper_record(&mut bytes, records + 8, 8);
+        std::fs::write(&snapshot, &bytes).unwrap();
+        assert!(WalStorage::load_snapshoot(&snapshot, 0, keys).is_err());
+
+        // Cut short
+        std::fs::write(&wal, &clean_wal[..clean_wal.len() - 1]).unwrap();
+        let scan = WalStorage::scan_wal(&wal, 0, keys).unwrap();
+        assert_eq!(scan.records.len(), 2);
+        assert!(matches!(
+            scan.damage,
+            Some(record::WalDamage::TornTail { .. })
+        ));
+        std::fs::write(&snapshot, &clean_snapshot[..clean_snapshot.len() - 1]).unwrap();
+        assert!(WalStorage::load_snapshoot(&snapshot, 0, keys).is_err());
+
+        // Other keys, whether under the id the files name or another one
+        std::fs::write(&wal, &clean_wal).unwrap();
+        std::fs::write(&snapshot, &clean_snapshot).unwrap();
+        let imposter = encrypted_config(&KEY_B.replacen('b', "a", 1));
+        let scan = WalStorage::scan_wal(&wal, 0, imposter.encryption.as_ref()).unwrap();
+        assert_eq!(scan.damage, first_wal_record);
+        let other = encrypted_config(KEY_B);
+        assert!(WalStorage::scan_wal(&wal, 0, other.encryption.as_ref()).is_err());
+        for other in [imposter, other] {
+            let keys = other.encryption.as_ref();
+            assert!(WalStorage::load_snapshoot(&snapshot, 0, keys).is_err());
+            // Refused outright; the WAL is not mistaken for a torn one and cut
+            assert!(ShardedQueue::with_config(2, &temp_dir, other).is_err());
+            assert_eq!(std::fs::read(&wal).unwrap(), clean_wal);
+        }
+
+        let queue = ShardedQueue::with_config(2, &temp_dir, config).unwrap();
+        assert_eq!(queue.stats()[0].in_memory + queue.stats()[0].on_disk, 6);
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_compressed_records_read_back_without_setting() {
+        let temp_dir = make_test_dir();
+        let prompt = |i: usize| {
+            let mut msg = make_mesages(i);
+            msg.tlvs[1].value = format!("summarise document {} ", i).repeat(40).into_bytes();
+            msg
+        };
+        let config = QueueConfig {
+            memory_budget: Some(4096),
+            compress: true,
+            ..Default::default()
+        };
+        {
+            let queue = ShardedQueue::with_config(1, &temp_dir, config).unwrap();
+            queue.push_batch(0, (0..5).map(prompt).collect()).unwrap();
+            queue.force_checkpoint().unwrap();
+            queue.push_batch(0, (5..10).map(prompt).collect()).unwrap();
+            queue.shards[0].storage.lock().unwrap().flush().unwrap();
+
+            let stats = &queue.stats()[0];
+            assert!(stats.on_disk > 0);
+            let logical: usize = (0..10).map(|i| prompt(i).encoded_len()).sum();
+            assert_eq!(stats.in_memory_bytes + stats.on_disk_bytes, logical);
+            let on_disk = std::fs::metadata(snapshot_path(&temp_dir, 0))
+                .unwrap()
+                .len()
+                + std::fs::metadata(wal_path(&temp_dir, 0)).unwrap().len();
+            assert!((on_disk as usize) < logical / 4);
+        }
+
+        let queue = ShardedQueue::new(1, &temp_dir).unwrap();
+        let popped = queue.pop_batch(0, 10).unwrap();
+        assert_eq!(popped.len(), 10);
+        for (i, msg) in popped.iter().enumerate() {
+            assert_eq!(msg.tlvs, prompt(i).tlvs);
+        }
+        cleanup_test_dir(&temp_dir);
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let data_dir = make_test_dir();
+        let backup_dir = data_dir.join("backup");
+        let restored_dir = make_test_dir();
+        let keys = ["alpha", "beta", "gamma"];
+        {
+            let queue = ShardedQueue::with_config(2, &data_dir, budget_config(256)).unwrap();
+            for n in 0..4 {
+                for key in keys {
+                    let msg = keyed_message(key, n);
+                    queue.push(compute_shard_key(&msg, 2), msg).unwrap();
+                }
+            }
+            let alpha = compute_shard_key(&keyed_message("alpha", 0), 2);
+            queue.pop(alpha).unwrap();
+            assert!(queue.stats().iter().any(|s| s.on_disk > 0));
+
+            assert_eq!(queue.backup(&backup_dir).unwrap(), 11);
+            // Later traffic stays out of the backup
+            queue.push(alpha, keyed_message("alpha", 4)).unwrap();
+            let again = queue.backup(&backup_dir).unwrap_err();
+            assert_eq!(again.kind(), io::ErrorKind::AlreadyExists);
+        }
+
+        // A backup without its manifest is unfinished and never restored
+        std::fs::rename(backup_dir.join("backup.meta"), data_dir.join("meta")).unwrap();
+        assert!(backup::restore(&backup_dir, &restored_dir, &QueueConfig::default()).is_err());
+        std::fs::rename(data_dir.join("meta"), backup_dir.join("backup.meta")).unwrap();
+
+        ShardedQueue::new(2, &restored_dir)
+            .unwrap()
+            .push(0, keyed_message("stale", 0))
+            .unwrap();
+        let restored = backup::restore(&backup_dir, &restored_dir, &QueueConfig::default());
+        assert_eq!(restored.unwrap(), 11);
+
+        let queue = ShardedQueue::new(3, &restored_dir).unwrap();
+        let mut ids: Vec<String> = (0..3).flat_map(|s| queued_ids(&queue, s)).collect();
+        ids.sort();
+        let mut expected: Vec<String> = keys
+            .iter()
+            .flat_map(|key| (0..4).map(move |n| format!("{}-{}", key, n)))
+            .filter(|id| id != "alpha-0")
+            .collect();
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        cleanup_test_dir(&data_dir);
+        cleanup_test_dir(&restored_dir);
+    }
 }
@@ -0,0 +1,605 @@
+use crate::checksum::{Crc32, crc32};
+use crate::crypto::{Keyring, MAX_KEY_ID_LEN};
+use crate::protocol::Message;
+use crate::shards::{WalOp, WalRecord};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/*
+Every file starts with a header naming what it holds and the layout of
+the rest
++--------+------+---------+
+| Magic  | Kind | Version |
++--------+------+---------+
+  "RLBG"   u8     u8
+
+Kind is 1 for a WAL, 2 for a snapshot and 3 for a spill file. Files from
+before the header existed (version 0) are converted by `upgrade` when the
+queue opens.
+
+WAL record
++------+----------+----------+---------+----------+
+| Op   | Seq      | Len      | Payload | Crc32    |
++------+----------+----------+---------+----------+
+  u8     u64 (le)   u32 (le)   Len bytes  u32 (le)
+
+Push carries the encoded message, compressed as on the wire when the
+queue compresses records, and Pop carries no payload and only
+references the seq of the message that left the queue. The checksum
+covers every byte before it.
+
+Snapshot
++--------------+-------------------------------------+
+| NextSeq      | (Seq, Len, Payload, Crc32) records  |
++--------------+-------------------------------------+
+  u64 (le)
+
+Spill files use the snapshot record layout without the NextSeq prefix.
+
+Encrypted files follow the file header with one naming the key that
+sealed them
++------------+-------+-------+
+| Magic      | IdLen | KeyId |
++------------+-------+-------+
+  "RLBGENC1"   u8      IdLen bytes
+
+and every payload is replaced by `nonce | ciphertext | tag`, sealed with
+associated data naming where the record belongs
++--------+------+----------+------+----------+
+| Magic  | Kind | Shard    | Op   | Seq      |
++--------+------+----------+------+----------+
+  "rlbg"   u8     u32 (le)   u8     u64 (le)
+
+so a record copied into another file kind, shard or position does not
+open. Plaintext files have no key header.
+*/
+
+pub const FILE_MAGIC: [u8; 4] = *b"RLBG";
+pub const FILE_HEADER_LEN: usize = 6;
+/// Version of the layout this build writes.
+pub const FORMAT_VERSION: u8 = 1;
+/// Files written before the file header existed.
+pub const LEGACY_VERSION: u8 = 0;
+
+pub const SEALED_MAGIC: [u8; 8] = *b"RLBGENC1";
+
+/// What a shard file holds, as recorded in its header.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Wal = 1,
+    Snapshot = 2,
+    Spill = 3,
+}
+
+impl std::fmt::Display for FileKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Wal => write!(f, "WAL"),
+            Self::Snapshot => write!(f, "snapshot"),
+            Self::Spill => write!(f, "spill"),
+        }
+    }
+}
+
+pub fn write_file_header(out: &mut impl Write, kind: FileKind) -> io::Result<()> {
+    out.write_all(&FILE_MAGIC)?;
+    out.write_all(&[kind as u8, FORMAT_VERSION])
+}
+
+/// Format version of a `kind` file that starts with `prefix`, which holds
+/// at least the file header unless the file is shorter. Empty files are
+/// current. Files of another kind or from a newer build are errors.
+pub fn file_version(prefix: &[u8], kind: FileKind) -> io::Result<u8> {
+    if prefix.is_empty() {
+        return Ok(FORMAT_VERSION);
+    }
+    if !prefix.starts_with(&FILE_MAGIC) {
+        // Legacy WALs start with an op byte and legacy snapshots with a
+        // record length, neither of which can spell the magic
+        return Ok(LEGACY_VERSION);
+    }
+    if prefix.len() < FILE_HEADER_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated file header",
+        ));
+    }
+    if prefix[4] != kind as u8 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("not a {} file (kind {})", kind, prefix[4]),
+        ));
+    }
+    let version = prefix[5];
+    if version > FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} file has format version {}, newer than the {} this broker reads",
+                kind, version, FORMAT_VERSION
+            ),
+        ));
+    }
+    Ok(version)
+}
+
+/// Fails unless a `kind` file starting with `prefix` has the current layout.
+fn check_current(prefix: &[u8], kind: FileKind) -> io::Result<()> {
+    match file_version(prefix, kind)? {
+        FORMAT_VERSION => Ok(()),
+        version => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} file has format version {}; opening the queue upgrades it",
+                kind, version
+            ),
+        )),
+    }
+}
+
+const WAL_HEADER_LEN: usize = 13;
+const SNAPSHOT_HEADER_LEN: usize = 12;
+
+/// Encodes a message for a record payload. Compressed messages are flagged
+/// in their own header, so readers need no setting to decode them.
+pub fn encode_message(msg: &Message, compress: bool) -> Vec<u8> {
+    if compress {
+        msg.encode_compressed()
+    } else {
+        msg.encode()
+    }
+}
+
+pub fn encode_wal_record(op: WalOp, seq: u64, data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(WAL_HEADER_LEN + data.len() + 4);
+    record.push(op as u8);
+    record.extend_from_slice(&seq.to_le_bytes());
+    record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    record.extend_from_slice(data);
+    let crc = crc32(&record);
+    record.extend_from_slice(&crc.to_le_bytes());
+    record
+}
+
+/// How the payloads of one file are stored.
+#[derive(Debug, Clone, Default)]
+pub enum Codec {
+    #[default]
+    Plain,
+    Sealed {
+        keyring: Arc<Keyring>,
+        key_id: String,
+        /// Shard whose files this codec reads or writes.
+        shard: u32,
+    },
+}
+
+impl Codec {
+    /// Codec for a new file of shard `shard`: sealed with the active key if
+    /// there are keys.
+    pub fn for_writing(keys: Option<&Arc<Keyring>>, shard: usize) -> Self {
+        match keys {
+            Some(keyring) => Self::Sealed {
+                keyring: keyring.clone(),
+                key_id: keyring.active_id().to_string(),
+                shard: shard as u32,
+            },
+            None => Self::Plain,
+        }
+    }
+
+    /// Codec for an existing file of shard `shard` whose header named
+    /// `key_id`.
+    pub fn for_reading(
+        key_id: Option<String>,
+        keys: Option<&Arc<Keyring>>,
+        shard: usize,
+    ) -> io::Result<Self> {
+        let Some(key_id) = key_id else {
+            return Ok(Self::Plain);
+        };
+        match keys {
+            Some(keyring) if keyring.contains(&key_id) => Ok(Self::Sealed {
+                keyring: keyring.clone(),
+                key_id,
+                shard: shard as u32,
+            }),
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("encrypted with unknown key {}", key_id),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("encrypted with key {} but no keys were given", key_id),
+            )),
+        }
+    }
+
+    pub fn key_id(&self) -> Option<&str> {
+        match self {
+            Self::Plain => None,
+            Self::Sealed { key_id, .. } => Some(key_id),
+        }
+    }
+
+    /// Length of the file and key headers of a file written with this codec.
+    pub fn header_len(&self) -> u64 {
+        let key_header = self
+            .key_id()
+            .map_or(0, |id| SEALED_MAGIC.len() + 1 + id.len());
+        (FILE_HEADER_LEN + key_header) as u64
+    }
+
+    /// Writes the file header of a `kind` file, then the key header if the
+    /// file is sealed.
+    pub fn write_header(&self, out: &mut impl Write, kind: FileKind) -> io::Result<()> {
+        write_file_header(out, kind)?;
+        if let Some(id) = self.key_id() {
+            out.write_all(&SEALED_MAGIC)?;
+            out.write_all(&[id.len() as u8])?;
+            out.write_all(id.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Seals the payload of a record of a `kind` file.
+    pub fn seal<'a>(&self, kind: FileKind, op: WalOp, seq: u64, data: &'a [u8]) -> Cow<'a, [u8]> {
+        match self {
+            Self::Plain => Cow::Borrowed(data),
+            Self::Sealed { keyring, shard, .. } => {
+                Cow::Owned(keyring.seal(&aad(kind, *shard, op, seq), data))
+            }
+        }
+    }
+
+    /// Opens the payload of a record read from a `kind` file.
+    pub fn open<'a>(
+        &self,
+        kind: FileKind,
+        op: WalOp,
+        seq: u64,
+        data: &'a [u8],
+    ) -> io::Result<Cow<'a, [u8]>> {
+        match self {
+            Self::Plain => Ok(Cow::Borrowed(data)),
+            Self::Sealed {
+                keyring,
+                key_id,
+                shard,
+            } => keyring
+                .open(key_id, &aad(kind, *shard, op, seq), data)
+                .map(Cow::Owned)
+                .map_err(|e| io::Error::new(e.kind(), format!("record seq {}: {}", seq, e))),
+        }
+    }
+}
+
+const AAD_MAGIC: [u8; 4] = *b"rlbg";
+const AAD_LEN: usize = 18;
+
+fn aad(kind: FileKind, shard: u32, op: WalOp, seq: u64) -> [u8; AAD_LEN] {
+    let mut aad = [0u8; AAD_LEN];
+    aad[0..4].copy_from_slice(&AAD_MAGIC);
+    aad[4] = kind as u8;
+    aad[5..9].copy_from_slice(&shard.to_le_bytes());
+    aad[9] = op as u8;
+    aad[10..].copy_from_slice(&seq.to_le_bytes());
+    aad
+}
+
+/// Splits the headers off the front of a `kind` file: the key id, if the
+/// file is sealed, and the offset its records start at. An empty buffer
+/// has no records.
+pub fn split_header(buffer: &[u8], kind: FileKind) -> io::Result<(Option<String>, usize)> {
+    if buffer.is_empty() {
+        return Ok((None, 0));
+    }
+    check_current(buffer, kind)?;
+    let buffer = &buffer[FILE_HEADER_LEN..];
+    if !buffer.starts_with(&SEALED_MAGIC) {
+        return Ok((None, FILE_HEADER_LEN));
+    }
+    let truncated = || io::Error::new(io::ErrorKind::InvalidData, "truncated encryption header");
+    let id_len = *buffer.get(SEALED_MAGIC.len()).ok_or_else(truncated)? as usize;
+    let start = SEALED_MAGIC.len() + 1;
+    let id = buffer.get(start..start + id_len).ok_or_else(truncated)?;
+    let id = String::from_utf8(id.to_vec())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad key id"))?;
+    Ok((Some(id), FILE_HEADER_LEN + start + id_len))
+}
+
+/// Reads the file header of the `kind` file at `path` and returns its
+/// format version.
+pub fn read_file_version(path: &Path, kind: FileKind) -> io::Result<u8> {
+    let mut prefix = [0u8; FILE_HEADER_LEN];
+    let read = read_full(&mut File::open(path)?, &mut prefix)?;
+    file_version(&prefix[..read], kind)
+}
+
+/// Key id named by the header of the `kind` file at `path`, if it is
+/// sealed.
+pub fn file_key_id(path: &Path, kind: FileKind) -> io::Result<Option<String>> {
+    let mut prefix = [0u8; FILE_HEADER_LEN + SEALED_MAGIC.len() + 1 + MAX_KEY_ID_LEN];
+    let read = read_full(&mut File::open(path)?, &mut prefix)?;
+    Ok(split_header(&prefix[..read], kind)?.0)
+}
+
+/// Why a WAL scan stopped before the end of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalDamage {
+    /// The last record was only partially written, as after a crash.
+    TornTail { offset: u64 },
+    /// A record failed its checksum.
+    BadChecksum { offset: u64, seq: u64 },
+    /// A record started with a byte that is not a known op.
+    UnknownOp { offset: u64, op: u8 },
+    /// A sealed record did not decrypt with its file's key.
+    AuthFailed { offset: u64, seq: u64 },
+}
+
+impl WalDamage {
+    /// Where the damaged record starts, which is where the valid ones end.
+    pub fn offset(&self) -> u64 {
+        match *self {
+            Self::TornTail { offset }
+            | Self::BadChecksum { offset, .. }
+            | Self::UnknownOp { offset, .. }
+            | Self::AuthFailed { offset, .. } => offset,
+        }
+    }
+}
+
+impl std::fmt::Display for WalDamage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::TornTail { offset } => write!(f, "torn record at offset {}", offset),
+            Self::BadChecksum { offset, seq } => {
+                write!(f, "checksum mismatch at offset {} (seq {})", offset, seq)
+            }
+            Self::UnknownOp { offset, op } => {
+                write!(f, "unknown op {:#04x} at offset {}", op, offset)
+            }
+            Self::AuthFailed { offset, seq } => {
+                write!(
+                    f,
+                    "authentication failed at offset {} (seq {})",
+                    offset, seq
+                )
+            }
+        }
+    }
+}
+
+/// Every valid record of a WAL buffer, plus what stopped the scan if it
+/// did not reach the end cleanly.
+#[derive(Debug, Clone, Default)]
+pub struct WalScan {
+    pub records: Vec<WalRecord>,
+    pub damage: Option<WalDamage>,
+}
+
+/// Scans the records of a WAL buffer, starting at `offset`, and opens
+/// their payloads with `codec`.
+pub fn scan_wal(buffer: &[u8], mut offset: usize, codec: &Codec) -> WalScan {
+    let mut scan = WalScan::default();
+
+    while offset < buffer.len() {
+        let start = offset as u64;
+        if offset + WAL_HEADER_LEN > buffer.len() {
+            scan.damage = Some(WalDamage::TornTail { offset: start });
+            break;
+        }
+        let Some(op) = WalOp::from_byte(buffer[offset]) else {
+            scan.damage = Some(WalDamage::UnknownOp {
+                offset: start,
+                op: buffer[offset],
+            });
+            break;
+        };
+        let seq = u64::from_le_bytes(buffer[offset + 1..offset + 9].try_into().unwrap());
+        let len = u32::from_le_bytes(buffer[offset + 9..offset + 13].try_into().unwrap()) as usize;
+
+        let body_end = offset + WAL_HEADER_LEN + len;
+        if body_end + 4 > buffer.len() {
+            scan.damage = Some(WalDamage::TornTail { offset: start });
+            break;
+        }
+        let stored_crc = u32::from_le_bytes(buffer[body_end..body_end + 4].try_into().unwrap());
+        if crc32(&buffer[offset..body_end]) != stored_crc {
+            scan.damage = Some(WalDamage::BadChecksum { offset: start, seq });
+            break;
+        }
+
+        let payload = &buffer[offset + WAL_HEADER_LEN..body_end];
+        let Ok(data) = codec.open(FileKind::Wal, op, seq, payload) else {
+            scan.damage = Some(WalDamage::AuthFailed { offset: start, seq });
+            break;
+        };
+        scan.records.push(WalRecord {
+            op,
+            seq,
+            data: data.into_owned(),
+            offset: start,
+        });
+        offset = body_end + 4;
+    }
+    scan
+}
+
+pub fn write_snapshot_record(out: &mut impl Write, seq: u64, data: &[u8]) -> io::Result<()> {
+    let mut header = [0u8; SNAPSHOT_HEADER_LEN];
+    header[0..8].copy_from_slice(&seq.to_le_bytes());
+    header[8..12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    let mut crc = Crc32::new();
+    crc.update(&header);
+    crc.update(data);
+    out.write_all(&header)?;
+    out.write_all(data)?;
+    out.write_all(&crc.finish().to_le_bytes())
+}
+
+/// Size on disk of a snapshot record holding `len` payload bytes.
+pub fn snapshot_record_len(len: usize) -> u64 {
+    (SNAPSHOT_HEADER_LEN + len + 4) as u64
+}
+
+/// Reads the start of a snapshot: the key id of a sealed file and the next
+/// seq. `None` for an empty file.
+pub fn read_snapshot_header(input: &mut impl Read) -> io::Result<Option<(Option<String>, u64)>> {
+    let mut header = [0u8; FILE_HEADER_LEN];
+    let read = read_full(input, &mut header)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    check_current(&header[..read], FileKind::Snapshot)?;
+    let mut first = [0u8; 8];
+    let read = read_full(input, &mut first)?;
+    if read < first.len() {
+        return Ok(None);
+    }
+    let mut key_id = None;
+    if first == SEALED_MAGIC {
+        let mut id_len = [0u8; 1];
+        input.read_exact(&mut id_len)?;
+        let mut id = vec![0u8; id_len[0] as usize];
+        input.read_exact(&mut id)?;
+        key_id = Some(
+            String::from_utf8(id)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad key id"))?,
+        );
+        input.read_exact(&mut first)?;
+    }
+    Ok(Some((key_id, u64::from_le_bytes(first))))
+}
+
+/// Reads the next snapshot record into `payload` and returns its seq, or
+/// `None` at a clean end of input. Partial or corrupt records are errors.
+pub fn read_snapshot_record(
+    input: &mut impl Read,
+    payload: &mut Vec<u8>,
+) -> io::Result<Option<u64>> {
+    let mut header = [0u8; SNAPSHOT_HEADER_LEN];
+    let read = read_full(input, &mut header)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read < header.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "truncated snapshot record",
+        ));
+    }
+    let seq = u64::from_le_bytes(header[0..8].try_into().unwrap());
+    let len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+
+    payload.resize(len, 0);
+    input.read_exact(payload)?;
+    let mut stored_crc = [0u8; 4];
+    input.read_exact(&mut stored_crc)?;
+
+    let mut crc = Crc32::new();
+    crc.update(&header);
+    crc.update(payload);
+    if crc.finish() != u32::from_le_bytes(stored_crc) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("checksum mismatch in record seq {}", seq),
+        ));
+    }
+    Ok(Some(seq))
+}
+
+/// Like `read_exact`, but reports how much was read instead of failing on
+/// a short read.
+fn read_full(input: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match input.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "a 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    // Same id, other key bytes
+    const IMPOSTER: &str = "a 1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    fn codec(keys: &str, shard: usize) -> Codec {
+        Codec::for_writing(Some(&Arc::new(Keyring::parse(keys).unwrap())), shard)
+    }
+
+    #[test]
+    fn test_sealed_records_only_open_where_they_were_written() {
+        let shard_3 = codec(KEY, 3);
+        let sealed = shard_3.seal(FileKind::Spill, WalOp::Push, 7, b"prompt");
+        let opened = shard_3
+            .open(FileKind::Spill, WalOp::Push, 7, &sealed)
+            .unwrap();
+        assert_eq!(&*opened, b"prompt");
+
+        // Another file kind, shard, op or seq
+        assert!(
+            shard_3
+                .open(FileKind::Snapshot, WalOp::Push, 7, &sealed)
+                .is_err()
+        );
+        assert!(
+            shard_3
+                .open(FileKind::Wal, WalOp::Push, 7, &sealed)
+                .is_err()
+        );
+        let shard_4 = codec(KEY, 4);
+        assert!(
+            shard_4
+                .open(FileKind::Spill, WalOp::Push, 7, &sealed)
+                .is_err()
+        );
+        assert!(
+            shard_3
+                .open(FileKind::Spill, WalOp::Pop, 7, &sealed)
+                .is_err()
+        );
+        assert!(
+            shard_3
+                .open(FileKind::Spill, WalOp::Push, 8, &sealed)
+                .is_err()
+        );
+
+        // Another key, tampering and truncation
+        let imposter = codec(IMPOSTER, 3);
+        assert!(
+            imposter
+                .open(FileKind::Spill, WalOp::Push, 7, &sealed)
+                .is_err()
+        );
+        for at in [0, sealed.len() / 2, sealed.len() - 1] {
+            let mut tampered = sealed.to_vec();
+            tampered[at] ^= 0x01;
+            assert!(
+                shard_3
+                    .open(FileKind::Spill, WalOp::Push, 7, &tampered)
+                    .is_err()
+            );
+        }
+        for len in [0, 12, sealed.len() - 1] {
+            let truncated = &sealed[..len];
+            assert!(
+                shard_3
+                    .open(FileKind::Spill, WalOp::Push, 7, truncated)
+                    .is_err()
+            );
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use crate::log_info;
+use crate::logger::global_loger;
+use crate::shards::record::Codec;
+use crate::shards::storage::SnapshotWriter;
+use crate::shards::{
+    QueueConfig, WalStorage, compute_shard_key, snapshot_path, spill_path, wal_path,
+};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const META_FILE: &str = "queue.meta";
+pub(super) const STAGING_DIR: &str = "reshard.staging";
+
+/// Reads the shard count recorded in `queue.meta`, if any.
+pub fn read_shard_count(data_dir: &Path) -> io::Result<Option<usize>> {
+    let text = match fs::read_to_string(data_dir.join(META_FILE)) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    for line in text.lines() {
+        if let Some(count) = line.trim().strip_prefix("shard_count=") {
+            let count = count
+                .parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad shard_count"))?;
+            return Ok(Some(count));
+        }
+    }
+    Ok(None)
+}
+
+pub(super) fn write_meta(dir: &Path, shard_count: usize) -> io::Result<()> {
+    let temp = dir.join(format!("{}.tmp", META_FILE));
+    let mut file = File::create(&temp)?;
+    writeln!(file, "shard_count={}", shard_count)?;
+    file.sync_all()?;
+    fs::rename(temp, dir.join(META_FILE))
+}
+
+/// Ids of every shard with a WAL or snapshot in `data_dir`.
+pub fn existing_shard_ids(data_dir: &Path) -> io::Result<BTreeSet<usize>> {
+    let mut ids = BTreeSet::new();
+    for entry in fs::read_dir(data_dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        let id = name
+            .strip_prefix("shard_")
+            .and_then(|rest| {
+                rest.strip_suffix(".wal")
+                    .or_else(|| rest.strip_suffix(".snap"))
+            })
+            .and_then(|id| id.parse().ok());
+        if let Some(id) = id {
+            ids.insert(id);
+        }
+    }
+    Ok(ids)
+}
+
+/// Makes the data directory match `shard_count` before any shard is opened.
+///
+/// When the directory was written with a different count, every persisted
+/// message is re-routed with `compute_shard_key` into a staging directory,
+/// which then replaces the old shard files. All messages of a key live in a
+/// single old shard and are recovered in seq order, so per-key order
+/// survives. A crash while staging leaves the old files untouched; a crash
+/// while swapping is finished on the next start.
+pub fn ensure_layout(data_dir: &Path, shard_count: usize, config: &QueueConfig) -> io::Result<()> {
+    let staging = data_dir.join(STAGING_DIR);
+    if staging.exists() {
+        if read_shard_count(&staging)?.is_some() {
+            log_info!(global_loger(), "Finishing interrupted reshard");
+            return commit(data_dir, &staging);
+        }
+        fs::remove_dir_all(&staging)?;
+    }
+
+    let ids = existing_shard_ids(data_dir)?;
+    let previous = match read_shard_count(data_dir)? {
+        Some(count) => count,
+        // Directories from before queue.meta existed
+        None => ids.last().map_or(shard_count, |max| max + 1),
+    };
+    if previous == shard_count {
+        return write_meta(data_dir, shard_count);
+    }
+
+    log_info!(
+        global_loger(),
+        "Resharding {} from {} to {} shards",
+        data_dir.display(),
+        previous,
+        shard_count
+    );
+    let moved = stage(data_dir, &staging, &ids, shard_count, config)?;
+    commit(data_dir, &staging)?;
+    log_info!(global_loger(), "Reshard moved {} messages", moved);
+    Ok(())
+}
+
+/// Writes the new layout as snapshot-only shards under `staging`, then
+/// marks it complete by writing its meta file last.
+pub(super) fn stage(
+    data_dir: &Path,
+    staging: &Path,
+    old_ids: &BTreeSet<usize>,
+    shard_count: usize,
+    config: &QueueConfig,
+) -> io::Result<usize> {
+    let keys = config.encryption.as_ref();
+    fs::create_dir_all(staging)?;
+    let mut writers = Vec::with_capacity(shard_count);
+    for id in 0..shard_count {
+        let writer = SnapshotWriter::create(
+            &snapshot_path(staging, id),
+            Codec::for_writing(keys, id),
+            config.compress,
+        )?;
+        writers.push((writer, 0u64));
+    }
+
+    let mut moved = 0;
+    for &old_id in old_ids {
+        WalStorage::recover_files(data_dir, old_id, keys, |stored| {
+            let (writer, next_seq) = &mut writers[compute_shard_key(&stored.msg, shard_count)];
+            writer.write(*next_seq, &stored.msg)?;
+            *next_seq += 1;
+            moved += 1;
+            Ok(())
+        })?;
+    }
+
+    for (writer, next_seq) in writers {
+        writer.finish(next_seq)?;
+    }
+    write_meta(staging, shard_count)?;
+    Ok(moved)
+}
+
+/// Swaps a complete staging directory in. Every step is safe to repeat.
+pub(super) fn commit(data_dir: &Path, staging: &Path) -> io::Result<()> {
+    let shard_count = read_shard_count(staging)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "staging has no meta"))?;
+
+    // The staged snapshots hold everything, so the old logs can go.
+    for id in existing_shard_ids(data_dir)? {
+        remove_if_exists(&wal_path(data_dir, id))?;
+        remove_if_exists(&spill_path(data_dir, id))?;
+        if id >= shard_count {
+            remove_if_exists(&snapshot_path(data_dir, id))?;
+        }
+    }
+    for id in 0..shard_count {
+        let staged: PathBuf = snapshot_path(staging, id);
+        if staged.exists() {
+            fs::rename(staged, snapshot_path(data_dir, id))?;
+        }
+    }
+    fs::rename(staging.join(META_FILE), data_dir.join(META_FILE))?;
+    fs::remove_dir_all(staging)
+}
+
+pub(super) fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,336 @@
+use crate::protocol::Message;
+use crate::shards::record::{
+    Codec, FileKind, read_snapshot_record, snapshot_record_len, write_snapshot_record,
+};
+use crate::shards::{StoredMessage, WalOp};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Consumed bytes at the front of the file before it is compacted, at
+/// least. Compaction also waits for them to outweigh the unread records, so
+/// each byte is copied a bounded number of times however long the backlog
+/// lasts.
+const COMPACT_MIN: u64 = 1024 * 1024;
+const COPY_CHUNK: usize = 64 * 1024;
+
+/// Overflow area for a shard's cold tail.
+///
+/// Messages that do not fit into the shard's memory budget are appended
+/// here in seq order and paged back in from the front as the hot window
+/// drains. Records use the same `(Seq, Len, Payload)` layout as snapshot
+/// records, checksum included, and are sealed like every other shard file
+/// when the queue is encrypted. The file is scratch space only: the
+/// snapshot and WAL stay the source of truth, so it is recreated empty on
+/// every start.
+///
+/// Paged-in records are cut off the front of the file once enough of them
+/// pile up, so a backlog that never quite drains does not grow it forever.
+/// Messages removed out of order keep their record until it is paged past,
+/// but are never returned again.
+#[derive(Debug)]
+pub struct SpillLog {
+    file: File,
+    codec: Codec,
+    read_offset: u64,
+    write_offset: u64,
+    len: usize,
+    bytes: usize,
+    /// Seqs of removed messages whose records are still in the file.
+    removed: HashSet<u64>,
+    compact_min: u64,
+}
+
+impl SpillLog {
+    pub fn create(path: &Path, codec: Codec) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        codec.write_header(&mut file, FileKind::Spill)?;
+        let start = codec.header_len();
+        Ok(Self {
+            file,
+            codec,
+            read_offset: start,
+            write_offset: start,
+            len: 0,
+            bytes: 0,
+            removed: HashSet::new(),
+            compact_min: COMPACT_MIN,
+        })
+    }
+
+    /// Number of messages currently on disk.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Encoded size of the messages currently on disk, before compression.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn append(&mut self, stored: &StoredMessage, encoded: &[u8]) -> io::Result<()> {
+        let sealed = self
+            .codec
+            .seal(FileKind::Spill, WalOp::Push, stored.seq, encoded);
+        let mut record = Vec::with_capacity(snapshot_record_len(sealed.len()) as usize);
+        write_snapshot_record(&mut record, stored.seq, &sealed)?;
+
+        self.file.seek(SeekFrom::Start(self.write_offset))?;
+        self.file.write_all(&record)?;
+        self.write_offset += record.len() as u64;
+        self.len += 1;
+        self.bytes += stored.msg.encoded_len();
+        Ok(())
+    }
+
+    pub fn pop_front(&mut self) -> io::Result<Option<StoredMessage>> {
+        while self.len > 0 {
+            self.file.seek(SeekFrom::Start(self.read_offset))?;
+            let mut payload = Vec::new();
+            let seq = read_snapshot_record(&mut self.file, &mut payload)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "spill file ended early")
+            })?;
+            if self.removed.remove(&seq) {
+                self.read_offset += snapshot_record_len(payload.len());
+                continue;
+            }
+            let encoded = self
+                .codec
+                .open(FileKind::Spill, WalOp::Push, seq, &payload)?;
+            let msg = Message::decode(&encoded)?;
+
+            self.read_offset += snapshot_record_len(payload.len());
+            self.len -= 1;
+            self.bytes -= msg.encoded_len();
+            if self.len == 0 {
+                self.reset()?;
+            } else {
+                let consumed = self.read_offset - self.codec.header_len();
+                if consumed >= self.compact_min && consumed >= self.write_offset - self.read_offset
+                {
+                    self.compact()?;
+                }
+            }
+            return Ok(Some(StoredMessage { seq, msg }));
+        }
+        Ok(None)
+    }
+
+    /// Drops the message with `seq` wherever it sits in the file. Returns
+    /// whether it was there. This reads the whole file, so it is only meant
+    /// for the rare message removed out of order.
+    pub fn remove(&mut self, seq: u64) -> io::Result<bool> {
+        let mut found = None;
+        self.for_each(|stored| {
+            if stored.seq == seq {
+                found = Some(stored.msg.encoded_len());
+            }
+            Ok(())
+        })?;
+        let Some(len) = found else {
+            return Ok(false);
+        };
+        self.removed.insert(seq);
+        self.len -= 1;
+        self.bytes -= len;
+        if self.len == 0 {
+            self.reset()?;
+        }
+        Ok(true)
+    }
+
+    /// Calls `f` for every message on disk, oldest first, without consuming
+    /// them.
+    pub fn for_each(
+        &mut self,
+        mut f: impl FnMut(StoredMessage) -> io::Result<()>,
+    ) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.read_offset))?;
+        let mut reader =
+            io::BufReader::new((&mut self.file).take(self.write_offset - self.read_offset));
+        let mut payload = Vec::new();
+        while let Some(seq) = read_snapshot_record(&mut reader, &mut payload)? {
+            if self.removed.contains(&seq) {
+                continue;
+            }
+            let encoded = self
+                .codec
+                .open(FileKind::Spill, WalOp::Push, seq, &payload)?;
+            f(StoredMessage {
+                seq,
+                msg: Message::decode(&encoded)?,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Drops every message on disk.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.len = 0;
+        self.reset()
+    }
+
+    /// Moves the unread records to the front of the file and cuts off the
+    /// rest.
+    fn compact(&mut self) -> io::Result<()> {
+        let mut chunk = vec![0u8; COPY_CHUNK];
+        let mut from = self.read_offset;
+        let mut to = self.codec.header_len();
+        while from < self.write_offset {
+            let n = chunk.len().min((self.write_offset - from) as usize);
+            self.file.seek(SeekFrom::Start(from))?;
+            self.file.read_exact(&mut chunk[..n])?;
+            self.file.seek(SeekFrom::Start(to))?;
+            self.file.write_all(&chunk[..n])?;
+            from += n as u64;
+            to += n as u64;
+        }
+        self.file.set_len(to)?;
+        self.read_offset = self.codec.header_len();
+        self.write_offset = to;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        let start = self.codec.header_len();
+        self.file.set_len(start)?;
+        self.read_offset = start;
+        self.write_offset = start;
+        self.bytes = 0;
+        self.removed.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::crc32;
+    use crate::crypto::Keyring;
+    use crate::protocol::{Header, MAGIC, MessageType, Tlv, VERSION};
+    use std::sync::Arc;
+
+    fn stored(seq: u64) -> StoredMessage {
+        StoredMessage {
+            seq,
+            msg: Message {
+                header: Header {
+                    magic: *MAGIC,
+                    version: VERSION,
+                    msg_type: MessageType::JobPush,
+                    flags: 0,
+                    payload_len: 0,
+                },
+                tlvs: vec![Tlv {
+                    tag: 0x01,
+                    value: format!("job{}", seq).into_bytes(),
+                }],
+            },
+        }
+    }
+
+    #[test]
+    fn test_file_stays_bounded_while_a_backlog_drains_and_refills() {
+        let path = std::env::temp_dir().join(format!("rbq_spill_{}.spill", std::process::id()));
+        let mut spill = SpillLog::create(&path, Codec::Plain).unwrap();
+        spill.compact_min = 4096;
+
+        let mut pushed = 0;
+        let mut push = |spill: &mut SpillLog| {
+            let next = stored(pushed);
+            spill.append(&next, &next.msg.encode()).unwrap();
+            pushed += 1;
+        };
+        for _ in 0..100 {
+            push(&mut spill);
+        }
+        let backlog = spill.write_offset - spill.read_offset;
+        let mut largest = 0;
+        for expected in 0..5000 {
+            push(&mut spill);
+            let popped = spill.pop_front().unwrap().unwrap();
+            assert_eq!(popped.seq, expected);
+            assert_eq!(
+                popped.msg.tlvs[0].value,
+                format!("job{}", expected).into_bytes()
+            );
+            largest = largest.max(spill.file.metadata().unwrap().len());
+        }
+        assert_eq!(spill.len(), 100);
+        // Far less than the records written, which run to hundreds of KiB
+        assert!(
+            largest < 4096 + 2 * backlog + 64,
+            "spill file reached {}",
+            largest
+        );
+
+        let mut seqs = Vec::new();
+        spill
+            .for_each(|stored| {
+                seqs.push(stored.seq);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(seqs, (5000..5100).collect::<Vec<_>>());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_sealed_records_refuse_tampering_truncation_and_other_keys() {
+        let sealed_by = |keys: &str, shard: usize| {
+            let keys = Arc::new(Keyring::parse(keys).unwrap());
+            Codec::for_writing(Some(&keys), shard)
+        };
+        let key = "a 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        let imposter = "a 1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+        let dir = std::env::temp_dir().join(format!("rbq_sealed_spill_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(format!("{}.spill", name));
+
+        let mut spill = SpillLog::create(&path("mine"), sealed_by(key, 0)).unwrap();
+        let next = stored(0);
+        spill.append(&next, &next.msg.encode()).unwrap();
+        let clean = std::fs::read(path("mine")).unwrap();
+        let records = spill.codec.header_len() as usize;
+
+        // The same bytes in another shard's spill file, or under another key
+        // with the same id
+        for (name, codec) in [
+            ("shard", sealed_by(key, 1)),
+            ("key", sealed_by(imposter, 0)),
+        ] {
+            let mut other = SpillLog::create(&path(name), codec).unwrap();
+            other.append(&next, &next.msg.encode()).unwrap();
+            std::fs::write(path(name), &clean).unwrap();
+            assert!(other.pop_front().is_err(), "{}", name);
+        }
+
+        // A flipped bit under a valid checksum
+        let mut tampered = clean.clone();
+        let end = tampered.len() - 4;
+        tampered[end - 1] ^= 0x01;
+        let crc = crc32(&tampered[records..end]);
+        tampered[end..].copy_from_slice(&crc.to_le_bytes());
+        std::fs::write(path("mine"), &tampered).unwrap();
+        assert!(spill.pop_front().is_err());
+
+        // Cut short
+        std::fs::write(path("mine"), &clean[..clean.len() - 1]).unwrap();
+        assert!(spill.pop_front().is_err());
+
+        std::fs::write(path("mine"), &clean).unwrap();
+        let popped = spill.pop_front().unwrap().unwrap();
+        assert_eq!(popped.msg.tlvs, next.msg.tlvs);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,463 @@
+use crate::crypto::Keyring;
+use crate::logger::global_loger;
+use crate::protocol::Message;
+use crate::shards::record::{
+    self, Codec, FileKind, WalDamage, WalScan, encode_message, encode_wal_record, file_key_id,
+    read_snapshot_header, read_snapshot_record, write_snapshot_record,
+};
+use crate::shards::upgrade::upgrade_shard;
+use crate::shards::{
+    QueueConfig, Snapshot, StoredMessage, WalOp, WalRecord, snapshot_path, wal_path,
+};
+use crate::{log_debug, log_info, log_warn};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Records appended between syncs of the WAL unless configured otherwise.
+pub(crate) const WAL_BATCH_SIZE: usize = 100;
+
+/// Where a shard persists its queue.
+///
+/// A shard logs every push and pop through `append`, and periodically hands
+/// its whole queue to `checkpoint` so the backend can compact what it has
+/// logged. On start it rebuilds the queue from `recover`.
+pub trait ShardStorage: Send + fmt::Debug {
+    /// Streams the persisted messages into `f` in seq order and returns the
+    /// seq the next push should get.
+    fn recover(&mut self, f: &mut dyn FnMut(StoredMessage) -> io::Result<()>) -> io::Result<u64>;
+
+    /// Records a push (with the encoded message) or a pop (with no data).
+    fn append(&mut self, op: WalOp, seq: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Makes every appended record durable.
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Replaces everything persisted so far with the messages of `source`.
+    fn checkpoint(&mut self, next_seq: u64, source: &mut dyn SnapshotSource) -> io::Result<()>;
+}
+
+/// The queued messages of a shard, as handed to `ShardStorage::checkpoint`.
+pub trait SnapshotSource {
+    fn for_each(&mut self, f: &mut dyn FnMut(&StoredMessage) -> io::Result<()>) -> io::Result<()>;
+}
+
+/// Selects the storage backend of a queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StorageKind {
+    /// WAL plus periodic snapshots in the queue's data directory.
+    #[default]
+    Wal,
+    /// Nothing is persisted; the queue is lost on restart.
+    Memory,
+}
+
+/// Backend for ephemeral queues and tests: keeps nothing.
+#[derive(Debug, Default)]
+pub struct MemoryStorage;
+
+impl ShardStorage for MemoryStorage {
+    fn recover(&mut self, _: &mut dyn FnMut(StoredMessage) -> io::Result<()>) -> io::Result<u64> {
+        Ok(0)
+    }
+
+    fn append(&mut self, _: WalOp, _: u64, _: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn checkpoint(&mut self, _: u64, _: &mut dyn SnapshotSource) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct WalWriter {
+    file: File,
+    codec: Codec,
+    entries_since_flish: usize,
+    batch_size: usize,
+}
+
+impl WalWriter {
+    /// Opens the log at `path`, syncing it every `batch_size` records.
+    pub(crate) fn new(path: &Path, codec: Codec, batch_size: usize) -> io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            codec.write_header(&mut file, FileKind::Wal)?;
+        }
+        Ok(Self {
+            file,
+            codec,
+            entries_since_flish: 0,
+            batch_size,
+        })
+    }
+
+    pub(crate) fn append(&mut self, op: WalOp, seq: u64, data: Option<&[u8]>) -> io::Result<()> {
+        let data = data.unwrap_or(&[]);
+        let sealed = self.codec.seal(FileKind::Wal, op, seq, data);
+        self.file.write_all(&encode_wal_record(op, seq, &sealed))?;
+        log_debug!(
+            global_loger(),
+            "Write msg to the WalWriter with WalOp {} seq {} and len {}",
+            op,
+            seq,
+            data.len()
+        );
+
+        self.entries_since_flish += 1;
+
+        if self.entries_since_flish > self.batch_size {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.entries_since_flish = 0;
+        Ok(())
+    }
+
+    fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.codec.write_header(&mut self.file, FileKind::Wal)?;
+        self.entries_since_flish = 0;
+        Ok(())
+    }
+}
+
+/// Writes a snapshot file, filling in its next seq once every record is in.
+pub(crate) struct SnapshotWriter {
+    writer: BufWriter<File>,
+    codec: Codec,
+    compress: bool,
+}
+
+impl SnapshotWriter {
+    pub(crate) fn create(path: &Path, codec: Codec, compress: bool) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        codec.write_header(&mut writer, FileKind::Snapshot)?;
+        // Placeholder for the next seq
+        writer.write_all(&0u64.to_le_bytes())?;
+        Ok(Self {
+            writer,
+            codec,
+            compress,
+        })
+    }
+
+    pub(crate) fn write(&mut self, seq: u64, msg: &Message) -> io::Result<()> {
+        let encoded = encode_message(msg, self.compress);
+        let sealed = self
+            .codec
+            .seal(FileKind::Snapshot, WalOp::Push, seq, &encoded);
+        write_snapshot_record(&mut self.writer, seq, &sealed)
+    }
+
+    pub(crate) fn finish(self, next_seq: u64) -> io::Result<()> {
+        let mut file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(self.codec.header_len()))?;
+        file.write_all(&next_seq.to_le_bytes())?;
+        file.sync_all()
+    }
+}
+
+/// The durable backend: a `shard_N.wal` of every push and pop since the
+/// last checkpoint, and a `shard_N.snap` of the queue as of that checkpoint.
+///
+/// Snapshots compress their records when the queue does; WAL records
+/// arrive already encoded by the shard.
+///
+/// With a keyring every record is sealed under its active key. Files
+/// written under another key, or in plaintext, are rewritten under the
+/// active key when the shard recovers.
+#[derive(Debug)]
+pub struct WalStorage {
+    data_dir: PathBuf,
+    id: usize,
+    keys: Option<Arc<Keyring>>,
+    compress: bool,
+    wal: WalWriter,
+}
+
+impl WalStorage {
+    /// Opens the files of shard `id`, taking encryption and compression
+    /// from `config`.
+    pub fn open(data_dir: &Path, id: usize, config: &QueueConfig) -> io::Result<Self> {
+        upgrade_shard(data_dir, id, config)?;
+        let keys = config.encryption.clone();
+        Self::cut_damaged_tail(&wal_path(data_dir, id), id, keys.as_ref())?;
+        let wal = WalWriter::new(
+            &wal_path(data_dir, id),
+            Codec::for_writing(keys.as_ref(), id),
+            config.wal_batch_size,
+        )?;
+        Ok(Self {
+            data_dir: data_dir.to_path_buf(),
+            id,
+            keys,
+            compress: config.compress,
+            wal,
+        })
+    }
+
+    /// Cuts the WAL at `path` back to its last valid record. Replay stops
+    /// at the first damaged one, so anything appended behind it would never
+    /// be read again. A record that passes its checksum but not its seal
+    /// was not torn by a crash, so the WAL is refused instead.
+    fn cut_damaged_tail(path: &Path, id: usize, keys: Option<&Arc<Keyring>>) -> io::Result<()> {
+        if !path.exists() {
+            return Ok(());
+        }
+        let scan = Self::scan_wal(path, id, keys)?;
+        let Some(damage) = scan.damage else {
+            return Ok(());
+        };
+        if let WalDamage::AuthFailed { .. } = damage {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("WAL {}: {}", path.display(), damage),
+            ));
+        }
+        let file = OpenOptions::new().write(true).open(path)?;
+        let len = file.metadata()?.len();
+        let valid = damage.offset();
+        file.set_len(valid)?;
+        file.sync_all()?;
+        log_warn!(
+            global_loger(),
+            "Cut {} bytes off WAL {} at {}",
+            len - valid,
+            path.display(),
+            damage
+        );
+        Ok(())
+    }
+
+    fn create_snapshot(&self, path: &Path) -> io::Result<SnapshotWriter> {
+        // Same key and shard as the WAL; the writer seals for its own kind
+        SnapshotWriter::create(path, self.wal.codec.clone(), self.compress)
+    }
+
+    /// Whether a file of this shard was sealed with something other than
+    /// the key new records get.
+    fn has_stale_files(&self) -> io::Result<bool> {
+        let active = self.wal.codec.key_id();
+        for (path, kind) in [
+            (wal_path(&self.data_dir, self.id), FileKind::Wal),
+            (snapshot_path(&self.data_dir, self.id), FileKind::Snapshot),
+        ] {
+            if std::fs::metadata(&path).is_ok_and(|m| m.len() > 0)
+                && file_key_id(&path, kind)?.as_deref() != active
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Rebuilds a shard's queue from its snapshot and WAL without modifying
+    /// either, streaming the surviving messages into `f` in seq order.
+    /// Returns the seq the next push should get.
+    pub fn recover_files(
+        data_dir: &Path,
+        id: usize,
+        keys: Option<&Arc<Keyring>>,
+        mut f: impl FnMut(StoredMessage) -> io::Result<()>,
+    ) -> io::Result<u64> {
+        let wal_path = wal_path(data_dir, id);
+        let snapshot_path = snapshot_path(data_dir, id);
+
+        // The WAL only covers what happened since the last checkpoint, so it
+        // is small enough to fold in memory before streaming the snapshot.
+        let mut wal_pushes = BTreeMap::new();
+        let mut wal_pops = HashSet::new();
+        let mut next_seq = 0;
+        if wal_path.exists() {
+            for record in Self::read_wal(&wal_path, id, keys)? {
+                next_seq = next_seq.max(record.seq + 1);
+                match record.op {
+                    WalOp::Push => {
+                        if let Ok(msg) = Message::decode(&record.data) {
+                            wal_pushes.insert(record.seq, msg);
+                        }
+                    }
+                    WalOp::Pop => {
+                        if wal_pushes.remove(&record.seq).is_none() {
+                            wal_pops.insert(record.seq);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut snapshot_next_seq = 0;
+        if snapshot_path.exists() {
+            snapshot_next_seq = Self::read_snapshot(&snapshot_path, id, keys, |stored| {
+                if wal_pops.contains(&stored.seq) {
+                    return Ok(());
+                }
+                f(stored)
+            })?;
+        }
+        for (seq, msg) in wal_pushes.into_iter() {
+            if seq >= snapshot_next_seq {
+                f(StoredMessage { seq, msg })?;
+            }
+        }
+        Ok(next_seq.max(snapshot_next_seq))
+    }
+
+    pub fn load_snapshoot(
+        path: &Path,
+        id: usize,
+        keys: Option<&Arc<Keyring>>,
+    ) -> io::Result<Snapshot> {
+        let mut messages = VecDeque::new();
+        let next_seq = Self::read_snapshot(path, id, keys, |stored| {
+            messages.push_back(stored);
+            Ok(())
+        })?;
+        Ok(Snapshot { next_seq, messages })
+    }
+
+    /// Streams the messages of the snapshot file of shard `id` into `f`
+    /// without holding the whole file in memory, and returns the snapshot's
+    /// next seq.
+    pub fn read_snapshot(
+        path: &Path,
+        id: usize,
+        keys: Option<&Arc<Keyring>>,
+        mut f: impl FnMut(StoredMessage) -> io::Result<()>,
+    ) -> io::Result<u64> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let Some((key_id, next_seq)) = read_snapshot_header(&mut reader)? else {
+            return Ok(0);
+        };
+        let codec = Codec::for_reading(key_id, keys, id)?;
+
+        let mut payload = Vec::new();
+        while let Some(seq) = read_snapshot_record(&mut reader, &mut payload)? {
+            let encoded = codec.open(FileKind::Snapshot, WalOp::Push, seq, &payload)?;
+            if let Ok(msg) = Message::decode(&encoded) {
+                f(StoredMessage { seq, msg })?;
+            }
+        }
+
+        Ok(next_seq)
+    }
+
+    /// Reads every valid record from the WAL file of shard `id`. A torn or
+    /// corrupt record (normally the tail of a crash mid-write) ends the scan.
+    pub fn read_wal(
+        path: &Path,
+        id: usize,
+        keys: Option<&Arc<Keyring>>,
+    ) -> io::Result<Vec<WalRecord>> {
+        let scan = Self::scan_wal(path, id, keys)?;
+        if let Some(damage) = &scan.damage {
+            log_warn!(
+                global_loger(),
+                "Ignoring WAL {} past {}",
+                path.display(),
+                damage
+            );
+        }
+        Ok(scan.records)
+    }
+
+    /// Scans the WAL file of shard `id` and reports where, and why, it
+    /// stopped being valid.
+    pub fn scan_wal(path: &Path, id: usize, keys: Option<&Arc<Keyring>>) -> io::Result<WalScan> {
+        let mut file = File::open(path)?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+        let (key_id, start) = record::split_header(&buffer, FileKind::Wal)?;
+        let codec = Codec::for_reading(key_id, keys, id)?;
+        Ok(record::scan_wal(&buffer, start, &codec))
+    }
+
+    /// Replays a WAL file on its own and returns the surviving messages in
+    /// seq order. Records are keyed by seq, so a Pop removes exactly the
+    /// message it names regardless of where it sits in the queue.
+    pub fn replay_wal(
+        path: &Path,
+        id: usize,
+        keys: Option<&Arc<Keyring>>,
+    ) -> io::Result<VecDeque<StoredMessage>> {
+        let mut entries = BTreeMap::new();
+        for record in Self::read_wal(path, id, keys)? {
+            match record.op {
+                WalOp::Push => {
+                    if let Ok(msg) = Message::decode(&record.data) {
+                        entries.insert(record.seq, msg);
+                    }
+                }
+                WalOp::Pop => {
+                    entries.remove(&record.seq);
+                }
+            }
+        }
+        Ok(entries
+            .into_iter()
+            .map(|(seq, msg)| StoredMessage { seq, msg })
+            .collect())
+    }
+}
+
+impl ShardStorage for WalStorage {
+    fn recover(&mut self, f: &mut dyn FnMut(StoredMessage) -> io::Result<()>) -> io::Result<u64> {
+        if !self.has_stale_files()? {
+            return Self::recover_files(&self.data_dir, self.id, self.keys.as_ref(), f);
+        }
+
+        let action = match self.wal.codec.key_id() {
+            Some(id) => format!("Re-encrypting shard {} with key {}", self.id, id),
+            None => format!("Decrypting shard {}", self.id),
+        };
+        log_info!(global_loger(), "{}", action);
+        let temp_path = self.data_dir.join(format!("shard_{}.snap.tmp", self.id));
+        let mut snapshot = self.create_snapshot(&temp_path)?;
+        let next_seq =
+            Self::recover_files(&self.data_dir, self.id, self.keys.as_ref(), |stored| {
+                snapshot.write(stored.seq, &stored.msg)?;
+                f(stored)
+            })?;
+        snapshot.finish(next_seq)?;
+        std::fs::rename(&temp_path, snapshot_path(&self.data_dir, self.id))?;
+        self.wal.truncate()?;
+        Ok(next_seq)
+    }
+
+    fn append(&mut self, op: WalOp, seq: u64, data: &[u8]) -> io::Result<()> {
+        self.wal.append(op, seq, Some(data))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.wal.flush()
+    }
+
+    fn checkpoint(&mut self, next_seq: u64, source: &mut dyn SnapshotSource) -> io::Result<()> {
+        let snapshot_path = snapshot_path(&self.data_dir, self.id);
+        let temp_path = self.data_dir.join(format!("shard_{}.snap.tmp", self.id));
+
+        let mut snapshot = self.create_snapshot(&temp_path)?;
+        source.for_each(&mut |stored| snapshot.write(stored.seq, &stored.msg))?;
+        snapshot.finish(next_seq)?;
+
+        std::fs::rename(&temp_path, &snapshot_path)?;
+        self.wal.truncate()
+    }
+}
@@ -0,0 +1,134 @@
+//! Converting shard files written before the file header existed.
+//!
+//! Those files have a layout of their own: the WAL is `Op | Len | Payload`
+//! records with no seq or checksum, where a Pop takes the oldest message,
+//! and the snapshot is `Len | Payload` records. Their checkpoints went to
+//! `shard_N.snapshot` rather than the `shard_N.snap` read back.
+
+use crate::log_info;
+use crate::logger::global_loger;
+use crate::protocol::Message;
+use crate::shards::record::{Codec, FORMAT_VERSION, FileKind, LEGACY_VERSION, read_file_version};
+use crate::shards::reshard::remove_if_exists;
+use crate::shards::storage::SnapshotWriter;
+use crate::shards::{QueueConfig, WalOp, existing_shard_ids, snapshot_path, wal_path};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+fn legacy_snapshot_path(data_dir: &Path, id: usize) -> PathBuf {
+    data_dir.join(format!("shard_{}.snapshot", id))
+}
+
+fn is_legacy(path: &Path, kind: FileKind) -> io::Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    Ok(read_file_version(path, kind)? == LEGACY_VERSION)
+}
+
+/// Converts every shard of `data_dir` to the current format.
+pub(super) fn upgrade(data_dir: &Path, config: &QueueConfig) -> io::Result<()> {
+    for id in existing_shard_ids(data_dir)? {
+        upgrade_shard(data_dir, id, config)?;
+    }
+    Ok(())
+}
+
+/// Rewrites the legacy files of shard `id` as a current snapshot, giving
+/// the messages seqs in queue order, and removes them. Does nothing when
+/// the shard has none. A crash part way is finished on the next call.
+pub(crate) fn upgrade_shard(data_dir: &Path, id: usize, config: &QueueConfig) -> io::Result<()> {
+    let wal = wal_path(data_dir, id);
+    let snapshot = snapshot_path(data_dir, id);
+    let legacy_snapshot = legacy_snapshot_path(data_dir, id);
+    let legacy_wal = is_legacy(&wal, FileKind::Wal)?;
+    let legacy_snap = is_legacy(&snapshot, FileKind::Snapshot)?;
+    if !legacy_wal && !legacy_snap && !legacy_snapshot.exists() {
+        return Ok(());
+    }
+    if snapshot.exists() && !legacy_snap {
+        // Only the conversion writes a current snapshot next to legacy
+        // files, so it already holds what they did
+        remove_if_exists(&wal)?;
+        return remove_if_exists(&legacy_snapshot);
+    }
+
+    let mut queue = VecDeque::new();
+    if legacy_snapshot.exists() {
+        read_legacy_snapshot(&legacy_snapshot, &mut queue)?;
+    } else if legacy_snap {
+        read_legacy_snapshot(&snapshot, &mut queue)?;
+    }
+    if legacy_wal {
+        replay_legacy_wal(&wal, &mut queue)?;
+    }
+
+    let temp_path = data_dir.join(format!("shard_{}.snap.tmp", id));
+    let mut writer = SnapshotWriter::create(
+        &temp_path,
+        Codec::for_writing(config.encryption.as_ref(), id),
+        config.compress,
+    )?;
+    for (seq, msg) in queue.iter().enumerate() {
+        writer.write(seq as u64, msg)?;
+    }
+    writer.finish(queue.len() as u64)?;
+    fs::rename(&temp_path, &snapshot)?;
+    remove_if_exists(&wal)?;
+    remove_if_exists(&legacy_snapshot)?;
+    log_info!(
+        global_loger(),
+        "Upgraded shard {} to format version {} with {} messages",
+        id,
+        FORMAT_VERSION,
+        queue.len()
+    );
+    Ok(())
+}
+
+fn read_legacy_snapshot(path: &Path, queue: &mut VecDeque<Message>) -> io::Result<()> {
+    let buffer = fs::read(path)?;
+    let mut offset = 0;
+    while offset + 4 <= buffer.len() {
+        let len = u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap()) as usize;
+        offset += 4;
+        if offset + len > buffer.len() {
+            break;
+        }
+        if let Ok(msg) = Message::decode(&buffer[offset..offset + len]) {
+            queue.push_back(msg);
+        }
+        offset += len;
+    }
+    Ok(())
+}
+
+/// Applies a legacy WAL to the queue its snapshot left. A torn tail ends
+/// it, as it did for the broker that wrote it.
+fn replay_legacy_wal(path: &Path, queue: &mut VecDeque<Message>) -> io::Result<()> {
+    let buffer = fs::read(path)?;
+    let mut offset = 0;
+    while offset + 5 <= buffer.len() {
+        let op = WalOp::from_byte(buffer[offset]);
+        let len = u32::from_le_bytes(buffer[offset + 1..offset + 5].try_into().unwrap()) as usize;
+        offset += 5;
+        match op {
+            Some(WalOp::Push) => {
+                if offset + len > buffer.len() {
+                    break;
+                }
+                if let Ok(msg) = Message::decode(&buffer[offset..offset + len]) {
+                    queue.push_back(msg);
+                }
+                offset += len;
+            }
+            Some(WalOp::Pop) => {
+                queue.pop_front();
+            }
+            None => break,
+        }
+    }
+    Ok(())
+}
@@ -4,14 +4,48 @@ import jsonschema
 from typing import Dict, Optional
 
 from .logger import logger
-from .protocol import Message, JOB_PUSH, JOB_ACK, CONTROL
+from .protocol import (
+    Message,
+    JOB_PUSH,
+    JOB_ACK,
+    CONTROL,
+    ERR_QUEUE_FULL,
+    ERR_REDIRECT,
+    ERR_REPLICATION_TIMEOUT,
+    ERR_SERVER_BUSY,
+    TAG_MIN_REPLICAS,
+    TAG_RETRY_AFTER,
+)
 from .job_schema import job_schema
 
 
+class QueueFullError(Exception):
+    """Raised when the broker rejects a push because the queue is at capacity"""
+
+
+class ReplicationTimeoutError(Exception):
+    """Raised when a push was stored but too few replicas acked it in time"""
+
+
+class ServerBusyError(Exception):
+    """Raised when the broker turned the connection away at its connection
+    limit; `retry_after` is how many seconds it asks us to wait"""
+
+    def __init__(self, retry_after: float):
+        super().__init__(f"Broker is busy, retry in {retry_after:.3f}s")
+        self.retry_after = retry_after
+
+
+# Redirects followed per request before giving up
+MAX_REDIRECTS = 3
+
+
 class Client:
-    def __init__(self, host: str, port: int):
+    def __init__(self, host: str, port: int, compress: bool = False):
         self.host = host
         self.port = port
+        # Compress requests; the broker then compresses its replies too
+        self.compress = compress
         self.reader: asyncio.StreamReader
         self.writer: asyncio.StreamWriter
 
@@ -19,6 +53,33 @@ class Client:
         self.reader, self.writer = await asyncio.open_connection(self.host, self.port)
         await logger.log("INFO", f"Connected to broker {self.host}:{self.port}")
 
+    async def request(self, msg: Message) -> Optional[Message]:
+        """Send a message and read the reply, reconnecting to the owning
+        cluster member whenever the broker redirects us"""
+        for _ in range(MAX_REDIRECTS + 1):
+            self.writer.write(msg.encode(self.compress))
+            await self.writer.drain()
+
+            data = await self.reader.read(4096)
+            if not data:
+                return None
+            reply = Message.decode(data)
+            tlvs = dict(reply.tlvs)
+            error_code = tlvs.get(4)
+            if error_code and error_code[0] == ERR_SERVER_BUSY:
+                await self.close()
+                retry_after = int.from_bytes(tlvs.get(TAG_RETRY_AFTER, b""), "big")
+                raise ServerBusyError(retry_after / 1000)
+            if not (error_code and error_code[0] == ERR_REDIRECT):
+                return reply
+
+            host, port = tlvs[5].decode().rsplit(":", 1)
+            await logger.log("INFO", f"Redirected to {host}:{port}")
+            await self.close()
+            self.host, self.port = host, int(port)
+            await self.connect()
+        raise ConnectionError(f"Too many redirects, last to {self.host}:{self.port}")
+
     async def validate_job_schema(self, msg: dict) -> bool:
         try:
             jsonschema.validate(instance=msg, schema=job_schema)
@@ -27,18 +88,24 @@ class Client:
             await logger.log("ERROR", f"Validation schema error {e}")
             return False
 
-    async def push_job(self, job_id: str, payload: bytes) -> bool:
-        """Send a job with arbitrary payload"""
-        msg = Message(JOB_PUSH, [(0x01, job_id.encode()), (0x02, payload)])
-        self.writer.write(msg.encode())
-        await self.writer.drain()
-
-        data = await self.reader.read(4096)
-        if not data:
+    async def push_job(self, job_id: str, payload: bytes, min_replicas: int = 0) -> bool:
+        """Send a job with arbitrary payload, optionally waiting until
+        `min_replicas` replicas hold it"""
+        tlvs = [(0x01, job_id.encode()), (0x02, payload)]
+        if min_replicas:
+            tlvs.append((TAG_MIN_REPLICAS, bytes([min_replicas])))
+        msg = await self.request(Message(JOB_PUSH, tlvs))
+        if msg is None:
             return False
 
-        msg = Message.decode(data)
         tlv_dict = msg.tlvs_as_dict()
+        error_code = dict(msg.tlvs).get(4)
+        if error_code and error_code[0] == ERR_QUEUE_FULL:
+            raise QueueFullError(f"Broker queue is full, job {job_id} was not stored")
+        if error_code and error_code[0] == ERR_REPLICATION_TIMEOUT:
+            raise ReplicationTimeoutError(
+                f"Job {job_id} was not acked by {min_replicas} replicas in time"
+            )
         if tlv_dict.get(3) != "success":
             await logger.log("ERROR", f"Error while pushing the msg {tlv_dict}")
             return False
@@ -47,15 +114,10 @@ class Client:
 
     async def ack_job(self, job_id: str) -> Optional[Dict]:
         """Request a job from the broker by job_id"""
-        msg = Message(JOB_ACK, [(0x01, job_id.encode())])
-        self.writer.write(msg.encode())
-        await self.writer.drain()
-
-        data = await self.reader.read(4096)
-        if not data:
+        msg = await self.request(Message(JOB_ACK, [(0x01, job_id.encode())]))
+        if msg is None:
             return None
 
-        msg = Message.decode(data)
         tlv_dict = msg.tlvs_as_dict()
         if msg.msg_type == CONTROL and tlv_dict.get(3) == "No message to pop":
             return None
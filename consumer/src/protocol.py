@@ -1,4 +1,5 @@
 import struct
+import zlib
 
 MAGIC = b"RBQ1"
 VERSION = 1
@@ -8,20 +9,45 @@ JOB_PUSH = 0x01
 JOB_ACK = 0x02
 CONTROL = 0x20  # for responses / errors
 
+# Header flags
+FLAG_COMPRESSED = 0x8000  # payload is raw DEFLATE of the TLV bytes
+
+# Error codes (Control TLV tag 0x04)
+ERR_INTERNAL = 0x01
+ERR_QUEUE_FULL = 0x02
+ERR_READ_ONLY = 0x03  # broker is a replication follower
+ERR_REPLICATION_TIMEOUT = 0x04  # stored, but too few replicas acked in time
+ERR_REDIRECT = 0x05  # another cluster member owns the shard, see tag 0x05
+ERR_SERVER_BUSY = 0x06  # connection refused at admission, see tag 0x06
+ERR_UNAUTHORIZED = 0x07  # admin command without the admin token
+
+# Control TLV: milliseconds to wait before reconnecting after ERR_SERVER_BUSY
+TAG_RETRY_AFTER = 0x06
+
+# Push TLV: number of replicas that must hold the job before the reply
+TAG_MIN_REPLICAS = 0xF0
+
 
 class Message:
     def __init__(self, msg_type, tlvs=None):
         self.msg_type = msg_type
         self.tlvs = tlvs or []
 
-    def encode(self):
+    def encode(self, compress=False):
         payload = b""
         for tag, value in self.tlvs:
             length = len(value)
             payload += struct.pack(">BH", tag, length) + value
+        flags = 0
+        if compress:
+            deflate = zlib.compressobj(wbits=-15)
+            packed = deflate.compress(payload) + deflate.flush()
+            if len(packed) < len(payload):
+                payload = packed
+                flags |= FLAG_COMPRESSED
         header = (
             MAGIC
-            + struct.pack(">BBH", VERSION, self.msg_type, 0)
+            + struct.pack(">BBH", VERSION, self.msg_type, flags)
             + struct.pack(">I", len(payload))
         )
         return header + payload
@@ -38,6 +64,8 @@ class Message:
         payload_len = struct.unpack(">I", data[8:12])[0]
 
         payload = data[12 : 12 + payload_len]
+        if flags & FLAG_COMPRESSED:
+            payload = zlib.decompress(payload, -15)
         tlvs = []
         i = 0
         while i < len(payload):
@@ -1,7 +1,8 @@
 import asyncio
 import json
 import argparse
-from consumer.src.client import Client
+import sys
+from consumer.src.client import Client, QueueFullError
 
 async def main():
     # Parse command-line arguments
@@ -40,7 +41,13 @@ async def main():
     print("INFO", f"System prompt: {args.system_prompt}")
 
     # Push job asynchronously
-    success = await client.push_job(args.job_id, json.dumps(prompt).encode())
+    try:
+        success = await client.push_job(args.job_id, json.dumps(prompt).encode())
+    except QueueFullError as e:
+        print("ERROR", f"{e}; retry later")
+        await client.close()
+        sys.exit(2)
+
     if success:
         print("INFO", f"Job '{args.job_id}' submitted successfully")
     else: